@@ -1 +1,5 @@
-fn main() {}
+use lfa_init::service_main;
+
+fn main() {
+    service_main();
+}
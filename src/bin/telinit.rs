@@ -0,0 +1,7 @@
+//! Switching the runlevel of the running system. Located at `/sbin/telinit`
+
+use lfa_init::telinit_main;
+
+fn main() {
+    telinit_main();
+}
@@ -0,0 +1,263 @@
+//! Timings of the boot
+//!
+//! Init records when it has started, when it has entered each runlevel and
+//! when each service has been started, become ready (or failed) and
+//! exited. All times are read from `CLOCK_MONOTONIC` and are measured in
+//! milliseconds since the kernel has started. The record is mirrored into
+//! `/run/init/boot.toml` and used by `service blame`, `service
+//! critical-chain` and `service timeline`.
+
+use anyhow::anyhow;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+
+use nix::time::clock_gettime;
+use nix::time::ClockId;
+
+use crate::paths::Paths;
+use crate::traits::TomlConfig;
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+pub struct BootRecord {
+    /// Time init has started
+    pub init_start: u64,
+
+    /// Time the final runlevel has been entered
+    pub finished: Option<u64>,
+
+    /// Runlevels in the order of their entering
+    #[serde(default)]
+    pub runlevels: Vec<RunlevelTiming>,
+
+    /// Services in the order of their start
+    #[serde(default)]
+    pub services: Vec<ServiceTiming>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct RunlevelTiming {
+    pub runlevel: String,
+
+    /// Time the runlevel has been entered
+    pub at: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct ServiceTiming {
+    pub runlevel: String,
+    pub service: String,
+
+    /// Time the start commands have been run
+    pub start: u64,
+
+    /// Time the service has become ready
+    pub ready: Option<u64>,
+
+    /// Time the service has failed to start
+    pub failed: Option<u64>,
+
+    /// Time the main process has exited or the service has been stopped
+    pub exit: Option<u64>,
+
+    /// Time the service has been skipped because its start conditions
+    /// are not met
+    pub skipped: Option<u64>,
+}
+
+impl BootRecord {
+    /// Starts the record at the current time
+    pub fn new() -> Self {
+        Self {
+            init_start: monotonic(),
+            ..Self::default()
+        }
+    }
+
+    pub fn enter_runlevel(&mut self, runlevel: &str) {
+        self.runlevels.push(RunlevelTiming {
+            runlevel: runlevel.to_string(),
+            at: monotonic(),
+        });
+    }
+
+    /// Records the start of the service. Only the first start is recorded,
+    /// later restarts don't change the timings of the boot
+    pub fn start(&mut self, runlevel: &str, service: &str) {
+        if self.service(service).is_some() {
+            return;
+        }
+
+        self.services.push(ServiceTiming {
+            runlevel: runlevel.to_string(),
+            service: service.to_string(),
+            start: monotonic(),
+            ready: None,
+            failed: None,
+            exit: None,
+            skipped: None,
+        });
+    }
+
+    /// Records the result of the start of the service
+    pub fn started(&mut self, service: &str, ok: bool) {
+        if let Some(timing) = self.pending(service) {
+            match ok {
+                true => timing.ready = Some(monotonic()),
+                false => timing.failed = Some(monotonic()),
+            }
+        }
+    }
+
+    /// Records that the start conditions of the service are not met
+    pub fn skipped(&mut self, service: &str) {
+        if let Some(timing) = self.pending(service) {
+            timing.skipped = Some(monotonic());
+        }
+    }
+
+    pub fn exit(&mut self, service: &str) {
+        if let Some(timing) = self
+            .services
+            .iter_mut()
+            .find(|timing| timing.service == service && timing.exit.is_none())
+        {
+            timing.exit = Some(monotonic());
+        }
+    }
+
+    pub fn finish(&mut self) {
+        self.finished = Some(monotonic());
+    }
+
+    pub fn service(&self, name: &str) -> Option<&ServiceTiming> {
+        self.services.iter().find(|timing| timing.service == name)
+    }
+
+    /// Returns the services sorted by the time of their start from the
+    /// slowest one
+    pub fn blame(&self) -> Vec<&ServiceTiming> {
+        let mut services: Vec<&ServiceTiming> = self
+            .services
+            .iter()
+            .filter(|timing| timing.duration().is_some())
+            .collect();
+        services.sort_by_key(|timing| std::cmp::Reverse(timing.duration()));
+
+        services
+    }
+
+    /// Returns the time of the end of the boot (or of the last recorded
+    /// event if the boot hasn't finished)
+    pub fn end(&self) -> u64 {
+        let last_service = self.services.iter().flat_map(|timing| {
+            [timing.start]
+                .into_iter()
+                .chain(timing.ready)
+                .chain(timing.failed)
+                .chain(timing.exit)
+                .chain(timing.skipped)
+        });
+        let last_runlevel = self.runlevels.iter().map(|rl| rl.at);
+
+        last_service
+            .chain(last_runlevel)
+            .chain(self.finished)
+            .fold(self.init_start, u64::max)
+    }
+
+    /// Service whose start is still being waited for
+    fn pending(&mut self, service: &str) -> Option<&mut ServiceTiming> {
+        self.services.iter_mut().find(|timing| {
+            timing.service == service
+                && timing.ready.is_none()
+                && timing.failed.is_none()
+                && timing.skipped.is_none()
+        })
+    }
+}
+
+impl ServiceTiming {
+    /// Time the service took to become ready or to fail
+    pub fn duration(&self) -> Option<u64> {
+        self.ready
+            .or(self.failed)
+            .map(|end| end.saturating_sub(self.start))
+    }
+}
+
+impl TomlConfig for BootRecord {}
+
+/// Reads the timings of the last boot written by init
+pub fn load(paths: &Paths) -> Result<BootRecord> {
+    let pth = paths.boot_record();
+    if !pth.is_file() {
+        return Err(anyhow!(
+            "boot timings are not available ({} not found)",
+            pth.display()
+        ));
+    }
+
+    BootRecord::parse(pth)
+}
+
+/// Returns the time since the kernel has started in milliseconds
+pub fn monotonic() -> u64 {
+    clock_gettime(ClockId::CLOCK_MONOTONIC)
+        .map(|ts| ts.tv_sec() as u64 * 1000 + ts.tv_nsec() as u64 / 1_000_000)
+        .unwrap_or_default()
+}
+
+/// Formats the milliseconds as seconds (`1.234s`)
+pub fn secs(ms: u64) -> String {
+    format!("{}.{:03}s", ms / 1000, ms % 1000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timing(service: &str, start: u64, ready: u64) -> ServiceTiming {
+        ServiceTiming {
+            runlevel: "rl1".to_string(),
+            service: service.to_string(),
+            start,
+            ready: Some(ready),
+            failed: None,
+            exit: None,
+            skipped: None,
+        }
+    }
+
+    #[test]
+    fn boot_record_test() {
+        let mut boot = BootRecord::new();
+        boot.enter_runlevel("rl1");
+        boot.start("rl1", "mount");
+        boot.started("mount", true);
+        boot.start("rl1", "broken");
+        boot.started("broken", false);
+        boot.start("rl1", "broken");
+        boot.finish();
+
+        assert_eq!(boot.services.len(), 2);
+        assert!(boot.service("mount").unwrap().ready.is_some());
+        assert!(boot.service("broken").unwrap().failed.is_some());
+        assert!(boot.end() >= boot.init_start);
+
+        let boot = BootRecord {
+            services: vec![
+                timing("a", 10, 20),
+                timing("b", 20, 120),
+                timing("c", 120, 150),
+            ],
+            ..BootRecord::default()
+        };
+        let blame: Vec<&str> = boot.blame().iter().map(|t| t.service.as_str()).collect();
+        assert_eq!(blame, ["b", "c", "a"]);
+        assert_eq!(boot.end(), 150);
+
+        assert_eq!(secs(1234), "1.234s");
+        assert_eq!(secs(5), "0.005s");
+    }
+}
@@ -0,0 +1,366 @@
+//! Early boot tasks performed by init itself
+//!
+//! Minimal images may lack `/bin/hostname`, `/sbin/ifconfig` and
+//! `/sbin/sysctl`, so a service can ask init to do its job natively with
+//! `builtin = "..."` in the `[service]` section instead of the start
+//! commands:
+//!
+//! - `hostname` - sets the hostname from `/etc/hostname` (or
+//!   `Config.hostname`) with `sethostname(2)`;
+//! - `loopback` - brings up `lo` with `127.0.0.1/8` and `::1/128` through
+//!   rtnetlink;
+//! - `sysctl` - applies `/etc/sysctl.d/*.conf` in lexical order and then
+//!   `/etc/sysctl.conf`.
+
+use anyhow::anyhow;
+use anyhow::Result;
+use std::fs;
+use std::os::fd::AsRawFd;
+use std::os::fd::OwnedFd;
+use std::path::PathBuf;
+
+use nix::sys::socket::bind;
+use nix::sys::socket::recv;
+use nix::sys::socket::sendto;
+use nix::sys::socket::socket;
+use nix::sys::socket::AddressFamily;
+use nix::sys::socket::MsgFlags;
+use nix::sys::socket::NetlinkAddr;
+use nix::sys::socket::SockFlag;
+use nix::sys::socket::SockProtocol;
+use nix::sys::socket::SockType;
+use nix::unistd::sethostname;
+
+use crate::config::Config;
+use crate::paths::Paths;
+use crate::service::Builtin;
+
+/// Hostname used if neither `/etc/hostname` nor `Config.hostname` is set
+const DEFAULT_HOSTNAME: &str = "localhost";
+
+const LOOPBACK: &str = "lo";
+
+/// Used if `/sys/class/net/lo/ifindex` can't be read. The loopback
+/// interface is always created first
+const LOOPBACK_INDEX: u32 = 1;
+
+/// Constants of rtnetlink from `<linux/netlink.h>`, `<linux/rtnetlink.h>`
+/// and `<linux/if.h>`
+const RTM_NEWLINK: u16 = 16;
+const RTM_NEWADDR: u16 = 20;
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_REPLACE: u16 = 0x100;
+const NLM_F_CREATE: u16 = 0x400;
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+const IFF_UP: u32 = 0x1;
+const RT_SCOPE_HOST: u8 = 254;
+const AF_INET: u8 = 2;
+const AF_INET6: u8 = 10;
+
+/// Length of `struct nlmsghdr`
+const NLMSG_HDRLEN: usize = 16;
+
+/// Performs the builtin task
+pub fn run(paths: &Paths, conf: &Config, builtin: Builtin) -> Result<()> {
+    match builtin {
+        Builtin::hostname => set_hostname(paths, conf),
+        Builtin::loopback => loopback_up(paths),
+        Builtin::sysctl => apply_sysctl(paths),
+    }
+}
+
+/// Returns the hostname from `/etc/hostname`: the first line that is not
+/// empty or a comment
+fn read_hostname(content: &str) -> Option<&str> {
+    content
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+fn set_hostname(paths: &Paths, conf: &Config) -> Result<()> {
+    let content = fs::read_to_string(paths.hostname()).unwrap_or_default();
+    let hostname = read_hostname(&content)
+        .or(conf.hostname.as_deref())
+        .unwrap_or(DEFAULT_HOSTNAME);
+
+    sethostname(hostname).map_err(|why| anyhow!("failed to set hostname {hostname}: {why}"))
+}
+
+/// Builds the rtnetlink message with the header and the `payload`
+fn netlink_msg(kind: u16, flags: u16, seq: u32, payload: &[u8]) -> Vec<u8> {
+    let mut msg = vec![];
+    msg.extend(((NLMSG_HDRLEN + payload.len()) as u32).to_ne_bytes());
+    msg.extend(kind.to_ne_bytes());
+    msg.extend((flags | NLM_F_REQUEST | NLM_F_ACK).to_ne_bytes());
+    msg.extend(seq.to_ne_bytes());
+    msg.extend(0u32.to_ne_bytes());
+    msg.extend(payload);
+    msg
+}
+
+/// Message setting the `IFF_UP` flag of the interface (`struct ifinfomsg`)
+fn link_up_msg(seq: u32, index: u32) -> Vec<u8> {
+    let mut ifinfo = vec![0, 0, 0, 0];
+    ifinfo.extend(index.to_ne_bytes());
+    ifinfo.extend(IFF_UP.to_ne_bytes());
+    ifinfo.extend(IFF_UP.to_ne_bytes());
+
+    netlink_msg(RTM_NEWLINK, 0, seq, &ifinfo)
+}
+
+/// Message adding the address to the interface (`struct ifaddrmsg` with
+/// the `IFA_LOCAL` and `IFA_ADDRESS` attributes)
+fn addr_msg(seq: u32, index: u32, family: u8, addr: &[u8], prefix: u8) -> Vec<u8> {
+    let mut ifaddr = vec![family, prefix, 0, RT_SCOPE_HOST];
+    ifaddr.extend(index.to_ne_bytes());
+
+    for kind in [IFA_LOCAL, IFA_ADDRESS] {
+        // addresses are 4 or 16 bytes long, so no padding is needed
+        ifaddr.extend((4 + addr.len() as u16).to_ne_bytes());
+        ifaddr.extend(kind.to_ne_bytes());
+        ifaddr.extend(addr);
+    }
+
+    netlink_msg(RTM_NEWADDR, NLM_F_CREATE | NLM_F_REPLACE, seq, &ifaddr)
+}
+
+/// Returns the error code of the acknowledgement (`0` on success)
+fn ack_error(reply: &[u8]) -> Option<i32> {
+    let kind = u16::from_ne_bytes(reply.get(4..6)?.try_into().ok()?);
+    if kind != NLMSG_ERROR {
+        return None;
+    }
+    let error = reply.get(NLMSG_HDRLEN..NLMSG_HDRLEN + 4)?;
+    Some(i32::from_ne_bytes(error.try_into().ok()?))
+}
+
+/// Sends the request to the kernel and waits for its acknowledgement
+fn request(sock: &OwnedFd, msg: &[u8]) -> Result<()> {
+    sendto(
+        sock.as_raw_fd(),
+        msg,
+        &NetlinkAddr::new(0, 0),
+        MsgFlags::empty(),
+    )?;
+
+    let mut buf = [0; 1024];
+    let len = recv(sock.as_raw_fd(), &mut buf, MsgFlags::empty())?;
+    match ack_error(&buf[..len]) {
+        Some(0) => Ok(()),
+        Some(code) => Err(nix::errno::Errno::from_raw(-code).into()),
+        None => Err(anyhow!("unexpected reply of rtnetlink")),
+    }
+}
+
+fn loopback_up(paths: &Paths) -> Result<()> {
+    let index = fs::read_to_string(paths.sys_net_dir().join(LOOPBACK).join("ifindex"))
+        .ok()
+        .and_then(|index| index.trim().parse().ok())
+        .unwrap_or(LOOPBACK_INDEX);
+
+    let sock = socket(
+        AddressFamily::Netlink,
+        SockType::Raw,
+        SockFlag::SOCK_CLOEXEC,
+        SockProtocol::NetlinkRoute,
+    )?;
+    bind(sock.as_raw_fd(), &NetlinkAddr::new(0, 0))?;
+
+    request(&sock, &addr_msg(1, index, AF_INET, &[127, 0, 0, 1], 8))
+        .map_err(|why| anyhow!("failed to add 127.0.0.1 to {LOOPBACK}: {why}"))?;
+
+    // IPv6 may be disabled in the kernel
+    if paths.proc_sys_dir().join("net/ipv6").exists() {
+        let mut addr = [0; 16];
+        addr[15] = 1;
+        request(&sock, &addr_msg(2, index, AF_INET6, &addr, 128))
+            .map_err(|why| anyhow!("failed to add ::1 to {LOOPBACK}: {why}"))?;
+    }
+
+    request(&sock, &link_up_msg(3, index))
+        .map_err(|why| anyhow!("failed to bring {LOOPBACK} up: {why}"))
+}
+
+/// Setting of the kernel parameter from the sysctl config
+#[derive(Debug, PartialEq)]
+struct Setting {
+    /// Name with dots or slashes as separators (`net.ipv4.ip_forward`)
+    key: String,
+    value: String,
+
+    /// The key was prefixed with `-`: failures are ignored
+    ignore_errors: bool,
+}
+
+/// Parses the `key = value` lines of the sysctl config. Lines starting
+/// with `#` or `;` are comments
+fn parse_sysctl(content: &str) -> Vec<Setting> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#') && !line.starts_with(';'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| {
+            let key = key.trim();
+            Setting {
+                key: key.trim_start_matches('-').to_string(),
+                value: value.trim().to_string(),
+                ignore_errors: key.starts_with('-'),
+            }
+        })
+        .collect()
+}
+
+/// Returns the sysctl configs in the order they are applied
+fn sysctl_files(paths: &Paths) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(paths.sysctl_dir())
+        .map(|dir| {
+            dir.flatten()
+                .map(|entry| entry.path())
+                .filter(|pth| pth.extension().is_some_and(|ext| ext == "conf"))
+                .collect()
+        })
+        .unwrap_or_default();
+    files.sort();
+
+    let conf = paths.sysctl_conf();
+    if conf.is_file() {
+        files.push(conf);
+    }
+
+    files
+}
+
+/// Writes the parameters to `/proc/sys`. All settings are applied even if
+/// some of them fail
+fn apply_sysctl(paths: &Paths) -> Result<()> {
+    let mut failed = vec![];
+
+    for pth in sysctl_files(paths) {
+        let content = match fs::read_to_string(&pth) {
+            Ok(content) => content,
+            Err(why) => {
+                failed.push(format!("{} ({why})", pth.display()));
+                continue;
+            }
+        };
+        for setting in parse_sysctl(&content) {
+            // dots are kept in the keys with slashes (`net/ipv4/conf/eth0.1`)
+            let key = match setting.key.contains('/') {
+                true => setting.key.clone(),
+                false => setting.key.replace('.', "/"),
+            };
+            let param = paths.proc_sys_dir().join(key);
+            if let Err(why) = fs::write(&param, &setting.value) {
+                if !setting.ignore_errors {
+                    failed.push(format!("{} ({why})", &setting.key));
+                }
+            }
+        }
+    }
+
+    match failed.is_empty() {
+        true => Ok(()),
+        false => Err(anyhow!("failed to set {}", failed.join(", "))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_root;
+
+    #[test]
+    fn hostname_test() {
+        assert_eq!(read_hostname("# host\n\n  lfa-board \n"), Some("lfa-board"));
+        assert_eq!(read_hostname("\n# empty\n"), None);
+    }
+
+    #[test]
+    fn netlink_test() {
+        let msg = addr_msg(7, 1, AF_INET, &[127, 0, 0, 1], 8);
+        assert_eq!(msg.len(), NLMSG_HDRLEN + 8 + 2 * 8);
+        assert_eq!(&msg[..4], (msg.len() as u32).to_ne_bytes());
+        assert_eq!(&msg[4..6], RTM_NEWADDR.to_ne_bytes());
+        assert_eq!(&msg[8..12], 7u32.to_ne_bytes());
+        assert_eq!(&msg[16..20], [AF_INET, 8, 0, RT_SCOPE_HOST]);
+        assert_eq!(&msg[msg.len() - 4..], [127, 0, 0, 1]);
+
+        let msg = link_up_msg(1, 1);
+        assert_eq!(msg.len(), NLMSG_HDRLEN + 16);
+
+        let mut ack = netlink_msg(NLMSG_ERROR, 0, 1, &(-17i32).to_ne_bytes());
+        assert_eq!(ack_error(&ack), Some(-17));
+        ack[4..6].copy_from_slice(&RTM_NEWLINK.to_ne_bytes());
+        assert_eq!(ack_error(&ack), None);
+    }
+
+    #[test]
+    fn sysctl_test() {
+        let settings = parse_sysctl(
+            "# comment\n; comment\nnet.ipv4.ip_forward = 1\n\
+             -kernel.unknown=0\nkernel/printk = 3 4 1 3\ngarbage\n",
+        );
+        assert_eq!(
+            settings,
+            [
+                Setting {
+                    key: "net.ipv4.ip_forward".to_string(),
+                    value: "1".to_string(),
+                    ignore_errors: false,
+                },
+                Setting {
+                    key: "kernel.unknown".to_string(),
+                    value: "0".to_string(),
+                    ignore_errors: true,
+                },
+                Setting {
+                    key: "kernel/printk".to_string(),
+                    value: "3 4 1 3".to_string(),
+                    ignore_errors: false,
+                },
+            ]
+        );
+
+        let root = temp_root("sysctl");
+        let paths = Paths::with_root(&root);
+        fs::create_dir_all(paths.resolve("/etc/sysctl.d")).unwrap();
+        fs::create_dir_all(paths.resolve("/proc/sys/vm")).unwrap();
+        fs::write(
+            paths.resolve("/etc/sysctl.d/20-b.conf"),
+            "vm.swappiness = 20\n",
+        )
+        .unwrap();
+        fs::write(
+            paths.resolve("/etc/sysctl.d/10-a.conf"),
+            "vm.swappiness = 10\n",
+        )
+        .unwrap();
+        fs::write(paths.resolve("/etc/sysctl.conf"), "-vm/missing/x = 1\n").unwrap();
+
+        apply_sysctl(&paths).unwrap();
+        assert_eq!(
+            fs::read_to_string(paths.resolve("/proc/sys/vm/swappiness")).unwrap(),
+            "20"
+        );
+
+        fs::write(paths.resolve("/etc/sysctl.conf"), "vm/missing/x = 1\n").unwrap();
+        assert!(apply_sysctl(&paths).is_err());
+
+        // an unreadable file doesn't stop the following ones
+        fs::create_dir(paths.resolve("/etc/sysctl.d/15-dir.conf")).unwrap();
+        fs::write(paths.resolve("/etc/sysctl.conf"), "vm.swappiness = 30\n").unwrap();
+        let why = apply_sysctl(&paths).unwrap_err().to_string();
+        assert!(why.contains("15-dir.conf"), "{why}");
+        assert_eq!(
+            fs::read_to_string(paths.resolve("/proc/sys/vm/swappiness")).unwrap(),
+            "30"
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
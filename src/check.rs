@@ -0,0 +1,627 @@
+//! Validation of the init configuration
+//!
+//! `service check` strictly parses `init.toml` and every service file and
+//! looks for problems that would otherwise surface only at boot: missing
+//! files, duplicate names, unknown runlevels and dependencies, dependency
+//! cycles and nonexistent executables. Paths are resolved inside the root
+//! directory, so the rootfs of an image can be checked on the build host.
+
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::conditions;
+use crate::config::Action;
+use crate::config::Config;
+use crate::devices::DeviceConf;
+use crate::fsck;
+use crate::fstab;
+use crate::paths::Paths;
+use crate::service::split_args;
+use crate::service::Ready;
+use crate::service::Service;
+use crate::swap;
+use crate::tmpfiles;
+use crate::traits::TomlConfig;
+
+/// Directories searched for programs specified without a path
+const SEARCH_PATH: [&str; 6] = [
+    "/usr/local/sbin",
+    "/usr/local/bin",
+    "/usr/sbin",
+    "/usr/bin",
+    "/sbin",
+    "/bin",
+];
+
+/// Maximum number of symbolic links followed while resolving a path
+const MAX_SYMLINKS: usize = 40;
+
+/// Problems found in the configuration
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Problems that break the boot
+    pub errors: Vec<String>,
+
+    /// Suspicious settings that are probably mistakes
+    pub warnings: Vec<String>,
+}
+
+impl Report {
+    fn error<D: Display>(&mut self, msg: D) {
+        self.errors.push(msg.to_string());
+    }
+
+    fn warning<D: Display>(&mut self, msg: D) {
+        self.warnings.push(msg.to_string());
+    }
+
+    /// Whether the configuration has no errors
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// Checks the configuration of init installed into the root directory
+pub fn check(paths: &Paths) -> Report {
+    let mut report = Report::default();
+
+    let conf_file = paths.master_conf();
+    let conf = match Config::parse(&conf_file) {
+        Ok(conf) => conf,
+        Err(why) => {
+            report.error(why);
+            return report;
+        }
+    };
+    let conf_file = conf_file.display().to_string();
+
+    check_runlevels(paths, &conf, &conf_file, &mut report);
+    check_emergency(paths, &conf, &conf_file, &mut report);
+    check_early_mount(&conf, &conf_file, &mut report);
+    check_fstab(paths, &conf, &conf_file, &mut report);
+    check_swap(&conf, &conf_file, &mut report);
+    check_tmpfiles(paths, &mut report);
+
+    let (listed, services) = check_services(paths, &conf, &conf_file, &mut report);
+    if paths.devices_conf().exists() {
+        check_devices(paths, &listed, &mut report);
+    }
+    check_dependencies(&conf, &listed, &services, &mut report);
+
+    report
+}
+
+fn check_runlevels(paths: &Paths, conf: &Config, conf_file: &str, report: &mut Report) {
+    let mut dirs = HashSet::new();
+    for rl in &conf.runlevel {
+        if !dirs.insert(&rl.dir) {
+            report.error(format!("{conf_file}: duplicate runlevel {}", &rl.dir));
+        }
+    }
+
+    if !dirs.contains(&conf.final_runlevel) {
+        report.error(format!(
+            "{conf_file}: final_runlevel: unknown runlevel {}",
+            &conf.final_runlevel
+        ));
+    }
+
+    for rl in &conf.runlevel {
+        let place = format!("{conf_file}: runlevel {}", &rl.dir);
+
+        match rl.action.unwrap_or_default() {
+            Action::run_services if rl.services.is_none() => {
+                report.error(format!("{place}: `services` is not specified"));
+            }
+            Action::exec => match &rl.exec {
+                Some(prog) => check_program(paths, &place, prog, report),
+                None => report.error(format!("{place}: `exec` is not specified")),
+            },
+            _ => {}
+        }
+
+        if let Some(sh) = rl.login_shell.as_ref().filter(|sh| !sh.is_empty()) {
+            check_program(paths, &place, sh, report);
+        }
+    }
+}
+
+fn check_emergency(paths: &Paths, conf: &Config, conf_file: &str, report: &mut Report) {
+    if let Some(sh) = conf.emergency.as_ref().and_then(|em| em.shell.as_ref()) {
+        check_program(paths, &format!("{conf_file}: emergency"), sh, report);
+    }
+}
+
+fn check_early_mount(conf: &Config, conf_file: &str, report: &mut Report) {
+    let Some(mounts) = conf.early_mount.as_ref().and_then(|em| em.mount.as_ref()) else {
+        return;
+    };
+
+    let mut targets = HashSet::new();
+    for point in mounts {
+        let place = format!("{conf_file}: early_mount {}", &point.target);
+
+        if !point.target.starts_with('/') {
+            report.error(format!("{place}: target must be an absolute path"));
+        }
+        if !targets.insert(point.target.trim_end_matches('/')) {
+            report.error(format!("{place}: mounted twice"));
+        }
+        if let Some(parent) = mounts[..]
+            .iter()
+            .skip_while(|p| *p != point)
+            .find(|p| Path::new(&point.target).starts_with(&p.target) && p.target != point.target)
+        {
+            report.warning(format!(
+                "{place}: mounted before its parent {}",
+                &parent.target
+            ));
+        }
+    }
+}
+
+fn check_fstab(paths: &Paths, conf: &Config, conf_file: &str, report: &mut Report) {
+    let em = conf.early_mount.clone().unwrap_or_default();
+    let use_fstab = em.enabled.unwrap_or(true) && em.fstab.unwrap_or(true);
+    if !use_fstab {
+        return;
+    }
+
+    let entries = match fstab::read(paths) {
+        Ok(entries) => entries,
+        Err(why) => {
+            report.error(why);
+            return;
+        }
+    };
+
+    for entry in &entries {
+        for opt in entry.unknown_init_options() {
+            report.warning(format!(
+                "/etc/fstab: {}: unknown option {opt}",
+                &entry.target
+            ));
+        }
+    }
+    if let Err(why) = fstab::mount_order(&entries) {
+        report.error(format!("/etc/fstab: {why}"));
+    }
+
+    if let Some(conf) = &conf.fsck {
+        let mode = conf.mode.unwrap_or_default();
+        if entries
+            .iter()
+            .any(|entry| fsck::needs_check(conf, mode, entry))
+        {
+            let prog = conf.program.as_deref().unwrap_or(fsck::DEFAULT_PROGRAM);
+            check_program(paths, &format!("{conf_file}: fsck"), prog, report);
+        }
+    }
+}
+
+fn check_swap(conf: &Config, conf_file: &str, report: &mut Report) {
+    let Some(conf) = &conf.swap else {
+        return;
+    };
+
+    let zram = conf
+        .zram
+        .as_ref()
+        .map(|zram| ("zram", &zram.size, zram.priority));
+    let files = conf
+        .file
+        .iter()
+        .flatten()
+        .map(|file| (file.path.as_str(), &file.size, file.priority));
+
+    for (name, size, priority) in zram.into_iter().chain(files) {
+        let place = format!("{conf_file}: swap {name}");
+        if name != "zram" && !name.starts_with('/') {
+            report.error(format!("{place}: path must be absolute"));
+        }
+        if let Some(Err(why)) = size.as_deref().map(|size| swap::parse_size(size, 0)) {
+            report.error(format!("{place}: {why}"));
+        }
+        if priority.is_some_and(|prio| !(0..=32767).contains(&prio)) {
+            report.error(format!("{place}: priority must be from 0 to 32767"));
+        }
+    }
+}
+
+fn check_tmpfiles(paths: &Paths, report: &mut Report) {
+    match tmpfiles::read(paths) {
+        Ok(lists) => {
+            for (name, entries) in lists {
+                let place = paths.tmpfiles_dir().join(format!("{name}.conf"));
+                for entry in entries {
+                    if let Err(why) = tmpfiles::validate(paths, &entry) {
+                        report.error(format!("{}: {why}", place.display()));
+                    }
+                }
+            }
+        }
+        Err(why) => report.error(why),
+    }
+}
+
+/// Service name -> runlevel of all listed services, including the ones
+/// that failed to parse
+type Listed<'a> = BTreeMap<&'a str, &'a str>;
+
+/// Service name -> file and configuration of the parsed services
+type Parsed<'a> = BTreeMap<&'a str, (PathBuf, Service)>;
+
+fn check_services<'a>(
+    paths: &Paths,
+    conf: &'a Config,
+    conf_file: &str,
+    report: &mut Report,
+) -> (Listed<'a>, Parsed<'a>) {
+    let mut listed = BTreeMap::new();
+    let mut services = BTreeMap::new();
+
+    for rl in &conf.runlevel {
+        for name in rl.services.iter().flatten() {
+            if let Some(other) = listed.insert(name.as_str(), rl.dir.as_str()) {
+                report.error(format!(
+                    "{conf_file}: service {name} is listed in both {other} and {} runlevels",
+                    &rl.dir
+                ));
+                continue;
+            }
+
+            let pth = paths.service_file(&rl.dir, name);
+            if !pth.is_file() {
+                report.error(format!("{}: service file not found", pth.display()));
+                continue;
+            }
+
+            match Service::parse(&pth) {
+                Ok(service) => {
+                    check_service(paths, &pth, &service, report);
+                    services.insert(name.as_str(), (pth, service));
+                }
+                Err(why) => report.error(why),
+            }
+        }
+    }
+
+    (listed, services)
+}
+
+fn check_dependencies(conf: &Config, listed: &Listed, services: &Parsed, report: &mut Report) {
+    let rl_index = |dir: &str| conf.runlevel.iter().position(|rl| rl.dir == dir);
+    let mut graph = BTreeMap::new();
+    for (name, (pth, service)) in services {
+        let deps: Vec<&str> = service
+            .init
+            .requires
+            .iter()
+            .chain(service.init.wants.iter())
+            .flatten()
+            .map(String::as_str)
+            .collect();
+
+        for dep in &deps {
+            let Some(dep_rl) = listed.get(dep) else {
+                report.error(format!("{}: unknown dependency {dep}", pth.display()));
+                continue;
+            };
+            // dependencies of other runlevels are not started, they must
+            // be running before this runlevel is entered
+            if rl_index(dep_rl) > rl_index(listed[name]) {
+                report.error(format!(
+                    "{}: dependency {dep} belongs to the later runlevel {dep_rl}",
+                    pth.display()
+                ));
+            }
+        }
+        graph.insert(*name, deps);
+    }
+
+    for cycle in find_cycles(&graph) {
+        report.error(format!("dependency cycle: {}", cycle.join(" -> ")));
+    }
+}
+
+fn check_service(paths: &Paths, pth: &Path, service: &Service, report: &mut Report) {
+    let place = pth.display().to_string();
+    let srv = &service.service;
+
+    let commands = [&srv.start, &srv.stop, &srv.restart, &srv.reload]
+        .into_iter()
+        .flatten()
+        .flatten()
+        .chain(service.health.iter().flat_map(|health| &health.command));
+    for cmd in commands {
+        check_program(paths, &place, cmd, report);
+    }
+
+    if let Err(why) = srv.reload_signal() {
+        report.error(format!("{place}: {why}"));
+    }
+
+    if srv.can_reload.unwrap_or(false) && srv.reload.is_none() && srv.reload_signal.is_none() {
+        report.error(format!(
+            "{place}: `can_reload = true` requires `reload` or `reload_signal`"
+        ));
+    }
+
+    if srv.can_restart.unwrap_or(false) && srv.restart.is_none() {
+        report.warning(format!(
+            "{place}: `can_restart = true` without `restart` commands, \
+             the service will be stopped and started again"
+        ));
+    }
+
+    if srv.builtin.is_some() && (srv.start.is_some() || srv.ready.is_some()) {
+        report.warning(format!(
+            "{place}: `start` and `ready` are ignored by the builtin service"
+        ));
+    }
+
+    if srv.ready == Some(Ready::Pidfile) && srv.pidfile.is_none() {
+        report.error(format!(
+            "{place}: `ready = \"pidfile\"` requires the `pidfile` parameter"
+        ));
+    }
+
+    for entry in service.paths.iter().flatten() {
+        if let Err(why) = tmpfiles::validate(paths, entry) {
+            report.error(format!("{place}: paths: {why}"));
+        }
+    }
+
+    if let Some(conds) = &service.conditions {
+        if let Err(why) = conditions::validate(conds) {
+            report.error(format!("{place}: conditions: {why}"));
+        }
+    }
+
+    if let Some(health) = &service.health {
+        let probes = [&health.command, &health.tcp, &health.unix]
+            .iter()
+            .filter(|probe| probe.is_some())
+            .count();
+        if probes != 1 {
+            report.error(format!(
+                "{place}: exactly one of `command`, `tcp` and `unix` must be specified in [health]"
+            ));
+        }
+    }
+}
+
+fn check_devices(paths: &Paths, listed: &Listed, report: &mut Report) {
+    let pth = paths.devices_conf();
+    let conf = match DeviceConf::parse(&pth) {
+        Ok(conf) => conf,
+        Err(why) => {
+            report.error(why);
+            return;
+        }
+    };
+
+    for (idx, rule) in conf.rule.iter().flatten().enumerate() {
+        let place = format!("{}: rule {}", pth.display(), idx + 1);
+
+        if let Some(Err(why)) = rule.mode.as_deref().map(tmpfiles::parse_mode) {
+            report.error(format!("{place}: {why}"));
+        }
+        if let Err(why) = tmpfiles::ids(paths, rule.user.as_deref(), rule.group.as_deref()) {
+            report.error(format!("{place}: {why}"));
+        }
+        for cmd in rule.run.iter().flatten() {
+            check_program(paths, &place, cmd, report);
+        }
+        if let Some(service) = rule.service.as_deref() {
+            if !listed.contains_key(service) {
+                report.error(format!("{place}: unknown service {service}"));
+            }
+        }
+    }
+}
+
+/// Checks that the program of the `cmd` command exists in the root
+/// directory and is executable
+fn check_program(paths: &Paths, place: &str, cmd: &str, report: &mut Report) {
+    let prog = match split_args(cmd) {
+        Ok(argv) => argv.into_iter().next(),
+        Err(why) => {
+            report.error(format!("{place}: {why}"));
+            return;
+        }
+    };
+    let Some(prog) = prog else {
+        report.error(format!("{place}: empty command"));
+        return;
+    };
+    let prog = prog.as_str();
+
+    let candidates: Vec<PathBuf> = match prog.contains('/') {
+        true => vec![PathBuf::from(prog)],
+        false => SEARCH_PATH
+            .iter()
+            .map(|dir| Path::new(dir).join(prog))
+            .collect(),
+    };
+
+    let found = candidates
+        .iter()
+        .filter_map(|pth| resolve(paths, pth))
+        .any(|pth| {
+            fs::metadata(pth)
+                .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        });
+
+    if !found {
+        report.error(format!("{place}: {prog}: no such executable"));
+    }
+}
+
+/// Resolves the `pth` inside the root directory like `chroot(2)` would:
+/// absolute symbolic links point into the root, not into the host system
+fn resolve(paths: &Paths, pth: &Path) -> Option<PathBuf> {
+    let mut resolved = PathBuf::from("/");
+    let mut pending: Vec<PathBuf> = pth
+        .components()
+        .rev()
+        .map(|comp| PathBuf::from(comp.as_os_str()))
+        .collect();
+    let mut links = 0;
+
+    while let Some(comp) = pending.pop() {
+        match comp.components().next() {
+            Some(Component::RootDir) => resolved = PathBuf::from("/"),
+            Some(Component::ParentDir) => {
+                resolved.pop();
+            }
+            Some(Component::Normal(name)) => {
+                let next = resolved.join(name);
+                let host = paths.resolve(&next);
+
+                if fs::symlink_metadata(&host).ok()?.is_symlink() {
+                    links += 1;
+                    if links > MAX_SYMLINKS {
+                        return None;
+                    }
+
+                    let target = fs::read_link(&host).ok()?;
+                    pending.extend(
+                        target
+                            .components()
+                            .rev()
+                            .map(|comp| PathBuf::from(comp.as_os_str())),
+                    );
+                } else {
+                    resolved = next;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Some(paths.resolve(resolved))
+}
+
+/// Returns all dependency cycles of the `graph` (service -> dependencies)
+fn find_cycles<'a>(graph: &BTreeMap<&'a str, Vec<&'a str>>) -> Vec<Vec<&'a str>> {
+    fn visit<'a>(
+        node: &'a str,
+        graph: &BTreeMap<&'a str, Vec<&'a str>>,
+        stack: &mut Vec<&'a str>,
+        done: &mut HashSet<&'a str>,
+        cycles: &mut Vec<Vec<&'a str>>,
+    ) {
+        if let Some(pos) = stack.iter().position(|srv| *srv == node) {
+            let mut cycle = stack[pos..].to_vec();
+            cycle.push(node);
+            cycles.push(cycle);
+            return;
+        }
+        if done.contains(node) {
+            return;
+        }
+
+        stack.push(node);
+        for dep in graph.get(node).into_iter().flatten() {
+            visit(dep, graph, stack, done, cycles);
+        }
+        stack.pop();
+        done.insert(node);
+    }
+
+    let mut cycles = vec![];
+    let mut done = HashSet::new();
+    for node in graph.keys() {
+        visit(node, graph, &mut vec![], &mut done, &mut cycles);
+    }
+
+    cycles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_root;
+    use crate::testutil::write;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn check_test() {
+        let root = temp_root("check");
+        let paths = Paths::with_root(&root);
+
+        write(&paths, "/bin/busybox", "");
+        fs::set_permissions(root.join("bin/busybox"), fs::Permissions::from_mode(0o755)).unwrap();
+        symlink("/bin/busybox", root.join("bin/sh")).unwrap();
+        fs::create_dir_all(root.join("usr")).unwrap();
+        symlink("../bin", root.join("usr/bin")).unwrap();
+
+        write(
+            &paths,
+            "/etc/init/init.toml",
+            r#"
+final_runlevel = "rl3"
+
+[[runlevel]]
+dir = "rl1"
+description = "Single user mode"
+services = ["a", "b", "c", "d", "e"]
+login_shell = "/bin/sh"
+
+[[runlevel]]
+dir = "rl2"
+description = "Multi user mode"
+services = ["f"]
+"#,
+        );
+        write(
+            &paths,
+            "/etc/init/rl1/a.toml",
+            "[service]\nstart = [\"/usr/bin/busybox true\"]\n\n[init]\ndescription = \"a\"\nrequires = [\"b\"]\n",
+        );
+        write(
+            &paths,
+            "/etc/init/rl1/b.toml",
+            "[service]\nstart = [\"sh -c true\"]\n\n[init]\ndescription = \"b\"\nwants = [\"a\", \"x\"]\n",
+        );
+        write(
+            &paths,
+            "/etc/init/rl1/c.toml",
+            "[service]\n\n[init]\ndescription = \"c\"\non_eror = \"abort\"\n",
+        );
+        write(
+            &paths,
+            "/etc/init/rl1/e.toml",
+            "[service]\nstart = [\"/sbin/ifconfig lo up\"]\n\n[init]\ndescription = \"e\"\nwants = [\"f\"]\n",
+        );
+        write(
+            &paths,
+            "/etc/init/rl2/f.toml",
+            "[service]\nstart = [\"/bin/sh\"]\n\n[init]\ndescription = \"f\"\nrequires = [\"a\"]\n",
+        );
+
+        let report = check(&paths);
+        let errors = report.errors.join("\n");
+        fs::remove_dir_all(&root).unwrap();
+
+        assert!(errors.contains("final_runlevel: unknown runlevel rl3"));
+        assert!(
+            errors.contains("c.toml:5:1: unknown field `on_eror`"),
+            "{errors}"
+        );
+        assert!(errors.contains("d.toml: service file not found"));
+        assert!(errors.contains("e.toml: /sbin/ifconfig: no such executable"));
+        assert!(errors.contains("b.toml: unknown dependency x"));
+        assert!(errors.contains("e.toml: dependency f belongs to the later runlevel rl2"));
+        assert!(!errors.contains("f.toml"), "{errors}");
+        assert!(errors.contains("dependency cycle: a -> b -> a"));
+        assert_eq!(report.errors.len(), 7, "{errors}");
+    }
+}
@@ -0,0 +1,81 @@
+//! Client of the init control socket
+//!
+//! Used by `service`, `telinit`, `poweroff` and `reboot` to send
+//! requests to the running init.
+
+use anyhow::anyhow;
+use anyhow::Result;
+use std::fmt;
+use std::fmt::Display;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::ErrorKind;
+use std::io::Write;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::control::Command;
+use crate::control::Request;
+use crate::control::Response;
+use crate::control::PROTOCOL_VERSION;
+use crate::paths::Paths;
+
+/// Maximum time to wait for the response of init. Requests are handled
+/// one by one, and starting a service waits for it to become ready
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The control socket can't be connected: init is not running or
+/// doesn't accept requests
+#[derive(Debug)]
+pub struct NotRunning {
+    path: PathBuf,
+    err: io::Error,
+}
+
+impl Display for NotRunning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "init is not running ({}: {})",
+            self.path.display(),
+            self.err
+        )
+    }
+}
+
+impl std::error::Error for NotRunning {}
+
+/// Sends the command to the running init and returns its response.
+/// `Response::Error` is returned as `Err`, a failed connection - as
+/// `NotRunning`
+pub fn request(paths: &Paths, command: Command) -> Result<Response> {
+    let sock = paths.control_socket();
+    let mut stream = UnixStream::connect(&sock).map_err(|err| NotRunning { path: sock, err })?;
+
+    let req = Request {
+        version: PROTOCOL_VERSION,
+        command,
+    };
+    let mut data = serde_json::to_string(&req)?;
+    data.push('\n');
+    stream.write_all(data.as_bytes())?;
+
+    stream.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+    let mut line = String::new();
+    if let Err(why) = BufReader::new(&stream).read_line(&mut line) {
+        return Err(match why.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => anyhow!(
+                "init hasn't responded in {} seconds",
+                RESPONSE_TIMEOUT.as_secs()
+            ),
+            _ => why.into(),
+        });
+    }
+    if line.is_empty() {
+        return Err(anyhow!("init closed the connection without a response"));
+    }
+
+    serde_json::from_str::<Response>(&line)?.into_result()
+}
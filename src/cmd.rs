@@ -2,13 +2,51 @@
 
 use clap::Parser;
 use clap::Subcommand;
+use clap::ValueEnum;
+use std::path::PathBuf;
 
 /// Command line arguments for `poweroff` and `reboot` programs
 #[derive(Parser)]
 pub struct Power {
     /// Force immediate power-off, halt, or reboot
     #[arg(long, short, default_value_t = false)]
-    force: bool,
+    pub force: bool,
+
+    /// Root directory of the system (`/` by default)
+    #[arg(long)]
+    pub root: Option<PathBuf>,
+}
+
+/// Command line arguments for the `telinit` program
+#[derive(Parser)]
+pub struct Telinit {
+    /// Runlevel to switch to (e.g. `rl1` or `1`)
+    pub runlevel: String,
+
+    /// Root directory of the system (`/` by default)
+    #[arg(long)]
+    pub root: Option<PathBuf>,
+}
+
+/// Command line arguments for `init --simulate`. Init doesn't parse its
+/// arguments otherwise because the kernel passes arbitrary ones to it
+#[derive(Parser)]
+pub struct Simulate {
+    /// Print what init would do at boot without executing anything
+    #[arg(long)]
+    pub simulate: bool,
+
+    /// Root directory of the system (`/` by default)
+    #[arg(long)]
+    pub root: Option<PathBuf>,
+
+    /// Kernel command line to use instead of `/proc/cmdline`
+    #[arg(long)]
+    pub cmdline: Option<String>,
+
+    /// Print the plan as JSON
+    #[arg(long, default_value_t = false)]
+    pub json: bool,
 }
 
 /// Command line arguments for the `service` program
@@ -16,6 +54,11 @@ pub struct Power {
 pub struct Service {
     #[command(subcommand)]
     pub command: Option<Commands>,
+
+    /// Root directory of the system (`/` by default), e.g. the rootfs
+    /// of an image
+    #[arg(long, global = true)]
+    pub root: Option<PathBuf>,
 }
 
 #[derive(Subcommand)]
@@ -40,4 +83,77 @@ pub enum Commands {
         #[arg(short, long, default_value_t = false)]
         force: bool,
     },
+
+    /// Makes the specified service re-read its configuration
+    /// without restarting it
+    Reload {
+        service: String,
+    },
+
+    /// Shows information about the specified service
+    Status {
+        service: String,
+    },
+
+    /// Shows the current runlevel and the list of loaded services
+    List,
+
+    /// Switches the running system to the specified runlevel
+    Runlevel {
+        runlevel: String,
+    },
+
+    /// Validates the configuration of init and all service files
+    Check,
+
+    /// Shows the commands init would execute at boot without
+    /// executing anything
+    Plan {
+        /// Kernel command line to use instead of `/proc/cmdline`
+        #[arg(long)]
+        cmdline: Option<String>,
+
+        /// Print the plan as JSON
+        #[arg(long, default_value_t = false)]
+        json: bool,
+    },
+
+    /// Prints the dependency graph of the services
+    Graph {
+        /// Show only the services of this runlevel and their dependencies
+        #[arg(long)]
+        runlevel: Option<String>,
+
+        #[arg(long, value_enum, default_value_t = GraphFormat::Dot)]
+        format: GraphFormat,
+    },
+
+    /// Shows the services sorted by the time they took to start
+    /// during the last boot
+    Blame,
+
+    /// Shows the longest chain of dependencies of the last boot
+    CriticalChain,
+
+    /// Prints the timeline of the last boot
+    Timeline {
+        #[arg(long, value_enum, default_value_t = TimelineFormat::Svg)]
+        format: TimelineFormat,
+    },
+}
+
+/// Output format of `service timeline`
+#[derive(Clone, Copy, ValueEnum)]
+pub enum TimelineFormat {
+    Svg,
+    Html,
+}
+
+/// Output format of `service graph`
+#[derive(Clone, Copy, ValueEnum)]
+pub enum GraphFormat {
+    /// Graphviz
+    Dot,
+    Json,
+    Mermaid,
 }
@@ -0,0 +1,174 @@
+//! Parsing the kernel command line
+//!
+//! Options are passed from the bootloader (e.g. from the U-Boot prompt) and
+//! override the values from the master config file:
+//!
+//! - `init.runlevel=rl1` - runlevel to boot into instead of `final_runlevel`;
+//! - `single`, `S`, `1`, `3`, ... - the same in SysV style;
+//! - `init.skip=svc1,svc2` - services that must not be started;
+//! - `init.debug` - print debug messages;
+//! - `init.quiet` - print only warnings and errors;
+//! - `init.shell[=/bin/sh]` - shell to run instead of the login shell;
+//! - `init.fsck=auto|force|skip` - mode of the filesystem checks;
+//! - `forcefsck`, `fastboot` - the same in SysV style.
+
+use std::fs;
+
+use crate::config::runlevel_dir;
+use crate::config::FsckMode;
+use crate::paths::Paths;
+
+/// Shell used if `init.shell` is specified without a value
+const DEFAULT_SHELL: &str = "/bin/sh";
+
+/// Boot options passed through the kernel command line
+#[derive(Debug, Default, PartialEq)]
+pub struct BootOptions {
+    /// Runlevel to boot into instead of `Config.final_runlevel`
+    pub runlevel: Option<String>,
+
+    /// Services that must not be started
+    pub skip: Vec<String>,
+
+    /// Print debug messages
+    pub debug: bool,
+
+    /// Print only warnings and errors
+    pub quiet: bool,
+
+    /// Shell to run instead of the login shell of the final runlevel
+    pub shell: Option<String>,
+
+    /// Mode of the filesystem checks instead of `Fsck.mode`
+    pub fsck: Option<FsckMode>,
+
+    /// All arguments of the command line. Checked by the `kernel_cmdline`
+    /// start condition of services
+    pub args: Vec<String>,
+}
+
+impl BootOptions {
+    /// Reads boot options from `/proc/cmdline`
+    pub fn read(paths: &Paths) -> Self {
+        let pth = paths.kernel_cmdline();
+        match fs::read_to_string(&pth) {
+            Ok(cmdline) => Self::parse(&cmdline),
+            Err(why) => {
+                eprintln!("init: failed to read {}: {why}", pth.display());
+                Self::default()
+            }
+        }
+    }
+
+    /// Parses the kernel command line. Unknown options are ignored
+    pub fn parse(cmdline: &str) -> Self {
+        let mut opts = Self {
+            args: cmdline.split_whitespace().map(str::to_string).collect(),
+            ..Self::default()
+        };
+
+        for arg in cmdline.split_whitespace() {
+            let (key, val) = match arg.split_once('=') {
+                Some((key, val)) => (key, Some(val)),
+                None => (arg, None),
+            };
+
+            match (key, val) {
+                ("init.runlevel", Some(rl)) if !rl.is_empty() => {
+                    opts.runlevel = Some(runlevel_dir(rl))
+                }
+                ("single" | "S" | "s", None) => opts.runlevel = Some("rl1".to_string()),
+                (rl, None) if rl.len() == 1 && rl.chars().all(|c| c.is_ascii_digit()) => {
+                    opts.runlevel = Some(runlevel_dir(rl))
+                }
+                ("init.skip", Some(services)) => opts.skip.extend(
+                    services
+                        .split(',')
+                        .filter(|srv| !srv.is_empty())
+                        .map(str::to_string),
+                ),
+                ("init.debug", None) => opts.debug = true,
+                ("init.quiet", None) => opts.quiet = true,
+                ("init.shell", None) => opts.shell = Some(DEFAULT_SHELL.to_string()),
+                ("init.shell", Some(sh)) if !sh.is_empty() => opts.shell = Some(sh.to_string()),
+                ("init.fsck", Some("auto")) => opts.fsck = Some(FsckMode::auto),
+                ("init.fsck", Some("force")) | ("forcefsck", None) => {
+                    opts.fsck = Some(FsckMode::force)
+                }
+                ("init.fsck", Some("skip")) | ("fastboot", None) => {
+                    opts.fsck = Some(FsckMode::skip)
+                }
+                _ => {}
+            }
+        }
+
+        opts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_test() {
+        let opts = BootOptions::parse(
+            "console=ttyS0,115200 root=/dev/mmcblk0p2 rw init.runlevel=rl2 \
+             init.skip=syslogd,klogd init.debug init.shell=/bin/ash",
+        );
+
+        assert_eq!(
+            opts,
+            BootOptions {
+                runlevel: Some("rl2".to_string()),
+                skip: vec!["syslogd".to_string(), "klogd".to_string()],
+                debug: true,
+                quiet: false,
+                shell: Some("/bin/ash".to_string()),
+                fsck: None,
+                args: [
+                    "console=ttyS0,115200",
+                    "root=/dev/mmcblk0p2",
+                    "rw",
+                    "init.runlevel=rl2",
+                    "init.skip=syslogd,klogd",
+                    "init.debug",
+                    "init.shell=/bin/ash",
+                ]
+                .map(String::from)
+                .to_vec(),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_sysv_test() {
+        assert_eq!(BootOptions::parse("quiet single").runlevel.unwrap(), "rl1");
+        assert_eq!(BootOptions::parse("ro 3").runlevel.unwrap(), "rl3");
+        assert_eq!(
+            BootOptions::parse("init.runlevel=1").runlevel.unwrap(),
+            "rl1"
+        );
+        assert_eq!(BootOptions::parse("mem=512M 13").runlevel, None);
+        assert_eq!(BootOptions::parse("forcefsck").fsck, Some(FsckMode::force));
+        assert_eq!(
+            BootOptions::parse("init.fsck=skip").fsck,
+            Some(FsckMode::skip)
+        );
+    }
+
+    #[test]
+    fn parse_empty_test() {
+        assert_eq!(BootOptions::parse(""), BootOptions::default());
+        assert_eq!(BootOptions::parse("init.skip= init.shell=").skip.len(), 0);
+        assert_eq!(
+            BootOptions::parse("init.shell init.quiet"),
+            BootOptions {
+                quiet: true,
+                shell: Some("/bin/sh".to_string()),
+                args: vec!["init.shell".to_string(), "init.quiet".to_string()],
+                ..Default::default()
+            }
+        );
+    }
+}
@@ -0,0 +1,376 @@
+//! Start conditions of services
+//!
+//! The `[conditions]` section of a service lists the facts about the
+//! system that must hold for the service to be started: existing paths,
+//! kernel command line arguments, the architecture, virtualization, the
+//! board (by the `compatible` strings of the device tree) and environment
+//! variables. If any condition is not met, the service is not started and
+//! gets the `skipped` state, which is not an error: the services depending
+//! on it are started as usual.
+//!
+//! ```toml
+//! [conditions]
+//! path_exists = ["/sys/class/net/eth0"]
+//! virtualization = ["!container"]
+//! ```
+
+use anyhow::anyhow;
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use nix::sys::utsname::uname;
+
+use crate::cmdline::BootOptions;
+use crate::paths::Paths;
+use crate::service::ConditionsSection;
+
+/// Strings found in the DMI data of the virtual machines and the names of
+/// the hypervisors
+const DMI_VENDORS: [(&str, &str); 10] = [
+    ("KVM", "kvm"),
+    ("QEMU", "qemu"),
+    ("VMware", "vmware"),
+    ("VirtualBox", "oracle"),
+    ("innotek", "oracle"),
+    ("Xen", "xen"),
+    ("Microsoft Corporation\nVirtual Machine", "microsoft"),
+    ("Parallels", "parallels"),
+    ("Bochs", "bochs"),
+    ("Amazon EC2", "amazon"),
+];
+
+/// Facts about the system checked by the conditions
+#[derive(Debug, Default)]
+pub struct Host {
+    /// Arguments of the kernel command line
+    pub cmdline: Vec<String>,
+
+    /// Names of the machine architecture (`uname -m` and the one of Rust)
+    pub architecture: Vec<String>,
+
+    /// `none` or the kind of virtualization (`vm` or `container`) followed
+    /// by the name of the technology if it is known
+    pub virtualization: Vec<String>,
+
+    /// `compatible` strings of the device tree
+    pub compatible: Vec<String>,
+
+    /// Environment variables of init
+    pub env: BTreeMap<String, String>,
+}
+
+impl Host {
+    /// Collects the facts about the running system
+    pub fn detect(paths: &Paths, opts: &BootOptions) -> Self {
+        let env: BTreeMap<String, String> = env::vars().collect();
+
+        let mut architecture = vec![env::consts::ARCH.to_string()];
+        if let Ok(uts) = uname() {
+            let machine = uts.machine().to_string_lossy().to_string();
+            if !architecture.contains(&machine) {
+                architecture.push(machine);
+            }
+        }
+
+        Self {
+            cmdline: opts.args.clone(),
+            architecture,
+            virtualization: virtualization(paths, &env),
+            compatible: nul_separated(&paths.dt_compatible()),
+            env,
+        }
+    }
+
+    /// Whether the kernel command line contains the argument. `NAME` also
+    /// matches `NAME=VALUE`
+    fn has_arg(&self, arg: &str) -> bool {
+        self.cmdline.iter().any(|cur| {
+            cur == arg
+                || (!arg.contains('=') && cur.split_once('=').is_some_and(|(key, _)| key == arg))
+        })
+    }
+
+    /// Whether the variable is set (`NAME`) or has the value (`NAME=VALUE`)
+    fn has_env(&self, var: &str) -> bool {
+        match var.split_once('=') {
+            Some((name, value)) => self.env.get(name).is_some_and(|cur| cur == value),
+            None => self.env.contains_key(var),
+        }
+    }
+}
+
+/// Returns the first condition that is not met
+pub fn unmet(paths: &Paths, conds: &ConditionsSection, host: &Host) -> Option<String> {
+    let exists = |pth: &str| paths.resolve(pth).symlink_metadata().is_ok();
+    let not_empty = |pth: &str| {
+        fs::metadata(paths.resolve(pth)).is_ok_and(|meta| meta.is_file() && meta.len() > 0)
+    };
+    let contains = |list: &[String], value: &str| list.iter().any(|cur| cur == value);
+
+    let checks = [
+        ("path_exists", all_of(&conds.path_exists, exists)),
+        (
+            "path_not_exists",
+            all_of(&conds.path_not_exists, |pth| !exists(pth)),
+        ),
+        ("file_not_empty", all_of(&conds.file_not_empty, not_empty)),
+        (
+            "kernel_cmdline",
+            all_of(&conds.kernel_cmdline, |arg| host.has_arg(arg)),
+        ),
+        (
+            "architecture",
+            any_of(&conds.architecture, |arch| {
+                contains(&host.architecture, arch)
+            }),
+        ),
+        (
+            "virtualization",
+            any_of(&conds.virtualization, |virt| {
+                contains(&host.virtualization, virt)
+            }),
+        ),
+        (
+            "device_tree_compatible",
+            any_of(&conds.device_tree_compatible, |compat| {
+                contains(&host.compatible, compat)
+            }),
+        ),
+        ("env", all_of(&conds.env, |var| host.has_env(var))),
+    ];
+
+    checks
+        .into_iter()
+        .find_map(|(name, unmet)| unmet.map(|value| format!("{name} = {value}")))
+}
+
+/// Checks that the paths are absolute and the values are not empty
+pub fn validate(conds: &ConditionsSection) -> Result<()> {
+    let paths = [
+        ("path_exists", &conds.path_exists),
+        ("path_not_exists", &conds.path_not_exists),
+        ("file_not_empty", &conds.file_not_empty),
+    ];
+    for (name, values) in paths {
+        let pth = values
+            .iter()
+            .flatten()
+            .find(|pth| !pth.trim_start_matches('!').starts_with('/'));
+        if let Some(pth) = pth {
+            return Err(anyhow!("{name}: path {pth} is not absolute"));
+        }
+    }
+
+    let values = [
+        ("kernel_cmdline", &conds.kernel_cmdline),
+        ("architecture", &conds.architecture),
+        ("virtualization", &conds.virtualization),
+        ("device_tree_compatible", &conds.device_tree_compatible),
+        ("env", &conds.env),
+    ];
+    for (name, values) in values {
+        let empty = values
+            .iter()
+            .flatten()
+            .any(|value| value.trim_start_matches('!').is_empty());
+        if empty {
+            return Err(anyhow!("{name}: empty value"));
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks the value negated with `!`
+fn holds<F: Fn(&str) -> bool>(value: &str, test: &F) -> bool {
+    match value.strip_prefix('!') {
+        Some(value) => !test(value),
+        None => test(value),
+    }
+}
+
+/// Checks that every value holds. Returns the first one that doesn't
+fn all_of<F: Fn(&str) -> bool>(values: &Option<Vec<String>>, test: F) -> Option<String> {
+    values
+        .iter()
+        .flatten()
+        .find(|value| !holds(value, &test))
+        .cloned()
+}
+
+/// Checks that any of the values holds and none of the negated ones is
+/// true. Returns the unmet values
+fn any_of<F: Fn(&str) -> bool>(values: &Option<Vec<String>>, test: F) -> Option<String> {
+    let (negated, values): (Vec<&String>, Vec<&String>) = values
+        .iter()
+        .flatten()
+        .partition(|value| value.starts_with('!'));
+
+    if let Some(value) = negated.iter().find(|value| !holds(value, &test)) {
+        return Some(value.to_string());
+    }
+    match values.is_empty() || values.iter().any(|value| test(value)) {
+        true => None,
+        false => Some(
+            values
+                .iter()
+                .map(|value| value.as_str())
+                .collect::<Vec<_>>()
+                .join(" | "),
+        ),
+    }
+}
+
+/// Reads the list of NUL-separated strings (used by the device tree)
+fn nul_separated(pth: &Path) -> Vec<String> {
+    fs::read(pth)
+        .unwrap_or_default()
+        .split(|byte| *byte == 0)
+        .filter(|value| !value.is_empty())
+        .map(|value| String::from_utf8_lossy(value).to_string())
+        .collect()
+}
+
+/// Detects the container or the virtual machine init is running in
+pub fn virtualization(paths: &Paths, env: &BTreeMap<String, String>) -> Vec<String> {
+    let found = |kind: &str, name: &str| vec![kind.to_string(), name.to_string()];
+
+    // container managers pass `container=NAME` to their init
+    if let Some(name) = env.get("container").filter(|name| !name.is_empty()) {
+        return found("container", name);
+    }
+    let container_files = [
+        (paths.dockerenv(), "docker"),
+        (paths.containerenv(), "podman"),
+    ];
+    for (pth, name) in container_files {
+        if pth.exists() {
+            return found("container", name);
+        }
+    }
+
+    let read = |pth: PathBuf| fs::read_to_string(pth).unwrap_or_default();
+    if read(paths.hypervisor_type()).trim() == "xen" {
+        return found("vm", "xen");
+    }
+
+    let dmi = ["sys_vendor", "product_name", "board_vendor", "bios_vendor"]
+        .map(|name| read(paths.dmi_dir().join(name)).trim().to_string())
+        .join("\n");
+    for (pattern, name) in DMI_VENDORS {
+        if dmi.contains(pattern) {
+            return found("vm", name);
+        }
+    }
+
+    let hypervisor = nul_separated(&paths.dt_hypervisor());
+    if hypervisor.iter().any(|compat| compat == "linux,kvm") {
+        return found("vm", "kvm");
+    }
+    if hypervisor.iter().any(|compat| compat.starts_with("xen")) {
+        return found("vm", "xen");
+    }
+
+    // the hypervisor is unknown, but the CPU reports that it is virtual
+    let cpuinfo = read(paths.cpuinfo());
+    let is_virtual = cpuinfo
+        .lines()
+        .filter(|line| line.starts_with("flags"))
+        .any(|line| line.split_whitespace().any(|flag| flag == "hypervisor"));
+    match is_virtual {
+        true => vec!["vm".to_string()],
+        false => vec!["none".to_string()],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_root;
+    use crate::testutil::write;
+    use std::fs::File;
+
+    #[test]
+    fn unmet_test() {
+        let root = temp_root("conditions");
+        let paths = Paths::with_root(&root);
+        write(&paths, "/sys/class/net/eth0/address", "02:00:00:00:00:01\n");
+        File::create(paths.resolve("/sys/class/net/eth0/carrier")).unwrap();
+
+        let host = Host {
+            cmdline: ["console=ttyS0,115200", "quiet"].map(String::from).to_vec(),
+            architecture: vec!["aarch64".to_string()],
+            virtualization: vec!["none".to_string()],
+            compatible: ["raspberrypi,4-model-b", "brcm,bcm2711"]
+                .map(String::from)
+                .to_vec(),
+            env: BTreeMap::from([("TERM".to_string(), "linux".to_string())]),
+        };
+        let conds = |toml: &str| toml::from_str::<ConditionsSection>(toml).unwrap();
+        let unmet = |toml: &str| unmet(&paths, &conds(toml), &host);
+
+        assert_eq!(
+            unmet(
+                r#"
+path_exists = ["/sys/class/net/eth0"]
+path_not_exists = ["/sys/class/net/wlan0"]
+file_not_empty = ["/sys/class/net/eth0/address"]
+kernel_cmdline = ["console", "quiet", "!init.debug"]
+architecture = ["armv7l", "aarch64"]
+virtualization = ["!vm", "!container"]
+device_tree_compatible = ["raspberrypi,4-model-b", "raspberrypi,5-model-b"]
+env = ["TERM=linux", "!container"]
+"#
+            ),
+            None
+        );
+
+        assert_eq!(
+            unmet(r#"path_exists = ["/sys/class/net/wlan0"]"#).unwrap(),
+            "path_exists = /sys/class/net/wlan0"
+        );
+        assert!(unmet(r#"file_not_empty = ["/sys/class/net/eth0/carrier"]"#).is_some());
+        assert!(unmet(r#"kernel_cmdline = ["console=tty0"]"#).is_some());
+        assert_eq!(
+            unmet(r#"architecture = ["x86_64", "i686"]"#).unwrap(),
+            "architecture = x86_64 | i686"
+        );
+        assert_eq!(
+            unmet(r#"virtualization = ["!none"]"#).unwrap(),
+            "virtualization = !none"
+        );
+        assert!(unmet(r#"device_tree_compatible = ["allwinner,sun8i-h3"]"#).is_some());
+        assert!(unmet(r#"env = ["TERM=vt100"]"#).is_some());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn virtualization_test() {
+        let root = temp_root("virt");
+        let paths = Paths::with_root(&root);
+        let env = BTreeMap::new();
+
+        write(
+            &paths,
+            "/proc/cpuinfo",
+            "processor\t: 0\nflags\t\t: fpu vme de pse\n",
+        );
+        assert_eq!(virtualization(&paths, &env), ["none"]);
+
+        write(&paths, "/sys/class/dmi/id/sys_vendor", "QEMU\n");
+        assert_eq!(virtualization(&paths, &env), ["vm", "qemu"]);
+
+        write(&paths, "/.dockerenv", "");
+        assert_eq!(virtualization(&paths, &env), ["container", "docker"]);
+
+        let env = BTreeMap::from([("container".to_string(), "lxc".to_string())]);
+        assert_eq!(virtualization(&paths, &env), ["container", "lxc"]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,12 +1,13 @@
 //! Contains the declaration of all LFA init configuration files
 
+use anyhow::anyhow;
 use anyhow::Result;
 use serde::Deserialize;
 use serde::Serialize;
 
-use nix::sys::reboot::reboot;
 use nix::sys::reboot::RebootMode;
 
+use crate::supervisor::Supervisor;
 use crate::traits::TomlConfig;
 
 /// Master config file of the LFA init
@@ -14,12 +15,40 @@ use crate::traits::TomlConfig;
 /// Contains a list of runlevels and the order in which services that
 /// belong to each runlevel are loaded.
 #[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     /// Last runlevel loaded
     pub final_runlevel: String,
 
     /// List of runlevels
     pub runlevel: Vec<Runlevel>,
+
+    /// Emergency mode settings
+    pub emergency: Option<Emergency>,
+
+    /// Action to be performed if init panics
+    pub on_panic: Option<OnPanic>,
+
+    /// Filesystems mounted by init before the first runlevel. The API
+    /// filesystems and `/etc/fstab` are mounted if this section is not
+    /// specified, `enabled = false` turns mounting off
+    pub early_mount: Option<EarlyMount>,
+
+    /// Checking of the filesystems from `/etc/fstab` before they are
+    /// mounted. Nothing is checked if this section is not specified
+    pub fsck: Option<Fsck>,
+
+    /// Swap areas activated by init after the filesystems are mounted.
+    /// Nothing is activated if this section is not specified
+    pub swap: Option<Swap>,
+
+    /// Kernel modules loaded by init before the swap is activated.
+    /// Nothing is loaded if this section is not specified
+    pub modules: Option<Modules>,
+
+    /// Hostname set by the `hostname` builtin if `/etc/hostname` doesn't
+    /// exist. Default: `localhost`
+    pub hostname: Option<String>,
 }
 
 /// Runlevel configuration
@@ -27,7 +56,8 @@ pub struct Config {
 /// **Runlevel** - a certain stage of LFA loading, at which a number of strictly defined
 /// components (services) are loaded. By default there are 6 runlevels in the system,
 /// but the user can define his own if necessary.
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Runlevel {
     /// The directory with the services of this runlevel and it's name
     pub dir: String,
@@ -52,16 +82,21 @@ pub struct Runlevel {
     /// Path to the login shell program that will be executed when all
     /// services of this runlevel have finished loading. The login shell
     /// will run only if this `runlevel` is specified in the
-    /// `final_runlevel` parameter.
+    /// `final_runlevel` parameter. Also used by the `Action::shell` action.
     pub login_shell: Option<String>,
+
+    /// Program (with arguments) that replaces init when switching to
+    /// this runlevel. Used only if `action = Action::exec`.
+    pub exec: Option<String>,
 }
 
 /// Action to be performed by the initialization system
 /// when switching to certain runlevel. Default: `Action::run_service`
-#[derive(Deserialize, Serialize, Clone, Copy)]
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
 #[allow(non_camel_case_types)]
 pub enum Action {
     /// Sequentially starts the services specified in the `Config.services` parameter
+    #[default]
     run_services,
 
     /// Terminates running services and shuts down the system
@@ -69,56 +104,261 @@ pub enum Action {
 
     /// Terminates running services and reboots the system
     reboot,
+
+    /// Replaces init with the program specified in the `Runlevel.exec` parameter
+    exec,
+
+    /// Runs the `Runlevel.login_shell` (`/bin/sh` by default) on the console
+    /// and waits for it to exit
+    shell,
 }
 
-/// Contains a list of running services
+/// Emergency mode configuration
 ///
-/// This list is used to shut down the system and also to view information about running services
-#[derive(Deserialize, Serialize)]
-pub struct LoadedServices {
-    pub service: Vec<LdSrv>,
+/// Emergency mode is entered when a service with `on_error = "abort"` fails.
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Emergency {
+    /// Rescue shell to run on the console. Default: `/bin/sh`
+    pub shell: Option<String>,
+
+    /// Whether to ask for the root password before running the
+    /// rescue shell. Default: `Some(true)`
+    pub require_password: Option<bool>,
+
+    /// Whether to run the rescue shell without a password if the root
+    /// password can't be read from `/etc/shadow`. Default: `Some(false)`
+    pub force: Option<bool>,
+
+    /// What to do when the rescue shell exits. Default: `EmergencyExit::continue`
+    pub on_exit: Option<EmergencyExit>,
 }
 
-/// Information about the running service
-#[derive(Deserialize, Serialize)]
-pub struct LdSrv {
-    /// Runlevel
-    pub rl: String,
+/// Action to be performed when the rescue shell exits
+#[derive(Deserialize, Serialize, Clone, Copy, Default)]
+#[allow(non_camel_case_types)]
+pub enum EmergencyExit {
+    /// Continue booting
+    #[default]
+    r#continue,
+
+    /// Stop all services and reboot the system
+    reboot,
+}
+
+/// Mounting of the API filesystems by init itself
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct EarlyMount {
+    /// Default: `Some(true)`
+    pub enabled: Option<bool>,
 
-    /// Service name
-    pub name: String,
+    /// Whether to mount the filesystems from `/etc/fstab` after the
+    /// API filesystems. Default: `Some(true)`
+    pub fstab: Option<bool>,
 
-    /// Return code
-    pub code: i32,
+    /// Filesystems to mount in this order. Default: `/proc`, `/sys`,
+    /// `/dev`, `/dev/pts`, `/dev/shm`, `/run` and `/tmp`
+    pub mount: Option<Vec<MountPoint>>,
 }
 
-impl Action {
-    /// Performs a reboot or system shutdown
-    pub fn take(&self) -> Result<()> {
-        match self {
-            Self::run_services => {
-                eprintln!("/sbin/init: WARNING: No implementation is provided for the `run_services` action.");
-            }
-            Self::power_off => {
-                reboot(RebootMode::RB_POWER_OFF)?;
-            }
-            Self::reboot => {
-                reboot(RebootMode::RB_AUTOBOOT)?;
+/// Filesystem mounted by init
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MountPoint {
+    /// Default: the same as `fstype`
+    pub source: Option<String>,
+
+    pub target: String,
+    pub fstype: String,
+
+    /// Comma-separated options like in `/etc/fstab` (`nosuid,mode=0755`)
+    pub options: Option<String>,
+}
+
+/// Checking of the filesystems with a non-zero pass number in `/etc/fstab`
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Fsck {
+    /// Default: `Some(true)`
+    pub enabled: Option<bool>,
+
+    /// Default: `FsckMode::auto`. Can be overridden by the `init.fsck`
+    /// kernel option
+    pub mode: Option<FsckMode>,
+
+    /// Checking program. Default: `/sbin/fsck`
+    pub program: Option<String>,
+
+    /// Arguments passed to the program before the filesystem type and
+    /// the device. Default: `["-a"]`
+    pub args: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[allow(non_camel_case_types)]
+pub enum FsckMode {
+    /// The program decides whether the filesystem needs a check
+    #[default]
+    auto,
+
+    /// Check all filesystems even if they are clean (`-f`)
+    force,
+
+    /// Don't check the filesystems
+    skip,
+}
+
+/// Swap areas activated with `swapon(2)`
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Swap {
+    /// Default: `Some(true)`
+    pub enabled: Option<bool>,
+
+    /// Whether to activate the swap entries of `/etc/fstab`.
+    /// Default: `Some(true)`
+    pub fstab: Option<bool>,
+
+    /// Swap files activated in addition to `/etc/fstab`
+    pub file: Option<Vec<SwapFile>>,
+
+    /// Compressed swap in RAM
+    pub zram: Option<Zram>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SwapFile {
+    /// Path to the swap file (or device)
+    pub path: String,
+
+    /// Size of the file created if it doesn't exist (`512M`, `1G`, `25%`
+    /// of RAM). If not specified, the file must already exist
+    pub size: Option<String>,
+
+    /// Priority from `0` to `32767`. Default: assigned by the kernel
+    pub priority: Option<i32>,
+}
+
+/// Swap on the `/dev/zram0` device. The zram module must be loaded
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Zram {
+    /// Default: `Some(true)`
+    pub enabled: Option<bool>,
+
+    /// Size of the uncompressed data (`256M`, `1G` or the percentage of
+    /// RAM). Default: `50%`
+    pub size: Option<String>,
+
+    /// Compression algorithm (`lzo-rle`, `lz4`, `zstd`, ...). Default:
+    /// the default one of the kernel
+    pub algorithm: Option<String>,
+
+    /// Default: `Some(100)`, so zram is used before the swap on disk
+    pub priority: Option<i32>,
+}
+
+/// Kernel modules from `/etc/modules` and `/etc/modules-load.d/*.conf`
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Modules {
+    /// Default: `Some(true)`
+    pub enabled: Option<bool>,
+
+    /// Modules loaded in addition to the files, one per item with its
+    /// parameters (`"g_ether host_addr=02:00:00:00:00:01"`)
+    pub load: Option<Vec<String>>,
+
+    /// Command loading a module, called with the module name and its
+    /// parameters (`/sbin/modprobe -q`). Default: init loads the module
+    /// and its dependencies from `modules.dep` itself
+    pub modprobe: Option<String>,
+}
+
+/// Action to be performed if init panics
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct OnPanic {
+    /// Default: `PanicAction::shell`
+    pub action: Option<PanicAction>,
+
+    /// Delay in seconds before the `PanicAction::reboot`. Default: `Some(10)`
+    pub delay: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Default)]
+#[allow(non_camel_case_types)]
+pub enum PanicAction {
+    /// Run the rescue shell (see `Emergency`) and reboot the system after it exits
+    #[default]
+    shell,
+
+    /// Reboot the system after `OnPanic.delay` seconds
+    reboot,
+
+    /// Halt the system
+    halt,
+
+    /// Do nothing and hang forever
+    freeze,
+}
+
+impl Config {
+    /// Returns the runlevel whose list of services contains `service`
+    pub fn find_service(&self, service: &str) -> Option<&Runlevel> {
+        self.runlevel.iter().find(|rl| {
+            rl.services
+                .as_ref()
+                .is_some_and(|services| services.iter().any(|srv| srv == service))
+        })
+    }
+}
+
+/// Converts the runlevel name passed by the user to the name of its
+/// directory: SysV-style numbers (`1`) are turned into `rl1`
+pub fn runlevel_dir(name: &str) -> String {
+    if !name.is_empty() && name.chars().all(|c| c.is_ascii_digit()) {
+        format!("rl{name}")
+    } else {
+        name.to_string()
+    }
+}
+
+impl Runlevel {
+    /// Performs the action of this runlevel
+    pub fn enter(&self, sv: &mut Supervisor) -> Result<()> {
+        match self.action.unwrap_or_default() {
+            Action::run_services => sv.start_services(self),
+            Action::power_off => sv.shutdown(RebootMode::RB_POWER_OFF),
+            Action::reboot => sv.shutdown(RebootMode::RB_AUTOBOOT),
+            Action::exec => match &self.exec {
+                Some(prog) => sv.exec(prog),
+                None => Err(anyhow!("the `exec` parameter is not specified")),
+            },
+            Action::shell => {
+                let shell = self.login_shell.as_deref().unwrap_or("/bin/sh");
+                sv.run_shell(shell)
             }
         }
-
-        Ok(())
     }
 }
 
 impl TomlConfig for Config {}
-impl TomlConfig for LoadedServices {}
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             final_runlevel: "rl1".to_string(),
             runlevel: vec![Runlevel::default()],
+            emergency: None,
+            on_panic: None,
+            early_mount: Some(EarlyMount::default()),
+            fsck: Some(Fsck::default()),
+            swap: Some(Swap::default()),
+            modules: Some(Modules::default()),
+            hostname: None,
         }
     }
 }
@@ -130,31 +370,14 @@ impl Default for Runlevel {
             r#use: Some(true),
             description: "Single user mode".to_string(),
             action: Some(Action::default()),
-            services: Some(vec![
-                "mount_procfs".to_string(),
-                "mount_sysfs".to_string(),
-                "mount_tmpfs".to_string(),
-                "mount_devpts".to_string(),
-                "mount_devshm".to_string(),
-            ]),
+            // API filesystems are mounted by init itself
+            services: Some(vec![]),
             login_shell: Some("/bin/login".to_string()),
+            exec: None,
         }
     }
 }
 
-impl Default for Action {
-    fn default() -> Self {
-        Self::run_services
-    }
-}
-
-impl Default for LoadedServices {
-    fn default() -> Self {
-        Self {
-            service: vec![],
-        }
-    }
-}
 
 #[cfg(test)]
 mod tests {
@@ -170,4 +393,17 @@ mod tests {
     fn read_test() {
         let _ = Config::parse("data/init.toml").unwrap();
     }
+
+    #[test]
+    fn runlevel_dir_test() {
+        assert_eq!(runlevel_dir("1"), "rl1");
+        assert_eq!(runlevel_dir("rl3"), "rl3");
+    }
+
+    #[test]
+    fn find_service_test() {
+        let conf = Config::parse("data/init.toml").unwrap();
+        assert_eq!(conf.find_service("syslogd").unwrap().dir, "rl2");
+        assert!(conf.find_service("unknown").is_none());
+    }
 }
@@ -6,5 +6,124 @@ pub const CONF_DIR: &str = "/etc/init/";
 /// containing startup level declarations and associated services
 pub const INIT_MASTER_CONF_FILE: &str = "/etc/init/init.toml";
 
-/// Path to the config with information about loaded services
-pub const LOADED_SERVICES_CONF_FILE: &str = "/var/log/ld_srv.toml.log";
+/// Directory with the runtime state of services written by the running init
+pub const STATE_DIR: &str = "/run/init/state";
+
+/// Control socket through which the running init receives requests
+/// (e.g. from `service` and `telinit`)
+pub const CONTROL_SOCKET: &str = "/run/init.sock";
+
+/// Kernel command line with boot options
+pub const KERNEL_CMDLINE_FILE: &str = "/proc/cmdline";
+
+/// Password database used to authenticate root in emergency mode
+pub const SHADOW_FILE: &str = "/etc/shadow";
+
+/// Kernel log buffer. Init writes critical messages to it
+pub const KMSG_FILE: &str = "/dev/kmsg";
+
+/// Directory with the `NOTIFY_SOCKET`s of services using `ready = "notify"`
+pub const NOTIFY_DIR: &str = "/run/init/notify";
+
+/// Timings of the last boot written by init
+pub const BOOT_RECORD_FILE: &str = "/run/init/boot.toml";
+
+/// Directory with the `tmpfiles.d`-style lists of files and directories
+/// created at boot
+pub const TMPFILES_DIR: &str = "/etc/init/tmpfiles.d";
+
+/// User database used to resolve the owners of the created files
+pub const PASSWD_FILE: &str = "/etc/passwd";
+
+/// Group database used to resolve the groups of the created files
+pub const GROUP_FILE: &str = "/etc/group";
+
+/// Rules of the device manager handling the kernel uevents
+pub const DEVICES_CONF_FILE: &str = "/etc/init/devices.toml";
+
+/// Table of the filesystems mounted in the namespace of init
+pub const MOUNTINFO_FILE: &str = "/proc/self/mountinfo";
+
+/// Filesystems mounted at boot and unmounted at shutdown
+pub const FSTAB_FILE: &str = "/etc/fstab";
+
+/// Symlinks to the block devices by their `UUID`, `LABEL` and so on
+pub const DISK_DIR: &str = "/dev/disk";
+
+/// Block devices probed if there are no `/dev/disk/by-*` links
+pub const SYS_BLOCK_DIR: &str = "/sys/class/block";
+
+/// Active swap areas
+pub const SWAPS_FILE: &str = "/proc/swaps";
+
+/// Memory statistics, used to size the zram device
+pub const MEMINFO_FILE: &str = "/proc/meminfo";
+
+/// Random UUID generated by the kernel on each read
+pub const UUID_FILE: &str = "/proc/sys/kernel/random/uuid";
+
+/// Compressed swap device in RAM
+pub const ZRAM_DEVICE: &str = "/dev/zram0";
+
+/// Attributes of the zram device
+pub const ZRAM_SYS_DIR: &str = "/sys/block/zram0";
+
+/// Hostname set by the builtin `hostname` task
+pub const HOSTNAME_FILE: &str = "/etc/hostname";
+
+/// Kernel parameters applied by the builtin `sysctl` task
+pub const SYSCTL_FILE: &str = "/etc/sysctl.conf";
+
+/// Directory with additional kernel parameters (`*.conf`)
+pub const SYSCTL_DIR: &str = "/etc/sysctl.d";
+
+/// Kernel parameters
+pub const PROC_SYS_DIR: &str = "/proc/sys";
+
+/// Network interfaces
+pub const SYS_NET_DIR: &str = "/sys/class/net";
+
+/// Kernel modules loaded at boot, one per line
+pub const MODULES_FILE: &str = "/etc/modules";
+
+/// Directory with additional lists of the modules loaded at boot (`*.conf`)
+pub const MODULES_LOAD_DIR: &str = "/etc/modules-load.d";
+
+/// Loaded kernel modules
+pub const PROC_MODULES_FILE: &str = "/proc/modules";
+
+/// Directory with the modules of the installed kernels. Paths in
+/// `modules.dep` are relative to its `<release>` subdirectory
+pub const MODULES_DIR: &str = "/lib/modules";
+
+/// Directory with the `uevent` files of all devices
+pub const SYS_DEVICES_DIR: &str = "/sys/devices";
+
+/// Program run by the kernel for each uevent (`/sbin/mdev`). Init handles
+/// the uevents itself, so it is disabled
+pub const HOTPLUG_FILE: &str = "/proc/sys/kernel/hotplug";
+
+/// Directory with the device nodes. Relative node names from the uevents
+/// are resolved against it
+pub const DEV_DIR: &str = "/dev";
+
+/// `compatible` strings of the board, separated by NUL
+pub const DT_COMPATIBLE_FILE: &str = "/proc/device-tree/compatible";
+
+/// `compatible` strings of the hypervisor on ARM
+pub const DT_HYPERVISOR_FILE: &str = "/proc/device-tree/hypervisor/compatible";
+
+/// Type of the hypervisor on Xen
+pub const HYPERVISOR_FILE: &str = "/sys/hypervisor/type";
+
+/// Vendor and product of the machine from the firmware (DMI)
+pub const DMI_DIR: &str = "/sys/class/dmi/id";
+
+/// Description of the CPUs, including the `hypervisor` flag
+pub const CPUINFO_FILE: &str = "/proc/cpuinfo";
+
+/// File created by Docker in the root of its containers
+pub const DOCKERENV_FILE: &str = "/.dockerenv";
+
+/// File created by Podman in its containers
+pub const CONTAINERENV_FILE: &str = "/run/.containerenv";
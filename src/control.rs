@@ -0,0 +1,479 @@
+//! Control socket of the running init
+//!
+//! Init listens on the `/run/init.sock` Unix domain socket. Each
+//! connection carries a single request and a single response, both
+//! serialized as one line of JSON. Requests that change the state of
+//! the system are accepted only from `root`.
+//!
+//! Init never blocks on its clients: connections are non-blocking and
+//! polled by the main loop together with other events, and a client that
+//! doesn't send its request in time is disconnected.
+
+use anyhow::anyhow;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fs;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::os::fd::AsFd;
+use std::os::fd::BorrowedFd;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+
+use nix::sys::reboot::RebootMode;
+use nix::sys::socket::getsockopt;
+use nix::sys::socket::sockopt::PeerCredentials;
+
+use crate::paths::Paths;
+use crate::state::ServiceRecord;
+use crate::supervisor::Supervisor;
+
+/// Version of the control protocol. Requests with a different
+/// version are rejected
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Maximum time to wait for a client to send its request
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Maximum time to wait for a client to read its response
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Maximum number of connections waiting for their requests. New
+/// connections are refused when it is reached
+const MAX_CLIENTS: usize = 16;
+
+/// Maximum length of a request in bytes
+const MAX_REQUEST: usize = 64 * 1024;
+
+/// Request to the running init
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct Request {
+    /// Version of the protocol used by the client
+    pub version: u32,
+
+    pub command: Command,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Command {
+    /// Start the service
+    Start { service: String },
+
+    /// Stop the service
+    Stop { service: String },
+
+    /// Restart the service
+    Restart { service: String, force: bool },
+
+    /// Make the service re-read its configuration
+    Reload { service: String },
+
+    /// Get information about the service
+    Status { service: String },
+
+    /// Get the current runlevel and the list of loaded services
+    List,
+
+    /// Switch to the runlevel
+    Runlevel { runlevel: String },
+
+    /// Stop all services and power off (`reboot = false`) or
+    /// reboot (`reboot = true`) the system
+    Shutdown { reboot: bool },
+}
+
+/// Response of the running init
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Response {
+    /// The request has been completed
+    Ok,
+
+    /// The request has failed
+    Error { message: String },
+
+    /// Information about the service
+    Status { service: ServiceRecord },
+
+    /// Current runlevel and the list of loaded services
+    List {
+        runlevel: Option<String>,
+        services: Vec<ServiceRecord>,
+    },
+}
+
+impl Command {
+    /// Whether this command changes the state of the system
+    fn is_mutating(&self) -> bool {
+        !matches!(self, Self::Status { .. } | Self::List)
+    }
+}
+
+impl Response {
+    fn error<D: std::fmt::Display>(err: D) -> Self {
+        Self::Error {
+            message: err.to_string(),
+        }
+    }
+
+    fn from_result(res: Result<()>) -> Self {
+        match res {
+            Ok(()) => Self::Ok,
+            Err(why) => Self::error(why),
+        }
+    }
+
+    /// Converts `Response::Error` to `Err`
+    pub fn into_result(self) -> Result<Self> {
+        match self {
+            Self::Error { message } => Err(anyhow!(message)),
+            resp => Ok(resp),
+        }
+    }
+}
+
+/// Listening end of the control socket owned by init
+pub struct ControlServer {
+    listener: UnixListener,
+    path: PathBuf,
+
+    /// Connections whose requests haven't been received completely yet
+    clients: Vec<Client>,
+}
+
+/// Connection to the control socket
+struct Client {
+    stream: UnixStream,
+
+    /// User of the peer process, taken when the connection is accepted
+    uid: u32,
+
+    /// Part of the request received so far
+    buf: Vec<u8>,
+
+    /// Time the connection has been accepted
+    since: Instant,
+}
+
+/// Result of reading from the client
+enum Received {
+    /// The whole request has been received
+    Request(String),
+
+    /// The rest of the request hasn't arrived yet
+    Pending,
+
+    /// The client has closed the connection without sending anything
+    Closed,
+}
+
+impl ControlServer {
+    /// Creates the control socket
+    pub fn bind(paths: &Paths) -> Result<Self> {
+        let path = paths.control_socket();
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+
+        // everyone can query the state of the system, permissions to
+        // change it are checked for each request
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o666))?;
+        listener.set_nonblocking(true)?;
+
+        Ok(Self {
+            listener,
+            path,
+            clients: vec![],
+        })
+    }
+
+    /// Returns the listening socket and the connections to be polled for
+    /// incoming data
+    pub fn fds(&self) -> Vec<BorrowedFd<'_>> {
+        let clients = self.clients.iter().map(|client| client.stream.as_fd());
+        [self.listener.as_fd()].into_iter().chain(clients).collect()
+    }
+
+    /// Accepts new connections and handles the requests that have been
+    /// received completely. Never waits for the clients
+    pub fn serve(&mut self, sv: &mut Supervisor) {
+        self.accept();
+
+        for mut client in std::mem::take(&mut self.clients) {
+            match client.receive() {
+                Ok(Received::Request(line)) => {
+                    if let Err(why) = handle(client.stream, client.uid, &line, sv) {
+                        eprintln!("init: {}: {why}", self.path.display());
+                    }
+                }
+                Ok(Received::Pending) if client.since.elapsed() < CLIENT_TIMEOUT => {
+                    self.clients.push(client);
+                }
+                Ok(Received::Pending) => {
+                    eprintln!("init: {}: client timed out", self.path.display());
+                }
+                Ok(Received::Closed) => {}
+                Err(why) => eprintln!("init: {}: {why}", self.path.display()),
+            }
+        }
+    }
+
+    fn accept(&mut self) {
+        while let Ok((stream, _)) = self.listener.accept() {
+            if self.clients.len() >= MAX_CLIENTS {
+                eprintln!(
+                    "init: {}: too many pending connections",
+                    self.path.display()
+                );
+                continue;
+            }
+
+            // the credentials are checked before anything is read
+            let client = getsockopt(&stream, PeerCredentials)
+                .map_err(anyhow::Error::from)
+                .and_then(|cred| {
+                    stream.set_nonblocking(true)?;
+                    Ok(Client {
+                        stream,
+                        uid: cred.uid(),
+                        buf: vec![],
+                        since: Instant::now(),
+                    })
+                });
+            match client {
+                Ok(client) => self.clients.push(client),
+                Err(why) => eprintln!("init: {}: {why}", self.path.display()),
+            }
+        }
+    }
+}
+
+impl Client {
+    /// Reads the available data without blocking
+    fn receive(&mut self) -> Result<Received> {
+        let mut chunk = [0; 4096];
+        let mut closed = false;
+        loop {
+            match self.stream.read(&mut chunk) {
+                // the client may shut down its end after the request
+                Ok(0) if self.buf.is_empty() => return Ok(Received::Closed),
+                Ok(0) => {
+                    closed = true;
+                    break;
+                }
+                Ok(len) => self.buf.extend_from_slice(&chunk[..len]),
+                Err(why) if why.kind() == ErrorKind::WouldBlock => break,
+                Err(why) if why.kind() == ErrorKind::Interrupted => continue,
+                Err(why) => return Err(why.into()),
+            }
+
+            if self.buf.len() > MAX_REQUEST {
+                return Err(anyhow!("request is too long"));
+            }
+            if self.buf.contains(&b'\n') {
+                break;
+            }
+        }
+
+        match self.buf.iter().position(|byte| *byte == b'\n') {
+            Some(end) => Ok(Received::Request(
+                String::from_utf8_lossy(&self.buf[..end]).to_string(),
+            )),
+            None if closed => Ok(Received::Request(
+                String::from_utf8_lossy(&self.buf).to_string(),
+            )),
+            None => Ok(Received::Pending),
+        }
+    }
+}
+
+fn handle(mut stream: UnixStream, uid: u32, line: &str, sv: &mut Supervisor) -> Result<()> {
+    let req = match serde_json::from_str::<Request>(line) {
+        Ok(req) => req,
+        Err(why) => return respond(&mut stream, &Response::error(why)),
+    };
+
+    if req.version != PROTOCOL_VERSION {
+        return respond(
+            &mut stream,
+            &Response::error(format!(
+                "unsupported protocol version {} (init uses {PROTOCOL_VERSION})",
+                req.version
+            )),
+        );
+    }
+
+    if req.command.is_mutating() && uid != 0 {
+        return respond(&mut stream, &Response::error("permission denied"));
+    }
+
+    let resp = match req.command {
+        Command::Start { service } => Response::from_result(sv.start_service(&service)),
+        Command::Stop { service } => Response::from_result(sv.stop_service(&service)),
+        Command::Restart { service, force } => {
+            Response::from_result(sv.restart_service(&service, force))
+        }
+        Command::Reload { service } => Response::from_result(sv.reload_service(&service)),
+        Command::Status { service } => match sv.service_status(&service) {
+            Ok(service) => Response::Status { service },
+            Err(why) => Response::error(why),
+        },
+        Command::List => Response::List {
+            runlevel: sv.current().map(str::to_string),
+            services: sv.services.clone(),
+        },
+        Command::Runlevel { runlevel } => {
+            if !sv.conf.runlevel.iter().any(|rl| rl.dir == runlevel) {
+                return respond(
+                    &mut stream,
+                    &Response::error(format!("unknown runlevel: {runlevel}")),
+                );
+            }
+
+            // switching to some runlevels never returns (e.g. `rl0`),
+            // so the client is answered in advance
+            respond(&mut stream, &Response::Ok)?;
+            drop(stream);
+
+            if let Err(why) = sv.switch_runlevel(&runlevel) {
+                eprintln!("init: failed to switch to the {runlevel} runlevel: {why}");
+            }
+            return Ok(());
+        }
+        Command::Shutdown { reboot } => {
+            respond(&mut stream, &Response::Ok)?;
+            drop(stream);
+
+            let mode = match reboot {
+                true => RebootMode::RB_AUTOBOOT,
+                false => RebootMode::RB_POWER_OFF,
+            };
+            return sv.shutdown(mode);
+        }
+    };
+
+    respond(&mut stream, &resp)
+}
+
+/// Sends the response. The accepted streams are non-blocking, so the
+/// stream is switched back to the blocking mode not to truncate long
+/// responses, but a client that doesn't read is given up on
+fn respond(stream: &mut UnixStream, resp: &Response) -> Result<()> {
+    let mut data = serde_json::to_string(resp)?;
+    data.push('\n');
+
+    stream.set_nonblocking(false)?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    stream.write_all(data.as_bytes())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_root;
+
+    #[test]
+    fn request_roundtrip() {
+        let req = Request {
+            version: PROTOCOL_VERSION,
+            command: Command::Restart {
+                service: "syslogd".to_string(),
+                force: true,
+            },
+        };
+
+        let data = serde_json::to_string(&req).unwrap();
+        assert_eq!(
+            data,
+            r#"{"version":1,"command":{"restart":{"service":"syslogd","force":true}}}"#
+        );
+        assert_eq!(serde_json::from_str::<Request>(&data).unwrap(), req);
+    }
+
+    #[test]
+    fn response_into_result() {
+        let resp = Response::error("service x not found");
+        assert_eq!(
+            resp.into_result().unwrap_err().to_string(),
+            "service x not found"
+        );
+        assert!(Response::Ok.into_result().is_ok());
+    }
+
+    #[test]
+    fn serve_test() {
+        let root = temp_root("control");
+        let paths = Paths::with_root(&root);
+        fs::create_dir_all(paths.control_socket().parent().unwrap()).unwrap();
+
+        let mut server = ControlServer::bind(&paths).unwrap();
+        let mut sv = Supervisor::new(crate::config::Config::default(), paths.clone());
+
+        // neither an idle client nor a closed one block the others
+        let idle = UnixStream::connect(paths.control_socket()).unwrap();
+        drop(UnixStream::connect(paths.control_socket()).unwrap());
+        let mut client = UnixStream::connect(paths.control_socket()).unwrap();
+        client.write_all(br#"{"version":1,"#).unwrap();
+
+        let started = Instant::now();
+        server.serve(&mut sv);
+        assert!(started.elapsed() < CLIENT_TIMEOUT);
+        assert_eq!(server.clients.len(), 2);
+        assert_eq!(server.fds().len(), 3);
+
+        client.write_all(b"\"command\":\"list\"}\n").unwrap();
+        server.serve(&mut sv);
+        assert_eq!(server.clients.len(), 1);
+
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).unwrap();
+        assert!(resp.starts_with(r#"{"list":{"runlevel":null,"#), "{resp}");
+
+        drop(idle);
+        server.serve(&mut sv);
+        assert!(server.clients.is_empty());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn long_response_test() {
+        let root = temp_root("control_long");
+        let paths = Paths::with_root(&root);
+        fs::create_dir_all(paths.control_socket().parent().unwrap()).unwrap();
+
+        let mut server = ControlServer::bind(&paths).unwrap();
+        let mut sv = Supervisor::new(crate::config::Config::default(), paths.clone());
+        sv.services = (0..5000)
+            .map(|idx| ServiceRecord::new("rl2", &format!("service{idx}")))
+            .collect();
+
+        // the response doesn't fit into the socket buffer and the client
+        // starts reading it a bit later
+        let mut client = UnixStream::connect(paths.control_socket()).unwrap();
+        client
+            .write_all(b"{\"version\":1,\"command\":\"list\"}\n")
+            .unwrap();
+        let reader = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            let mut resp = String::new();
+            client.read_to_string(&mut resp).unwrap();
+            resp
+        });
+
+        server.serve(&mut sv);
+        let resp = reader.join().unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert!(resp.len() > 256 * 1024, "{}", resp.len());
+        assert!(serde_json::from_str::<Response>(&resp).is_ok());
+    }
+}
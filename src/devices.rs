@@ -0,0 +1,649 @@
+//! Device manager
+//!
+//! If `/etc/init/devices.toml` exists, init listens to the kernel uevents
+//! on the `NETLINK_KOBJECT_UEVENT` socket and handles the devices that
+//! appear and disappear, like `mdev` or a minimal udev:
+//!
+//! - the device node `/dev/DEVNAME` is created if it doesn't exist (the
+//!   kernel usually creates it in `devtmpfs` itself);
+//! - the drivers of the device are loaded by its `MODALIAS` from
+//!   `modules.alias` (or with the `modprobe` command of `[modules]`);
+//! - the matching `[[rule]]`s change the mode and owner of the node,
+//!   create symlinks to it, run commands and start services.
+//!
+//! The uevents of the devices found before init has started are replayed
+//! at boot by writing `add` to the `uevent` files in `/sys/devices`
+//! (coldplug). The services requested by the rules during coldplug are
+//! started after the boot.
+//!
+//! ```toml
+//! [[rule]]
+//! match = { SUBSYSTEM = "tty", DEVNAME = "ttyUSB*|ttyACM*" }
+//! group = "dialout"
+//! mode = "0660"
+//! symlink = "serial/%k"
+//! service = "gpsd"
+//! ```
+
+use anyhow::anyhow;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::fd::AsFd;
+use std::os::fd::AsRawFd;
+use std::os::fd::BorrowedFd;
+use std::os::fd::OwnedFd;
+use std::path::Path;
+use std::process::Command;
+use std::process::Stdio;
+use std::time::Duration;
+use std::time::Instant;
+
+use nix::poll::poll;
+use nix::poll::PollFd;
+use nix::poll::PollFlags;
+use nix::sys::socket::bind;
+use nix::sys::socket::recvfrom;
+use nix::sys::socket::setsockopt;
+use nix::sys::socket::socket;
+use nix::sys::socket::sockopt::RcvBufForce;
+use nix::sys::socket::AddressFamily;
+use nix::sys::socket::NetlinkAddr;
+use nix::sys::socket::SockFlag;
+use nix::sys::socket::SockProtocol;
+use nix::sys::socket::SockType;
+
+use crate::config::Modules;
+use crate::consts::DEV_DIR;
+use crate::modules;
+use crate::modules::glob_match;
+use crate::modules::ModuleDb;
+use crate::modules::Request;
+use crate::paths::Paths;
+use crate::service::split_args;
+use crate::service::PathSection;
+use crate::service::PathType;
+use crate::tmpfiles;
+use crate::traits::TomlConfig;
+
+/// Netlink multicast group of the uevents sent by the kernel
+const KERNEL_GROUP: u32 = 1;
+
+/// Maximum size of a single uevent
+const UEVENT_BUFFER_SIZE: usize = 8192;
+
+/// Receive buffer of the socket, large enough for the coldplug uevents
+const RECV_BUFFER_SIZE: usize = 8 * 1024 * 1024;
+
+/// Coldplug is finished when no uevents arrive for this time
+const SETTLE_IDLE: u16 = 250;
+
+/// Maximum time of waiting for the coldplug uevents
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Contents of `/etc/init/devices.toml`
+#[derive(Deserialize, Serialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConf {
+    /// Replay the uevents of the devices present at boot. Default:
+    /// `Some(true)`
+    pub coldplug: Option<bool>,
+
+    /// Load the drivers of the new devices by their `MODALIAS`. Default:
+    /// `Some(true)`
+    pub modalias: Option<bool>,
+
+    /// Rules applied to the uevents in the order of their declaration
+    pub rule: Option<Vec<DeviceRule>>,
+}
+
+impl TomlConfig for DeviceConf {}
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceRule {
+    /// Uevent properties and their patterns (`SUBSYSTEM = "tty"`,
+    /// `DEVNAME = "ttyUSB*|ttyACM*"`). Patterns support `*`, `?`, `[...]`
+    /// and `|` between alternatives. The rule applies only to `add` uevents
+    /// unless `ACTION` is matched. Default: all devices
+    #[serde(rename = "match")]
+    pub matches: Option<BTreeMap<String, String>>,
+
+    /// Octal permissions of the device node
+    pub mode: Option<String>,
+
+    /// Owner of the device node (name or UID)
+    pub user: Option<String>,
+
+    /// Group of the device node (name or GID)
+    pub group: Option<String>,
+
+    /// Symlink to the device node, relative to `/dev`. `%k` is replaced
+    /// with the kernel name of the device (`ttyUSB0`), `%n` with its
+    /// number (`0`). The symlink is removed with the device
+    pub symlink: Option<String>,
+
+    /// Commands run with the uevent properties in the environment. `%k`
+    /// and `%n` are replaced like in `symlink`
+    pub run: Option<Vec<String>>,
+
+    /// Service started when the device appears
+    pub service: Option<String>,
+}
+
+/// Kernel uevent: the properties of the device and the action with it
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Uevent {
+    pub props: BTreeMap<String, String>,
+}
+
+/// Action performed for the uevent
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceAction {
+    /// Creating the device node or changing its mode and owner
+    Node(PathSection),
+
+    /// Creating the symlink to the device node
+    Link(PathSection),
+
+    /// Removing the symlink of the removed device
+    Unlink(String),
+
+    /// Loading the drivers of the device with the `MODALIAS`
+    LoadDrivers(String),
+
+    /// Running the command
+    Run(Vec<String>),
+
+    /// Starting the service
+    Start(String),
+}
+
+impl Uevent {
+    /// Parses the uevent sent by the kernel: the `ACTION@DEVPATH` header
+    /// followed by the NUL-separated `KEY=VALUE` properties. Returns `None`
+    /// for other messages (e.g. the ones sent by udev)
+    pub fn parse(msg: &[u8]) -> Option<Self> {
+        let mut fields = msg
+            .split(|byte| *byte == 0)
+            .filter(|field| !field.is_empty())
+            .map(String::from_utf8_lossy);
+
+        let header = fields.next()?;
+        let (action, devpath) = header.split_once('@')?;
+
+        let mut props: BTreeMap<String, String> = fields
+            .filter_map(|field| {
+                field
+                    .split_once('=')
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+            })
+            .collect();
+        props
+            .entry("ACTION".to_string())
+            .or_insert(action.to_string());
+        props
+            .entry("DEVPATH".to_string())
+            .or_insert(devpath.to_string());
+
+        Some(Self { props })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.props.get(key).map(String::as_str)
+    }
+
+    pub fn action(&self) -> &str {
+        self.get("ACTION").unwrap_or_default()
+    }
+
+    /// Kernel name of the device (`ttyUSB0`)
+    pub fn kernel(&self) -> &str {
+        let devpath = self.get("DEVPATH").unwrap_or_default();
+        devpath.rsplit('/').next().unwrap_or(devpath)
+    }
+
+    /// Number at the end of the kernel name (`0` for `ttyUSB0`)
+    pub fn number(&self) -> &str {
+        let kernel = self.kernel();
+        let start = kernel.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+        &kernel[start..]
+    }
+
+    /// Device node (`/dev/ttyUSB0`)
+    pub fn node(&self) -> Option<String> {
+        self.get("DEVNAME").map(|name| match name.starts_with('/') {
+            true => name.to_string(),
+            false => format!("{DEV_DIR}/{name}"),
+        })
+    }
+
+    /// Replaces `%k` and `%n` in the string. `%%` is `%`
+    pub fn substitute(&self, s: &str) -> String {
+        let mut res = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match (c, chars.clone().next()) {
+                ('%', Some('k')) => res.push_str(self.kernel()),
+                ('%', Some('n')) => res.push_str(self.number()),
+                ('%', Some('%')) => res.push('%'),
+                _ => {
+                    res.push(c);
+                    continue;
+                }
+            }
+            chars.next();
+        }
+
+        res
+    }
+}
+
+impl DeviceRule {
+    /// Whether the rule applies to the uevent. The action is not checked
+    /// if `any_action = true`
+    pub fn matches(&self, event: &Uevent, any_action: bool) -> bool {
+        let matches = self.matches.clone().unwrap_or_default();
+        if !any_action && !matches.contains_key("ACTION") && event.action() != "add" {
+            return false;
+        }
+
+        matches.iter().all(|(key, pattern)| {
+            (any_action && key == "ACTION")
+                || event.get(key).is_some_and(|value| {
+                    pattern.split('|').any(|pattern| glob_match(pattern, value))
+                })
+        })
+    }
+
+    /// Returns the entry changing the mode and owner of the device node
+    pub fn node(&self, path: &str, kind: PathType, device: &str) -> PathSection {
+        PathSection {
+            path: path.to_string(),
+            kind,
+            mode: self.mode.clone(),
+            user: self.user.clone(),
+            group: self.group.clone(),
+            age: None,
+            argument: Some(device.to_string()),
+        }
+    }
+
+    /// Returns the path of the symlink of the device
+    fn symlink(&self, event: &Uevent) -> Option<String> {
+        let link = event.substitute(self.symlink.as_ref()?);
+        match link.starts_with('/') {
+            true => Some(link),
+            false => Some(format!("{DEV_DIR}/{link}")),
+        }
+    }
+}
+
+impl DeviceConf {
+    /// Returns the actions for the uevent
+    pub fn actions(&self, event: &Uevent) -> Vec<DeviceAction> {
+        let mut actions = vec![];
+        let is_add = event.action() == "add";
+        let node = event.node();
+        let kind = match event.get("SUBSYSTEM") {
+            Some("block") => PathType::block,
+            _ => PathType::char,
+        };
+        let device = match (event.get("MAJOR"), event.get("MINOR")) {
+            (Some(major), Some(minor)) => Some(format!("{major}:{minor}")),
+            _ => None,
+        };
+
+        if is_add {
+            if let (Some(node), Some(device)) = (&node, &device) {
+                actions.push(DeviceAction::Node(
+                    DeviceRule::default().node(node, kind, device),
+                ));
+            }
+            if let Some(modalias) = event.get("MODALIAS") {
+                if self.modalias.unwrap_or(true) {
+                    actions.push(DeviceAction::LoadDrivers(modalias.to_string()));
+                }
+            }
+        }
+
+        for rule in self.rule.iter().flatten() {
+            if !rule.matches(event, false) {
+                // the symlinks are removed even if the rule handles only `add`
+                if event.action() == "remove" && rule.matches(event, true) {
+                    actions.extend(rule.symlink(event).map(DeviceAction::Unlink));
+                }
+                continue;
+            }
+
+            if let (true, Some(node), Some(device)) = (is_add, &node, &device) {
+                if rule.mode.is_some() || rule.user.is_some() || rule.group.is_some() {
+                    actions.push(DeviceAction::Node(rule.node(node, kind, device)));
+                }
+                if let Some(link) = rule.symlink(event) {
+                    actions.push(DeviceAction::Link(PathSection {
+                        path: link,
+                        kind: PathType::symlink,
+                        mode: None,
+                        user: None,
+                        group: None,
+                        age: None,
+                        argument: Some(node.clone()),
+                    }));
+                }
+            }
+            if event.action() == "remove" {
+                actions.extend(rule.symlink(event).map(DeviceAction::Unlink));
+            }
+
+            for cmd in rule.run.iter().flatten() {
+                match split_args(cmd) {
+                    Ok(argv) => actions.push(DeviceAction::Run(
+                        argv.iter().map(|arg| event.substitute(arg)).collect(),
+                    )),
+                    Err(why) => eprintln!("init: devices: {cmd}: {why}"),
+                }
+            }
+            if let Some(service) = &rule.service {
+                actions.push(DeviceAction::Start(service.clone()));
+            }
+        }
+
+        actions
+    }
+}
+
+/// Listener of the kernel uevents
+pub struct DeviceManager {
+    socket: OwnedFd,
+    conf: DeviceConf,
+
+    /// Database used to load the drivers. Not used if `modprobe` is
+    /// specified in `[modules]`
+    db: Option<ModuleDb>,
+
+    /// `modprobe` command from `[modules]`
+    modprobe: Option<String>,
+
+    /// Services requested by the rules and not started yet
+    pending: Vec<String>,
+}
+
+impl DeviceManager {
+    /// Reads `/etc/init/devices.toml` and subscribes to the kernel uevents
+    pub fn open(paths: &Paths, modules: Option<&Modules>) -> Result<Self> {
+        let conf = DeviceConf::parse(paths.devices_conf())?;
+
+        let socket = socket(
+            AddressFamily::Netlink,
+            SockType::Datagram,
+            SockFlag::SOCK_CLOEXEC | SockFlag::SOCK_NONBLOCK,
+            SockProtocol::NetlinkKObjectUEvent,
+        )?;
+        // the default buffer overflows while coldplugging
+        let _ = setsockopt(&socket, RcvBufForce, &RECV_BUFFER_SIZE);
+        bind(socket.as_raw_fd(), &NetlinkAddr::new(0, KERNEL_GROUP))?;
+
+        let _ = fs::write(paths.hotplug(), "\n");
+
+        let modprobe = modules.and_then(|modules| modules.modprobe.clone());
+        let db = match (conf.modalias.unwrap_or(true), &modprobe) {
+            (true, None) => ModuleDb::load(paths)
+                .map_err(|why| eprintln!("init: devices: drivers won't be loaded: {why}"))
+                .ok(),
+            _ => None,
+        };
+
+        Ok(Self {
+            socket,
+            conf,
+            db,
+            modprobe,
+            pending: vec![],
+        })
+    }
+
+    /// Replays the uevents of the present devices and handles them until
+    /// no more uevents arrive
+    pub fn coldplug(&mut self, paths: &Paths) {
+        if !self.conf.coldplug.unwrap_or(true) {
+            return;
+        }
+        trigger(&paths.sys_devices_dir());
+
+        let start = Instant::now();
+        while start.elapsed() < SETTLE_TIMEOUT {
+            let mut fds = [PollFd::new(self.socket.as_fd(), PollFlags::POLLIN)];
+            if !matches!(poll(&mut fds, SETTLE_IDLE), Ok(n) if n > 0) {
+                break;
+            }
+            self.receive(paths);
+        }
+    }
+
+    /// Handles the received uevents
+    pub fn receive(&mut self, paths: &Paths) {
+        let mut buf = [0; UEVENT_BUFFER_SIZE];
+        while let Ok((len, addr)) = recvfrom::<NetlinkAddr>(self.socket.as_raw_fd(), &mut buf) {
+            // only the kernel is trusted
+            if !matches!(addr, Some(addr) if addr.pid() == 0) {
+                continue;
+            }
+            if let Some(event) = Uevent::parse(&buf[..len]) {
+                self.handle(paths, &event);
+            }
+        }
+    }
+
+    /// Takes the services requested by the rules
+    pub fn take_services(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.pending)
+    }
+
+    fn handle(&mut self, paths: &Paths, event: &Uevent) {
+        for action in self.conf.actions(event) {
+            if let Err(why) = self.execute(paths, event, action) {
+                eprintln!("init: devices: {}: {why}", event.kernel());
+            }
+        }
+    }
+
+    fn execute(&mut self, paths: &Paths, event: &Uevent, action: DeviceAction) -> Result<()> {
+        match action {
+            DeviceAction::Node(entry) | DeviceAction::Link(entry) => tmpfiles::apply(paths, &entry),
+            DeviceAction::Unlink(link) => {
+                let pth = paths.resolve(&link);
+                if pth.symlink_metadata().is_ok_and(|meta| meta.is_symlink()) {
+                    fs::remove_file(pth)?;
+                }
+                Ok(())
+            }
+            DeviceAction::LoadDrivers(modalias) => match (&self.modprobe, &self.db) {
+                (Some(modprobe), _) => {
+                    let req = Request {
+                        name: modalias,
+                        params: String::new(),
+                    };
+                    // the modalias is loaded in the background not to delay the
+                    // handling of the following uevents
+                    spawn(&modules::modprobe_command(modprobe, &req)?, &event.props)
+                }
+                (None, Some(db)) => db
+                    .resolve_alias(&modalias)
+                    .iter()
+                    .try_for_each(|module| modules::load(paths, db, module, "")),
+                (None, None) => Ok(()),
+            },
+            DeviceAction::Run(argv) => spawn(&argv, &event.props),
+            DeviceAction::Start(service) => {
+                if !self.pending.contains(&service) {
+                    self.pending.push(service);
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Starts the command with the uevent properties in its environment. The
+/// process is reaped by the main loop of init
+fn spawn(argv: &[String], props: &BTreeMap<String, String>) -> Result<()> {
+    let (prog, args) = argv
+        .split_first()
+        .ok_or_else(|| anyhow!("command is empty"))?;
+
+    Command::new(prog)
+        .args(args)
+        .envs(props)
+        .stdin(Stdio::null())
+        .spawn()
+        .map_err(|why| anyhow!("failed to run {prog}: {why}"))?;
+    Ok(())
+}
+
+impl AsFd for DeviceManager {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.socket.as_fd()
+    }
+}
+
+/// Makes the kernel send the `add` uevents of all devices under the
+/// directory again. Symlinks are not followed
+pub fn trigger(dir: &Path) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+
+    for entry in entries.flatten() {
+        let pth = entry.path();
+        match entry.file_type() {
+            Ok(kind) if kind.is_dir() => trigger(&pth),
+            Ok(kind) if kind.is_file() && entry.file_name() == "uevent" => {
+                let _ = fs::write(&pth, "add");
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Uevents recorded with `udevadm monitor --kernel --property` while
+    /// plugging in and out a USB-serial adapter
+    const PLUG: &[&str] = &[
+        "add@/devices/pci0000:00/0000:00:14.0/usb1/1-2\0ACTION=add\0\
+         DEVPATH=/devices/pci0000:00/0000:00:14.0/usb1/1-2\0SUBSYSTEM=usb\0\
+         MAJOR=189\0MINOR=3\0DEVNAME=bus/usb/001/004\0DEVTYPE=usb_device\0\
+         PRODUCT=67b/2303/300\0TYPE=0/0/0\0BUSNUM=001\0DEVNUM=004\0SEQNUM=4711\0",
+        "add@/devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2:1.0\0ACTION=add\0\
+         DEVPATH=/devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2:1.0\0SUBSYSTEM=usb\0\
+         DEVTYPE=usb_interface\0PRODUCT=67b/2303/300\0TYPE=0/0/0\0INTERFACE=255/0/0\0\
+         MODALIAS=usb:v067Bp2303d0300dc00dsc00dp00icFFisc00ip00in00\0SEQNUM=4712\0",
+        "add@/devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2:1.0/ttyUSB0/tty/ttyUSB0\0\
+         ACTION=add\0DEVPATH=/devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2:1.0/ttyUSB0/tty/ttyUSB0\0\
+         SUBSYSTEM=tty\0MAJOR=188\0MINOR=0\0DEVNAME=ttyUSB0\0SEQNUM=4715\0",
+        "remove@/devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2:1.0/ttyUSB0/tty/ttyUSB0\0\
+         ACTION=remove\0DEVPATH=/devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2:1.0/ttyUSB0/tty/ttyUSB0\0\
+         SUBSYSTEM=tty\0MAJOR=188\0MINOR=0\0DEVNAME=ttyUSB0\0SEQNUM=4720\0",
+    ];
+
+    fn conf() -> DeviceConf {
+        toml::from_str(
+            r#"
+[[rule]]
+match = { SUBSYSTEM = "tty", DEVNAME = "ttyUSB*|ttyACM*" }
+group = "20"
+mode = "0660"
+symlink = "serial/gps%n"
+run = ["/bin/logger -t devices 'serial %k'"]
+service = "gpsd"
+
+[[rule]]
+match = { ACTION = "remove", SUBSYSTEM = "tty" }
+run = ["/bin/logger -t devices 'unplugged %k'"]
+"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn uevent_test() {
+        let event = Uevent::parse(PLUG[2].as_bytes()).unwrap();
+        assert_eq!(event.action(), "add");
+        assert_eq!(event.get("SUBSYSTEM"), Some("tty"));
+        assert_eq!(event.kernel(), "ttyUSB0");
+        assert_eq!(event.number(), "0");
+        assert_eq!(event.node().as_deref(), Some("/dev/ttyUSB0"));
+        assert_eq!(event.substitute("serial/%k-%n%%"), "serial/ttyUSB0-0%");
+
+        assert!(Uevent::parse(b"libudev\0\xfe\xed\xca\xfe").is_none());
+    }
+
+    #[test]
+    fn actions_test() {
+        let _ = DeviceConf::parse("data/devices.toml").unwrap();
+
+        let conf = conf();
+        let actions: Vec<Vec<DeviceAction>> = PLUG
+            .iter()
+            .map(|msg| conf.actions(&Uevent::parse(msg.as_bytes()).unwrap()))
+            .collect();
+
+        // USB device: only its node
+        assert_eq!(actions[0].len(), 1);
+        let DeviceAction::Node(node) = &actions[0][0] else {
+            panic!("unexpected action: {:?}", actions[0][0]);
+        };
+        assert_eq!(node.path, "/dev/bus/usb/001/004");
+        assert_eq!(node.argument.as_deref(), Some("189:3"));
+        assert_eq!(node.mode, None);
+
+        // USB interface: the driver
+        assert_eq!(
+            actions[1],
+            [DeviceAction::LoadDrivers(
+                "usb:v067Bp2303d0300dc00dsc00dp00icFFisc00ip00in00".to_string()
+            )]
+        );
+
+        // serial port: node, its permissions, symlink, command and service
+        assert_eq!(actions[2].len(), 5);
+        let DeviceAction::Node(node) = &actions[2][1] else {
+            panic!("unexpected action: {:?}", actions[2][1]);
+        };
+        assert_eq!(node.kind, PathType::char);
+        assert_eq!(node.mode.as_deref(), Some("0660"));
+        assert_eq!(node.group.as_deref(), Some("20"));
+        let DeviceAction::Link(link) = &actions[2][2] else {
+            panic!("unexpected action: {:?}", actions[2][2]);
+        };
+        assert_eq!(link.path, "/dev/serial/gps0");
+        assert_eq!(link.argument.as_deref(), Some("/dev/ttyUSB0"));
+        assert_eq!(
+            actions[2][3],
+            DeviceAction::Run(
+                ["/bin/logger", "-t", "devices", "serial ttyUSB0"]
+                    .map(String::from)
+                    .to_vec()
+            )
+        );
+        assert_eq!(actions[2][4], DeviceAction::Start("gpsd".to_string()));
+
+        // unplugging: the symlink is removed
+        assert_eq!(
+            actions[3],
+            [
+                DeviceAction::Unlink("/dev/serial/gps0".to_string()),
+                DeviceAction::Run(
+                    ["/bin/logger", "-t", "devices", "unplugged ttyUSB0"]
+                        .map(String::from)
+                        .to_vec()
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,162 @@
+//! Emergency mode
+//!
+//! Entered when a service with `on_error = "abort"` fails. Instead of
+//! exiting (which kills PID 1 and panics the kernel) init prints the
+//! failure, asks for the root password (like `sulogin`) and runs a
+//! rescue shell on the console. After the shell exits the boot either
+//! continues or the system is rebooted.
+
+use anyhow::anyhow;
+use anyhow::Result;
+use colored::Colorize;
+use std::fs;
+use std::io::stdin;
+use std::io::stdout;
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+
+use nix::sys::termios::tcgetattr;
+use nix::sys::termios::tcsetattr;
+use nix::sys::termios::LocalFlags;
+use nix::sys::termios::SetArg;
+
+use crate::config::Emergency;
+use crate::config::EmergencyExit;
+use crate::paths::Paths;
+
+/// Shell used if `Emergency.shell` is not specified
+const DEFAULT_SHELL: &str = "/bin/sh";
+
+/// Number of attempts to enter the root password
+const PASSWORD_ATTEMPTS: usize = 3;
+
+/// Runs the emergency mode and returns the action to be performed after it
+pub fn run(paths: &Paths, conf: &Emergency, reason: &str) -> EmergencyExit {
+    eprintln!();
+    eprintln!("{}", "*** EMERGENCY MODE ***".bold().red());
+    eprintln!("init: {reason}");
+    eprintln!("The boot process cannot continue normally.");
+
+    rescue_shell(paths, conf);
+
+    let action = conf.on_exit.unwrap_or_default();
+    match action {
+        EmergencyExit::r#continue => eprintln!("Leaving emergency mode, continuing boot..."),
+        EmergencyExit::reboot => eprintln!("Leaving emergency mode, rebooting..."),
+    }
+
+    action
+}
+
+/// Asks for the root password if required and runs the rescue shell
+pub fn rescue_shell(paths: &Paths, conf: &Emergency) {
+    if conf.require_password.unwrap_or(true) && !login(&paths.shadow(), conf.force.unwrap_or(false))
+    {
+        return;
+    }
+
+    let shell = conf.shell.as_deref().unwrap_or(DEFAULT_SHELL);
+    eprintln!("Running rescue shell {shell}. Exit it to resume.\n");
+
+    if let Err(why) = Command::new(shell).status() {
+        eprintln!("init: failed to run rescue shell {shell}: {why}");
+    }
+}
+
+/// Asks for the root password. Returns `true` if the rescue shell can be
+/// run. Without the password database the shell is run only if `force`
+/// is set, like `sulogin --force` does
+fn login(shadow: &Path, force: bool) -> bool {
+    let hash = match fs::read_to_string(shadow)
+        .map_err(|why| anyhow!("{}: {why}", shadow.display()))
+        .and_then(|shadow| root_hash(&shadow))
+    {
+        Ok(hash) => hash,
+        Err(why) if force => {
+            eprintln!("init: warning: cannot read the root password ({why}), access granted");
+            return true;
+        }
+        Err(why) => {
+            eprintln!("init: cannot read the root password ({why})");
+            eprintln!(
+                "Cannot open access to console, set `force = true` in [emergency] to allow it."
+            );
+            return false;
+        }
+    };
+
+    if hash.is_empty() {
+        return true;
+    }
+
+    if hash.starts_with('!') || hash.starts_with('*') {
+        eprintln!("Cannot open access to console, the root account is locked.");
+        return false;
+    }
+
+    for _ in 0..PASSWORD_ATTEMPTS {
+        print!("Give root password for maintenance\n(or press Control-D to continue): ");
+        let _ = stdout().flush();
+
+        match read_password() {
+            Ok(Some(pass)) if pwhash::unix::verify(&pass, &hash) => return true,
+            Ok(Some(_)) => eprintln!("Login incorrect"),
+            Ok(None) => return false,
+            Err(why) => {
+                eprintln!("init: failed to read the password: {why}");
+                return false;
+            }
+        }
+    }
+
+    false
+}
+
+/// Reads the password from the console with echo disabled. Returns
+/// `None` on end of file (Control-D)
+fn read_password() -> Result<Option<String>> {
+    let stdin = stdin();
+    let term = tcgetattr(&stdin).ok();
+
+    if let Some(term) = &term {
+        let mut noecho = term.clone();
+        noecho.local_flags.remove(LocalFlags::ECHO);
+        tcsetattr(&stdin, SetArg::TCSANOW, &noecho)?;
+    }
+
+    let mut pass = String::new();
+    let len = stdin.read_line(&mut pass);
+
+    if let Some(term) = &term {
+        tcsetattr(&stdin, SetArg::TCSANOW, term)?;
+    }
+    println!();
+
+    match len? {
+        0 => Ok(None),
+        _ => Ok(Some(pass.trim_end_matches(['\n', '\r']).to_string())),
+    }
+}
+
+/// Returns the password hash of `root` from the contents of `/etc/shadow`
+fn root_hash(shadow: &str) -> Result<String> {
+    shadow
+        .lines()
+        .find_map(|line| line.strip_prefix("root:"))
+        .map(|fields| fields.split(':').next().unwrap_or_default().to_string())
+        .ok_or_else(|| anyhow!("no root entry in the password database"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn root_hash_test() {
+        let shadow = "daemon:*:19000:0:99999:7:::\nroot:$6$salt$hash:19000:0:99999:7:::\n";
+        assert_eq!(root_hash(shadow).unwrap(), "$6$salt$hash");
+        assert_eq!(root_hash("root::19000::::::").unwrap(), "");
+        assert!(root_hash("user:x:1:::::").is_err());
+    }
+}
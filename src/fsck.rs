@@ -0,0 +1,160 @@
+//! Checking of the filesystems from `/etc/fstab`
+//!
+//! The filesystems with a non-zero pass number are checked by the program
+//! from the `[fsck]` section (`/sbin/fsck -a` by default). The root
+//! filesystem (pass number `1`) is checked while it is still mounted
+//! read-only and is then remounted with its options from `/etc/fstab`.
+//! Other filesystems are checked right before they are mounted.
+//!
+//! The exit code of the program is a bit mask:
+//!
+//! - `1` - errors were corrected, the boot continues;
+//! - `2` - errors were corrected, the system must be rebooted;
+//! - `4` - errors were left uncorrected, the emergency mode is entered;
+//! - `8` and above - the check itself has failed (treated as `4`).
+
+use anyhow::anyhow;
+use anyhow::Result;
+use std::process::Command;
+
+use crate::config::Fsck;
+use crate::config::FsckMode;
+use crate::fstab::FstabEntry;
+
+/// Errors were corrected
+const ERRORS_CORRECTED: i32 = 1;
+
+/// Errors were corrected, the system must be rebooted
+const REBOOT_REQUIRED: i32 = 2;
+
+/// Checking program used if `fsck.program` is not specified
+pub const DEFAULT_PROGRAM: &str = "/sbin/fsck";
+
+/// Result of the check interpreted from the exit code
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    /// No errors were found
+    Clean,
+
+    /// Errors were found and corrected
+    Corrected,
+
+    /// Errors were corrected, but the system must be rebooted
+    Reboot,
+
+    /// Errors were left uncorrected or the check has failed
+    Failed,
+}
+
+impl Outcome {
+    pub fn from_code(code: i32) -> Self {
+        match code {
+            0 => Self::Clean,
+            ERRORS_CORRECTED => Self::Corrected,
+            code if code & !(ERRORS_CORRECTED | REBOOT_REQUIRED) != 0 => Self::Failed,
+            _ => Self::Reboot,
+        }
+    }
+}
+
+/// Whether the filesystem of the entry must be checked
+pub fn needs_check(conf: &Fsck, mode: FsckMode, entry: &FstabEntry) -> bool {
+    conf.enabled.unwrap_or(true)
+        && mode != FsckMode::skip
+        && entry.passno > 0
+        && !entry.is_swap()
+        && !entry.is_bind()
+        && !entry.has_option("noauto")
+}
+
+/// Returns the command line checking the filesystem on the `device`
+pub fn command(conf: &Fsck, mode: FsckMode, entry: &FstabEntry, device: &str) -> Vec<String> {
+    let mut argv = vec![conf.program.clone().unwrap_or(DEFAULT_PROGRAM.to_string())];
+    argv.extend(conf.args.clone().unwrap_or(vec!["-a".to_string()]));
+    if mode == FsckMode::force {
+        argv.push("-f".to_string());
+    }
+    if entry.fstype != "auto" {
+        argv.push("-t".to_string());
+        argv.push(entry.fstype.clone());
+    }
+    argv.push(device.to_string());
+
+    argv
+}
+
+/// Runs the checking program and returns its exit code
+pub fn run(argv: &[String]) -> Result<i32> {
+    let (prog, args) = argv
+        .split_first()
+        .ok_or_else(|| anyhow!("fsck command is empty"))?;
+
+    let status = Command::new(prog)
+        .args(args)
+        .status()
+        .map_err(|why| anyhow!("failed to run {prog}: {why}"))?;
+
+    status
+        .code()
+        .ok_or_else(|| anyhow!("{prog} was killed by a signal"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(fstype: &str, options: &str, passno: u32) -> FstabEntry {
+        FstabEntry {
+            spec: "/dev/sda2".to_string(),
+            target: "/home".to_string(),
+            fstype: fstype.to_string(),
+            options: options.to_string(),
+            freq: 0,
+            passno,
+        }
+    }
+
+    #[test]
+    fn outcome_test() {
+        assert_eq!(Outcome::from_code(0), Outcome::Clean);
+        assert_eq!(Outcome::from_code(1), Outcome::Corrected);
+        assert_eq!(Outcome::from_code(2), Outcome::Reboot);
+        assert_eq!(Outcome::from_code(3), Outcome::Reboot);
+        assert_eq!(Outcome::from_code(4), Outcome::Failed);
+        assert_eq!(Outcome::from_code(6), Outcome::Failed);
+        assert_eq!(Outcome::from_code(8), Outcome::Failed);
+    }
+
+    #[test]
+    fn command_test() {
+        let conf = Fsck::default();
+        let home = entry("ext4", "defaults", 2);
+
+        assert!(needs_check(&conf, FsckMode::auto, &home));
+        assert!(!needs_check(&conf, FsckMode::skip, &home));
+        assert!(!needs_check(
+            &conf,
+            FsckMode::auto,
+            &entry("ext4", "defaults", 0)
+        ));
+        assert!(!needs_check(
+            &conf,
+            FsckMode::auto,
+            &entry("ext4", "noauto", 2)
+        ));
+
+        assert_eq!(
+            command(&conf, FsckMode::auto, &home, "/dev/sda2"),
+            ["/sbin/fsck", "-a", "-t", "ext4", "/dev/sda2"]
+        );
+        assert_eq!(
+            command(
+                &conf,
+                FsckMode::force,
+                &entry("auto", "defaults", 2),
+                "/dev/sda2"
+            ),
+            ["/sbin/fsck", "-a", "-f", "/dev/sda2"]
+        );
+    }
+}
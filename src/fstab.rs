@@ -0,0 +1,508 @@
+//! Processing of `/etc/fstab`
+//!
+//! Entries are mounted by init after the API filesystems. Sources given as
+//! `UUID=`, `LABEL=`, `PARTUUID=` or `PARTLABEL=` are looked up in
+//! `/dev/disk/by-*`; if there are no such links (e.g. there is no udev),
+//! the superblocks of the block devices are probed (ext2/3/4 and swap are
+//! recognized). A filesystem is mounted after the filesystems containing
+//! its mount point and its bind source, so the order of the entries
+//! doesn't matter. At shutdown the filesystems are unmounted in the
+//! reverse order.
+//!
+//! Options handled by init (they are not passed to the kernel):
+//!
+//! - `noauto` - don't mount at boot;
+//! - `nofail` - don't enter the emergency mode if the mount fails;
+//! - `x-init.mkdir` or `x-init.mkdir=MODE` - create the mount point;
+//! - `x-init.after=PATH` - mount after the filesystem mounted on `PATH`;
+//! - `x-init.timeout=SEC` - wait for the device to appear.
+
+use anyhow::anyhow;
+use anyhow::Result;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::thread::sleep;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::config::MountPoint;
+use crate::consts::DISK_DIR;
+use crate::mount;
+use crate::paths::Paths;
+
+/// Options of `x-init.*` understood by init
+const INIT_OPTIONS: [&str; 3] = ["x-init.mkdir", "x-init.after", "x-init.timeout"];
+
+/// Options used by `mount(8)` and other programs that must not be passed
+/// to the kernel
+const USERSPACE_OPTIONS: [&str; 9] = [
+    "auto", "noauto", "nofail", "user", "users", "nouser", "owner", "group", "_netdev",
+];
+
+/// Entry of `/etc/fstab`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FstabEntry {
+    /// Device, `UUID=...`, `LABEL=...` or the source of a bind mount
+    pub spec: String,
+
+    /// Mount point (`none` for swap)
+    pub target: String,
+
+    pub fstype: String,
+
+    /// Comma-separated options
+    pub options: String,
+
+    /// Dump frequency (unused)
+    pub freq: u32,
+
+    /// Order of filesystem checks: `0` - don't check, `1` - root, `2` - others
+    pub passno: u32,
+}
+
+impl FstabEntry {
+    pub fn has_option(&self, name: &str) -> bool {
+        self.options
+            .split(',')
+            .any(|opt| opt == name || opt.split_once('=').is_some_and(|(key, _)| key == name))
+    }
+
+    /// Returns the value of the `name=value` option
+    pub fn option_value(&self, name: &str) -> Option<&str> {
+        self.options
+            .split(',')
+            .filter_map(|opt| opt.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, val)| val)
+    }
+
+    pub fn is_swap(&self) -> bool {
+        self.fstype == "swap"
+    }
+
+    pub fn is_bind(&self) -> bool {
+        self.has_option("bind") || self.has_option("rbind")
+    }
+
+    /// Returns the options passed to the kernel
+    pub fn mount_options(&self) -> String {
+        self.options
+            .split(',')
+            .filter(|opt| {
+                let key = opt.split_once('=').map_or(*opt, |(key, _)| key);
+                !USERSPACE_OPTIONS.contains(&key) && !key.starts_with("x-") && key != "comment"
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    fn to_mount_point(&self, source: String) -> MountPoint {
+        MountPoint {
+            source: Some(source),
+            target: self.target.clone(),
+            fstype: self.fstype.clone(),
+            options: Some(self.mount_options()),
+        }
+    }
+
+    /// Returns the `x-init.*` options not understood by init
+    pub fn unknown_init_options(&self) -> Vec<&str> {
+        self.options
+            .split(',')
+            .filter(|opt| {
+                let key = opt.split_once('=').map_or(*opt, |(key, _)| key);
+                key.starts_with("x-init.") && !INIT_OPTIONS.contains(&key)
+            })
+            .collect()
+    }
+}
+
+/// Parses the content of `/etc/fstab`
+pub fn parse(content: &str) -> Result<Vec<FstabEntry>> {
+    let mut entries = vec![];
+
+    for (idx, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if !(4..=6).contains(&fields.len()) {
+            return Err(anyhow!(
+                "line {}: expected 4-6 fields, found {}",
+                idx + 1,
+                fields.len()
+            ));
+        }
+
+        let number = |pos: usize| -> Result<u32> {
+            match fields.get(pos) {
+                Some(val) => val
+                    .parse()
+                    .map_err(|_| anyhow!("line {}: invalid number `{val}`", idx + 1)),
+                None => Ok(0),
+            }
+        };
+
+        entries.push(FstabEntry {
+            spec: mount::unescape(fields[0]),
+            target: mount::unescape(fields[1]),
+            fstype: fields[2].to_string(),
+            options: fields[3].to_string(),
+            freq: number(4)?,
+            passno: number(5)?,
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Reads `/etc/fstab`. No entries are returned if the file doesn't exist
+pub fn read(paths: &Paths) -> Result<Vec<FstabEntry>> {
+    let pth = paths.fstab();
+    if !pth.exists() {
+        return Ok(vec![]);
+    }
+
+    let content = fs::read_to_string(&pth)?;
+    parse(&content).map_err(|why| anyhow!("{}: {why}", pth.display()))
+}
+
+/// Returns the filesystems (without swap) in the order they must be
+/// mounted: each one after the filesystems containing its mount point,
+/// its bind source and its `x-init.after` target. Otherwise the order of
+/// `/etc/fstab` is kept
+pub fn mount_order(entries: &[FstabEntry]) -> Result<Vec<&FstabEntry>> {
+    let entries: Vec<&FstabEntry> = entries.iter().filter(|entry| !entry.is_swap()).collect();
+
+    let deps: Vec<Vec<usize>> = entries
+        .iter()
+        .map(|entry| {
+            entries
+                .iter()
+                .enumerate()
+                .filter(|(_, other)| other.target != entry.target)
+                .filter(|(_, other)| {
+                    is_under(&entry.target, &other.target)
+                        || (entry.is_bind() && is_under(&entry.spec, &other.target))
+                        || entry.option_value("x-init.after") == Some(other.target.as_str())
+                })
+                .map(|(idx, _)| idx)
+                .collect()
+        })
+        .collect();
+
+    let mut order = vec![];
+    let mut done = vec![false; entries.len()];
+    while order.len() < entries.len() {
+        let next = (0..entries.len())
+            .find(|&idx| !done[idx] && deps[idx].iter().all(|&dep| done[dep]))
+            .ok_or_else(|| {
+                let rest: Vec<&str> = (0..entries.len())
+                    .filter(|&idx| !done[idx])
+                    .map(|idx| entries[idx].target.as_str())
+                    .collect();
+                anyhow!("mount order cycle between {}", rest.join(", "))
+            })?;
+
+        done[next] = true;
+        order.push(entries[next]);
+    }
+
+    Ok(order)
+}
+
+/// Returns the filesystems in the order they must be unmounted at
+/// shutdown. The root filesystem is not included
+pub fn unmount_order(entries: &[FstabEntry]) -> Result<Vec<&FstabEntry>> {
+    let mut order = mount_order(entries)?;
+    order.retain(|entry| entry.target != "/");
+    order.reverse();
+
+    Ok(order)
+}
+
+/// Whether the `pth` is inside the directory `dir` (or is `dir` itself)
+fn is_under(pth: &str, dir: &str) -> bool {
+    Path::new(pth).starts_with(dir)
+}
+
+/// Returns the device specified by the `spec` (`UUID=...`, `LABEL=...`,
+/// `PARTUUID=...`, `PARTLABEL=...` or a path)
+pub fn resolve_source(paths: &Paths, spec: &str) -> Result<String> {
+    let Some((key, val)) = spec.split_once('=') else {
+        return Ok(spec.to_string());
+    };
+
+    let link_dir = match key {
+        "UUID" => "by-uuid",
+        "LABEL" => "by-label",
+        "PARTUUID" => "by-partuuid",
+        "PARTLABEL" => "by-partlabel",
+        _ => return Ok(spec.to_string()),
+    };
+
+    let link = paths.disk_dir().join(link_dir).join(val);
+    if let Ok(target) = fs::read_link(&link) {
+        let dev = Path::new(DISK_DIR).join(link_dir).join(target);
+        return Ok(normalize(&dev).display().to_string());
+    }
+
+    if let Some(dev) = probe(paths, key, val) {
+        return Ok(dev);
+    }
+
+    Err(anyhow!("device {spec} not found"))
+}
+
+/// Removes `.` and `..` from the absolute path
+fn normalize(pth: &Path) -> PathBuf {
+    let mut res = PathBuf::from("/");
+    for comp in pth.components() {
+        match comp.as_os_str().to_str() {
+            Some("..") => {
+                res.pop();
+            }
+            Some(".") | Some("/") => {}
+            _ => res.push(comp),
+        }
+    }
+    res
+}
+
+/// Looks for the block device with the filesystem `UUID` or `LABEL`
+/// among the devices listed in `/sys/class/block`
+fn probe(paths: &Paths, key: &str, val: &str) -> Option<String> {
+    let mut names: Vec<String> = fs::read_dir(paths.sys_block_dir())
+        .ok()?
+        .flatten()
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect();
+    names.sort();
+
+    names
+        .into_iter()
+        .map(|name| format!("/dev/{name}"))
+        .find(|dev| {
+            let Ok(Some(sb)) = read_superblock(&paths.resolve(dev)) else {
+                return false;
+            };
+            match key {
+                "UUID" => sb.uuid.eq_ignore_ascii_case(val),
+                "LABEL" => sb.label == val,
+                _ => false,
+            }
+        })
+}
+
+/// Identifiers of a filesystem found on the device
+#[derive(Debug, PartialEq)]
+struct Superblock {
+    uuid: String,
+    label: String,
+}
+
+/// Reads the UUID and the label of the ext2/3/4 filesystem or the swap
+/// area on the device
+fn read_superblock(dev: &Path) -> Result<Option<Superblock>> {
+    let mut file = File::open(dev)?;
+    let mut buf = vec![0; 4096];
+    file.seek(SeekFrom::Start(0))?;
+    let len = file.read(&mut buf)?;
+    buf.truncate(len);
+
+    let field = |start: usize, len: usize| buf.get(start..start + len);
+
+    // swap: "SWAPSPACE2" at the end of the first page, UUID at 0x40c
+    // and label at 0x41c
+    if field(4096 - 10, 10) == Some(b"SWAPSPACE2") {
+        return Ok(field(0x40c, 16)
+            .zip(field(0x41c, 16))
+            .map(|(uuid, label)| Superblock {
+                uuid: format_uuid(uuid),
+                label: c_string(label),
+            }));
+    }
+
+    // ext2/3/4: superblock at 1024, magic 0xEF53 at 0x38, UUID at 0x68
+    // and label at 0x78
+    if field(1024 + 0x38, 2) == Some(&[0x53, 0xef]) {
+        return Ok(field(1024 + 0x68, 16)
+            .zip(field(1024 + 0x78, 16))
+            .map(|(uuid, label)| Superblock {
+                uuid: format_uuid(uuid),
+                label: c_string(label),
+            }));
+    }
+
+    Ok(None)
+}
+
+fn format_uuid(bytes: &[u8]) -> String {
+    let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+fn c_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).to_string()
+}
+
+/// Returns the device of the entry, waiting for it to appear for
+/// `x-init.timeout` seconds
+pub fn wait_source(paths: &Paths, entry: &FstabEntry) -> Result<String> {
+    let timeout = match entry.option_value("x-init.timeout") {
+        Some(val) => val
+            .parse()
+            .map_err(|_| anyhow!("invalid x-init.timeout: {val}"))?,
+        None => 0,
+    };
+
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+    loop {
+        match resolve_source(paths, &entry.spec) {
+            Ok(source) if !source.starts_with("/dev/") || paths.resolve(&source).exists() => {
+                return Ok(source)
+            }
+            Ok(source) if Instant::now() >= deadline => {
+                return Err(anyhow!("device {source} not found"))
+            }
+            Err(why) if Instant::now() >= deadline => return Err(why),
+            _ => sleep(Duration::from_millis(100)),
+        }
+    }
+}
+
+/// Mounts the filesystem of the entry, waiting for its device and
+/// creating the mount point if `x-init.mkdir` is specified
+pub fn mount_entry(paths: &Paths, entry: &FstabEntry) -> Result<()> {
+    let source = wait_source(paths, entry)?;
+
+    if entry.has_option("x-init.mkdir") {
+        let mode = match entry.option_value("x-init.mkdir") {
+            Some(mode) => u32::from_str_radix(mode, 8)
+                .map_err(|_| anyhow!("invalid x-init.mkdir mode: {mode}"))?,
+            None => 0o755,
+        };
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(mode)
+            .create(paths.resolve(&entry.target))?;
+    }
+
+    mount::mount(paths, &entry.to_mount_point(source))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_root;
+    use std::os::unix::fs::symlink;
+
+    const FSTAB: &str = r#"
+# <fs>          <mountpoint>      <type>  <opts>                   <dump> <pass>
+/dev/mmcblk0p2  /                 ext4    defaults,noatime         0      1
+/home/srv       /srv/data         none    bind,x-init.mkdir        0      0
+UUID=0f3e-11    /home             ext4    nofail,x-init.timeout=5  0      2
+LABEL=backup    /mnt/backup       ext4    noauto,x-init.foo        0      0
+/dev/sda2       none              swap    sw                       0      0
+tmpfs           /var/tmp          tmpfs   mode=1777,x-init.after=/home
+proc            /proc             proc    defaults
+"#;
+
+    #[test]
+    fn parse_test() {
+        let entries = parse(FSTAB).unwrap();
+        assert_eq!(entries.len(), 7);
+        assert_eq!(entries[0].passno, 1);
+        assert_eq!(entries[5].passno, 0);
+        assert!(entries[4].is_swap());
+        assert!(entries[1].is_bind());
+        assert_eq!(entries[2].option_value("x-init.timeout"), Some("5"));
+        assert_eq!(entries[2].mount_options(), "");
+        assert_eq!(entries[1].mount_options(), "bind");
+        assert_eq!(entries[3].unknown_init_options(), ["x-init.foo"]);
+
+        assert!(parse("/dev/sda1 /").is_err());
+        assert!(parse("/dev/sda1 / ext4 defaults x 1").is_err());
+    }
+
+    #[test]
+    fn order_test() {
+        let entries = parse(FSTAB).unwrap();
+
+        let order: Vec<&str> = mount_order(&entries)
+            .unwrap()
+            .iter()
+            .map(|entry| entry.target.as_str())
+            .collect();
+        assert_eq!(
+            order,
+            [
+                "/",
+                "/home",
+                "/srv/data",
+                "/mnt/backup",
+                "/var/tmp",
+                "/proc"
+            ]
+        );
+
+        let order: Vec<&str> = unmount_order(&entries)
+            .unwrap()
+            .iter()
+            .map(|entry| entry.target.as_str())
+            .collect();
+        assert_eq!(
+            order,
+            ["/proc", "/var/tmp", "/mnt/backup", "/srv/data", "/home"]
+        );
+
+        let cycle = parse("a /a tmpfs x-init.after=/b\nb /b tmpfs x-init.after=/a\n").unwrap();
+        assert!(mount_order(&cycle).is_err());
+    }
+
+    #[test]
+    fn resolve_test() {
+        let root = temp_root("fstab");
+        let paths = Paths::with_root(&root);
+
+        fs::create_dir_all(root.join("dev/disk/by-label")).unwrap();
+        symlink("../../sda1", root.join("dev/disk/by-label/rootfs")).unwrap();
+
+        // ext4 superblock of /dev/sdb1 without /dev/disk/by-uuid links
+        let mut image = vec![0u8; 4096];
+        image[1024 + 0x38..1024 + 0x3a].copy_from_slice(&[0x53, 0xef]);
+        image[1024 + 0x68..1024 + 0x78].copy_from_slice(&[0xab; 16]);
+        image[1024 + 0x78..1024 + 0x7c].copy_from_slice(b"data");
+        fs::write(root.join("dev/sdb1"), &image).unwrap();
+        fs::write(root.join("dev/sda1"), vec![0u8; 4096]).unwrap();
+        fs::create_dir_all(root.join("sys/class/block/sda1")).unwrap();
+        fs::create_dir_all(root.join("sys/class/block/sdb1")).unwrap();
+
+        assert_eq!(resolve_source(&paths, "LABEL=rootfs").unwrap(), "/dev/sda1");
+        assert_eq!(
+            resolve_source(&paths, "UUID=ABABABAB-ABAB-ABAB-ABAB-ABABABABABAB").unwrap(),
+            "/dev/sdb1"
+        );
+        assert_eq!(resolve_source(&paths, "LABEL=data").unwrap(), "/dev/sdb1");
+        assert!(resolve_source(&paths, "PARTUUID=1234").is_err());
+        assert_eq!(resolve_source(&paths, "/dev/sdc").unwrap(), "/dev/sdc");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
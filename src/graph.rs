@@ -0,0 +1,459 @@
+//! Dependency graph of the services
+//!
+//! Nodes are the services of the runlevels, edges go from the service to
+//! the services it `requires` or `wants`. The state of the last boot is
+//! taken from `/run/init/state` and `/run/init/boot.toml`: failed services
+//! are marked, and the longest chain of dependencies weighted by the start
+//! times of the services (the critical path) is highlighted.
+
+use anyhow::anyhow;
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::boot;
+use crate::boot::BootRecord;
+use crate::config::Config;
+use crate::paths::Paths;
+use crate::service::Service;
+use crate::state::ServiceRecord;
+use crate::state::ServiceState;
+use crate::traits::TomlConfig;
+
+#[derive(Debug, Serialize)]
+pub struct Graph {
+    /// Runlevels in the order of their entering
+    pub runlevels: Vec<String>,
+
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>,
+
+    /// Services of the critical path from the first started one
+    pub critical_path: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Node {
+    pub runlevel: String,
+    pub service: String,
+
+    /// State of the service after the last boot
+    pub state: Option<ServiceState>,
+
+    /// Time (in milliseconds) the service took to start during the last boot
+    pub start_time: Option<u64>,
+
+    /// Error of the service file
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Edge {
+    /// Dependent service
+    pub from: String,
+
+    /// Dependency
+    pub to: String,
+
+    pub kind: EdgeKind,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum EdgeKind {
+    requires,
+    wants,
+}
+
+impl Graph {
+    /// Builds the graph of the services of all runlevels or only of the
+    /// `runlevel` (its dependencies from other runlevels are included too).
+    /// `records` and `timings` are the state of the services and the
+    /// timings of the last boot
+    pub fn build(
+        paths: &Paths,
+        runlevel: Option<&str>,
+        records: &[ServiceRecord],
+        timings: &BootRecord,
+    ) -> Result<Self> {
+        let conf = Config::parse(paths.master_conf())?;
+
+        if let Some(rl) = runlevel {
+            if !conf.runlevel.iter().any(|r| r.dir == rl) {
+                return Err(anyhow!("unknown runlevel: {rl}"));
+            }
+        }
+
+        let mut graph = Self {
+            runlevels: vec![],
+            nodes: vec![],
+            edges: vec![],
+            critical_path: vec![],
+        };
+        let mut queue: Vec<(String, String)> = conf
+            .runlevel
+            .iter()
+            .filter(|rl| runlevel.is_none() || runlevel == Some(rl.dir.as_str()))
+            .flat_map(|rl| {
+                let services = rl.services.clone().unwrap_or_default();
+                services.into_iter().map(|srv| (rl.dir.clone(), srv))
+            })
+            .collect();
+        queue.reverse();
+
+        while let Some((rl, name)) = queue.pop() {
+            if graph.node(&name).is_some() {
+                continue;
+            }
+
+            let record = records.iter().find(|srv| srv.rl == rl && srv.name == name);
+            let mut node = Node {
+                runlevel: rl.clone(),
+                service: name.clone(),
+                state: record.map(|srv| srv.state),
+                start_time: timings.service(&name).and_then(|t| t.duration()),
+                error: None,
+            };
+
+            match Service::new(paths, &name, &rl) {
+                Ok(service) => {
+                    let deps = [
+                        (EdgeKind::requires, service.init.requires),
+                        (EdgeKind::wants, service.init.wants),
+                    ];
+                    for (kind, deps) in deps {
+                        for dep in deps.unwrap_or_default() {
+                            // unknown dependencies are reported by `service check`
+                            if let Some(dep_rl) = conf.find_service(&dep) {
+                                queue.push((dep_rl.dir.clone(), dep.clone()));
+                            }
+                            graph.edges.push(Edge {
+                                from: name.clone(),
+                                to: dep,
+                                kind,
+                            });
+                        }
+                    }
+                }
+                Err(why) => node.error = Some(why.to_string()),
+            }
+
+            graph.nodes.push(node);
+        }
+
+        let nodes = &graph.nodes;
+        graph
+            .edges
+            .retain(|edge| nodes.iter().any(|node| node.service == edge.to));
+        graph.runlevels = conf
+            .runlevel
+            .iter()
+            .map(|rl| rl.dir.clone())
+            .filter(|rl| graph.nodes.iter().any(|node| &node.runlevel == rl))
+            .collect();
+        graph.critical_path = graph.find_critical_path();
+
+        Ok(graph)
+    }
+
+    pub fn node(&self, name: &str) -> Option<&Node> {
+        self.nodes.iter().find(|node| node.service == name)
+    }
+
+    /// Whether the edge lies on the critical path
+    pub fn is_critical(&self, edge: &Edge) -> bool {
+        self.critical_path
+            .windows(2)
+            .any(|pair| pair[1] == edge.from && pair[0] == edge.to)
+    }
+
+    /// Returns the chain of dependencies with the longest total start time.
+    /// Empty if the start times are unknown
+    fn find_critical_path(&self) -> Vec<String> {
+        // total start time of the service with all its dependencies and
+        // the dependency it has waited for the longest
+        fn visit<'a>(
+            graph: &'a Graph,
+            name: &'a str,
+            stack: &mut Vec<&'a str>,
+            done: &mut BTreeMap<&'a str, (u64, Option<&'a str>)>,
+        ) -> u64 {
+            if let Some((total, _)) = done.get(name) {
+                return *total;
+            }
+            // cycles are reported by `service check`
+            if stack.contains(&name) {
+                return 0;
+            }
+
+            stack.push(name);
+            let mut longest: (u64, Option<&str>) = (0, None);
+            for edge in graph.edges.iter().filter(|edge| edge.from == name) {
+                let total = visit(graph, &edge.to, stack, done);
+                if longest.1.is_none() || total > longest.0 {
+                    longest = (total, Some(edge.to.as_str()));
+                }
+            }
+            stack.pop();
+
+            let own = graph
+                .node(name)
+                .and_then(|node| node.start_time)
+                .unwrap_or_default();
+            done.insert(name, (own + longest.0, longest.1));
+
+            own + longest.0
+        }
+
+        let mut done = BTreeMap::new();
+        let mut last = None;
+        let mut max = 0;
+        for node in &self.nodes {
+            let total = visit(self, &node.service, &mut vec![], &mut done);
+            if total > max {
+                (max, last) = (total, Some(node.service.as_str()));
+            }
+        }
+
+        let mut path = vec![];
+        while let Some(name) = last {
+            path.push(name.to_string());
+            last = done.get(name).and_then(|(_, dep)| *dep);
+        }
+        path.reverse();
+
+        path
+    }
+
+    /// Returns the graph in the Graphviz format
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph services {\n    rankdir=LR;\n    node [shape=box];\n");
+
+        for (idx, rl) in self.runlevels.iter().enumerate() {
+            let _ = writeln!(
+                dot,
+                "\n    subgraph cluster_{idx} {{\n        label=\"{rl}\";"
+            );
+            for node in self.nodes.iter().filter(|node| &node.runlevel == rl) {
+                let mut attrs = vec![format!("label=\"{}\"", self.label(node))];
+                if self.is_failed(node) {
+                    attrs.push("color=red".to_string());
+                    attrs.push("fontcolor=red".to_string());
+                }
+                if self.critical_path.contains(&node.service) {
+                    attrs.push("penwidth=2".to_string());
+                }
+                let _ = writeln!(dot, "        \"{}\" [{}];", &node.service, attrs.join(", "));
+            }
+            dot.push_str("    }\n");
+        }
+
+        dot.push('\n');
+        for edge in &self.edges {
+            let mut attrs = vec![];
+            if edge.kind == EdgeKind::wants {
+                attrs.push("style=dashed");
+            }
+            if self.is_critical(edge) {
+                attrs.push("color=red");
+                attrs.push("penwidth=2");
+            }
+            let attrs = match attrs.is_empty() {
+                true => String::new(),
+                false => format!(" [{}]", attrs.join(", ")),
+            };
+            let _ = writeln!(dot, "    \"{}\" -> \"{}\"{attrs};", &edge.from, &edge.to);
+        }
+        dot.push_str("}\n");
+
+        dot
+    }
+
+    /// Returns the graph as a Mermaid flowchart
+    pub fn to_mermaid(&self) -> String {
+        let mut mmd = String::from("flowchart LR\n");
+
+        for rl in &self.runlevels {
+            let _ = writeln!(mmd, "    subgraph {rl}");
+            for node in self.nodes.iter().filter(|node| &node.runlevel == rl) {
+                let _ = writeln!(
+                    mmd,
+                    "        {}[\"{}\"]",
+                    mermaid_id(&node.service),
+                    self.label(node).replace("\\n", "<br>")
+                );
+            }
+            mmd.push_str("    end\n");
+        }
+
+        let mut critical = vec![];
+        for (idx, edge) in self.edges.iter().enumerate() {
+            let arrow = match edge.kind {
+                EdgeKind::requires => "-->",
+                EdgeKind::wants => "-.->",
+            };
+            let _ = writeln!(
+                mmd,
+                "    {} {arrow} {}",
+                mermaid_id(&edge.from),
+                mermaid_id(&edge.to)
+            );
+            if self.is_critical(edge) {
+                critical.push(idx.to_string());
+            }
+        }
+
+        let failed: Vec<String> = self
+            .nodes
+            .iter()
+            .filter(|node| self.is_failed(node))
+            .map(|node| mermaid_id(&node.service))
+            .collect();
+        if !failed.is_empty() {
+            mmd.push_str("    classDef failed stroke:#f00,color:#f00\n");
+            let _ = writeln!(mmd, "    class {} failed", failed.join(","));
+        }
+        if !critical.is_empty() {
+            let _ = writeln!(
+                mmd,
+                "    linkStyle {} stroke:#f00,stroke-width:3px",
+                critical.join(",")
+            );
+        }
+
+        mmd
+    }
+
+    fn is_failed(&self, node: &Node) -> bool {
+        node.error.is_some() || node.state == Some(ServiceState::failed)
+    }
+
+    fn label(&self, node: &Node) -> String {
+        let mut label = node.service.clone();
+        if let Some(state) = node.state {
+            let _ = write!(label, "\\n{state}");
+        }
+        if let Some(time) = node.start_time {
+            let _ = write!(label, " ({})", boot::secs(time));
+        }
+        label
+    }
+}
+
+/// Mermaid doesn't allow some characters (e.g. `-`) in the node IDs
+fn mermaid_id(name: &str) -> String {
+    name.chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c,
+            false => '_',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boot::ServiceTiming;
+    use crate::testutil::temp_root;
+    use crate::testutil::write;
+    use std::fs;
+
+    fn record(rl: &str, name: &str, state: ServiceState) -> ServiceRecord {
+        let mut srv = ServiceRecord::new(rl, name);
+        srv.set_state(ServiceState::starting).unwrap();
+        srv.set_state(state).unwrap();
+        srv
+    }
+
+    fn timing(name: &str, start: u64, ready: u64) -> ServiceTiming {
+        ServiceTiming {
+            runlevel: "rl1".to_string(),
+            service: name.to_string(),
+            start,
+            ready: Some(ready),
+            failed: None,
+            exit: None,
+            skipped: None,
+        }
+    }
+
+    #[test]
+    fn graph_test() {
+        let root = temp_root("graph");
+        let paths = Paths::with_root(&root);
+
+        write(
+            &paths,
+            "/etc/init/init.toml",
+            r#"
+final_runlevel = "rl2"
+
+[[runlevel]]
+dir = "rl1"
+description = "Single user mode"
+services = ["mount", "udev", "klogd"]
+
+[[runlevel]]
+dir = "rl2"
+description = "Multi user mode"
+services = ["syslogd", "sshd"]
+"#,
+        );
+        for (rl, name, deps) in [
+            ("rl1", "mount", ""),
+            ("rl1", "udev", "requires = [\"mount\"]"),
+            ("rl1", "klogd", "wants = [\"syslogd\"]"),
+            ("rl2", "syslogd", "requires = [\"mount\"]"),
+            (
+                "rl2",
+                "sshd",
+                "requires = [\"udev\"]\nwants = [\"syslogd\"]",
+            ),
+        ] {
+            write(
+                &paths,
+                &format!("/etc/init/{rl}/{name}.toml"),
+                &format!("[service]\nstart = [\"/bin/true\"]\n\n[init]\ndescription = \"{name}\"\n{deps}\n"),
+            );
+        }
+
+        let records = [
+            record("rl1", "mount", ServiceState::running),
+            record("rl1", "udev", ServiceState::running),
+            record("rl2", "syslogd", ServiceState::running),
+            record("rl2", "sshd", ServiceState::failed),
+        ];
+        let timings = BootRecord {
+            services: vec![
+                timing("mount", 0, 1000),
+                timing("udev", 1000, 6000),
+                timing("syslogd", 6000, 8000),
+                timing("sshd", 8000, 9000),
+            ],
+            ..BootRecord::default()
+        };
+
+        let graph = Graph::build(&paths, None, &records, &timings).unwrap();
+        assert_eq!(graph.nodes.len(), 5);
+        assert_eq!(graph.edges.len(), 5);
+        assert_eq!(graph.critical_path, ["mount", "udev", "sshd"]);
+
+        let dot = graph.to_dot();
+        assert!(dot.contains("\"klogd\" -> \"syslogd\" [style=dashed];"));
+        assert!(dot.contains("\"sshd\" -> \"udev\" [color=red, penwidth=2];"));
+        assert!(dot.contains("\"sshd\" [label=\"sshd\\nfailed (1.000s)\", color=red"));
+
+        let mmd = graph.to_mermaid();
+        assert!(mmd.contains("    klogd -.-> syslogd\n"));
+        assert!(mmd.contains("    class sshd failed\n"));
+
+        // dependencies from other runlevels are included
+        let graph = Graph::build(&paths, Some("rl2"), &[], &BootRecord::default()).unwrap();
+        assert_eq!(graph.runlevels, ["rl1", "rl2"]);
+        assert_eq!(graph.nodes.len(), 4);
+        assert!(graph.critical_path.is_empty());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
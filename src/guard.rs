@@ -0,0 +1,82 @@
+//! Protection of PID 1 from panics
+//!
+//! If PID 1 exits, the kernel panics. So init catches its own panics,
+//! logs them to the console and to the kernel log and then performs the
+//! `on_panic` action from the master config file.
+
+use colored::Colorize;
+use std::panic;
+use std::thread::sleep;
+use std::time::Duration;
+
+use nix::sys::reboot::reboot;
+use nix::sys::reboot::RebootMode;
+use nix::unistd::sync;
+
+use crate::config::Config;
+use crate::config::PanicAction;
+use crate::emergency;
+use crate::msg::kmsg;
+use crate::paths::Paths;
+use crate::traits::TomlConfig;
+
+/// Delay before the `PanicAction::reboot` if `OnPanic.delay` is not specified
+const DEFAULT_REBOOT_DELAY: u64 = 10;
+
+/// Installs the panic hook that prints the panic message to the
+/// console and to `/dev/kmsg`
+pub fn install_panic_hook() {
+    panic::set_hook(Box::new(|info| {
+        let msg = format!("PANIC: {info}");
+        eprintln!("init: {}", msg.bold().red());
+        kmsg(2, msg);
+    }));
+}
+
+/// Runs `f`. If it panics or returns, performs the `on_panic` action.
+/// Never returns
+pub fn run(f: fn()) -> ! {
+    if panic::catch_unwind(f).is_ok() {
+        eprintln!("init: main loop exited unexpectedly");
+        kmsg(2, "main loop exited unexpectedly");
+    }
+
+    // the config is read again because the state of the panicked
+    // init can't be trusted
+    let paths = Paths::from_env();
+    let conf = Config::parse(paths.master_conf()).unwrap_or_default();
+    let on_panic = conf.on_panic.unwrap_or_default();
+
+    match on_panic.action.unwrap_or_default() {
+        PanicAction::shell => {
+            emergency::rescue_shell(&paths, &conf.emergency.unwrap_or_default());
+            restart_system(RebootMode::RB_AUTOBOOT, 0);
+        }
+        PanicAction::reboot => restart_system(
+            RebootMode::RB_AUTOBOOT,
+            on_panic.delay.unwrap_or(DEFAULT_REBOOT_DELAY),
+        ),
+        PanicAction::halt => restart_system(RebootMode::RB_HALT_SYSTEM, 0),
+        PanicAction::freeze => {}
+    }
+
+    freeze()
+}
+
+fn restart_system(mode: RebootMode, delay: u64) {
+    if delay > 0 {
+        eprintln!("init: the system will be rebooted in {delay} seconds...");
+        sleep(Duration::from_secs(delay));
+    }
+
+    sync();
+    let Err(why) = reboot(mode);
+    eprintln!("init: reboot error: {why}");
+}
+
+fn freeze() -> ! {
+    eprintln!("init: system halted");
+    loop {
+        std::thread::park();
+    }
+}
@@ -0,0 +1,248 @@
+//! Health checks of running services
+//!
+//! A daemon may stay alive but stop doing its job. Init periodically runs
+//! the check declared in the `[health]` section of the service and marks
+//! the service `unhealthy` after several consecutive failures.
+
+use anyhow::anyhow;
+use anyhow::Result;
+use nix::errno::Errno;
+use nix::poll::poll;
+use nix::poll::PollFd;
+use nix::poll::PollFlags;
+use nix::sys::signal::Signal;
+use nix::sys::socket::connect;
+use nix::sys::socket::getsockopt;
+use nix::sys::socket::socket;
+use nix::sys::socket::sockopt;
+use nix::sys::socket::SockFlag;
+use nix::sys::socket::SockType;
+use nix::sys::socket::SockaddrIn;
+use nix::sys::socket::SockaddrIn6;
+use nix::sys::socket::SockaddrLike;
+use nix::sys::socket::UnixAddr;
+use nix::sys::wait::WaitStatus;
+use std::io;
+use std::net::SocketAddr;
+use std::net::ToSocketAddrs;
+use std::os::fd::AsFd;
+use std::os::fd::AsRawFd;
+use std::os::fd::OwnedFd;
+use std::process::Child;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::service::HealthSection;
+use crate::service::Service;
+
+/// Time in seconds between checks if `interval` is not specified
+pub const DEFAULT_INTERVAL: u64 = 30;
+
+/// Time in seconds to wait for a check if `timeout` is not specified
+const DEFAULT_TIMEOUT: u64 = 5;
+
+/// Number of failed checks if `threshold` is not specified
+pub const DEFAULT_THRESHOLD: u32 = 3;
+
+/// Health check in progress. Checks never block init: the check command
+/// is reaped by the main loop and connections are established in the
+/// non-blocking mode
+pub enum Probe {
+    Command {
+        cmd: String,
+        child: Child,
+        deadline: Instant,
+    },
+    Connect {
+        addr: String,
+        sock: OwnedFd,
+        deadline: Instant,
+    },
+}
+
+/// Starts the health check of the `service`
+pub fn start(service: &Service, health: &HealthSection) -> Result<Probe> {
+    let timeout = Duration::from_secs(health.timeout.unwrap_or(DEFAULT_TIMEOUT));
+    let deadline = Instant::now() + timeout;
+
+    match (&health.command, &health.tcp, &health.unix) {
+        (Some(cmd), None, None) => Ok(Probe::Command {
+            cmd: cmd.clone(),
+            child: service.command(cmd)?.spawn()?,
+            deadline,
+        }),
+        (None, Some(addr), None) => Ok(Probe::Connect {
+            addr: addr.clone(),
+            sock: connect_tcp(addr)?,
+            deadline,
+        }),
+        (None, None, Some(path)) => Ok(Probe::Connect {
+            addr: path.clone(),
+            sock: connect_unix(path)?,
+            deadline,
+        }),
+        _ => Err(anyhow!(
+            "exactly one of `command`, `tcp` and `unix` must be specified in [health]"
+        )),
+    }
+}
+
+impl Probe {
+    /// PID of the check command
+    pub fn pid(&self) -> Option<u32> {
+        match self {
+            Self::Command { child, .. } => Some(child.id()),
+            Self::Connect { .. } => None,
+        }
+    }
+
+    /// Returns the result of the check if the connection has been
+    /// established or the timeout has expired. The check command that
+    /// has timed out is killed
+    pub fn poll(&mut self) -> Option<Result<()>> {
+        match self {
+            Self::Command {
+                cmd,
+                child,
+                deadline,
+            } => {
+                if Instant::now() < *deadline {
+                    return None;
+                }
+                let _ = child.kill();
+                Some(Err(anyhow!("`{cmd}` timed out")))
+            }
+            Self::Connect {
+                addr,
+                sock,
+                deadline,
+            } => {
+                let mut fds = [PollFd::new(sock.as_fd(), PollFlags::POLLOUT)];
+                if poll(&mut fds, 0u16).is_ok_and(|n| n > 0) {
+                    return Some(match getsockopt(sock, sockopt::SocketError) {
+                        Ok(0) => Ok(()),
+                        Ok(err) => Err(anyhow!("{addr}: {}", io::Error::from_raw_os_error(err))),
+                        Err(why) => Err(anyhow!("{addr}: {why}")),
+                    });
+                }
+
+                if Instant::now() < *deadline {
+                    return None;
+                }
+                Some(Err(anyhow!("{addr}: timed out")))
+            }
+        }
+    }
+
+    /// Returns the result of the check command that has exited with the
+    /// `status`
+    pub fn exited(&self, status: WaitStatus) -> Result<()> {
+        let Self::Command { cmd, .. } = self else {
+            return Ok(());
+        };
+
+        match status {
+            WaitStatus::Exited(_, 0) => Ok(()),
+            WaitStatus::Exited(_, code) => Err(anyhow!("`{cmd}` failed (exit code {code})")),
+            WaitStatus::Signaled(_, Signal::SIGKILL, _) => Err(anyhow!("`{cmd}` timed out")),
+            WaitStatus::Signaled(_, sig, _) => Err(anyhow!("`{cmd}` killed by {}", sig.as_str())),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn connect_tcp(addr: &str) -> Result<OwnedFd> {
+    match addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow!("{addr}: no address"))?
+    {
+        SocketAddr::V4(sa) => connect_nonblock(addr, &SockaddrIn::from(sa)),
+        SocketAddr::V6(sa) => connect_nonblock(addr, &SockaddrIn6::from(sa)),
+    }
+}
+
+fn connect_unix(path: &str) -> Result<OwnedFd> {
+    connect_nonblock(path, &UnixAddr::new(path)?)
+}
+
+/// Starts connecting to the address without waiting for the connection
+/// to be established
+fn connect_nonblock(name: &str, addr: &dyn SockaddrLike) -> Result<OwnedFd> {
+    let family = addr
+        .family()
+        .ok_or_else(|| anyhow!("{name}: unknown address family"))?;
+    let sock = socket(
+        family,
+        SockType::Stream,
+        SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+
+    match connect(sock.as_raw_fd(), addr) {
+        Ok(()) | Err(Errno::EINPROGRESS) => Ok(sock),
+        Err(why) => Err(anyhow!("{name}: {why}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::wait::waitpid;
+    use nix::unistd::Pid;
+    use std::net::TcpListener;
+    use std::thread::sleep;
+
+    /// Waits for the result of the probe like the main loop of init does
+    fn wait(mut probe: Probe) -> Result<()> {
+        loop {
+            if let Some(pid) = probe.pid() {
+                let status = waitpid(Pid::from_raw(pid as i32), None).unwrap();
+                return probe.exited(status);
+            }
+            if let Some(res) = probe.poll() {
+                return res;
+            }
+            sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn probe_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let service = Service::default();
+
+        let mut health: HealthSection = toml::from_str(&format!("tcp = \"{addr}\"")).unwrap();
+        assert!(wait(start(&service, &health).unwrap()).is_ok());
+
+        drop(listener);
+        assert!(start(&service, &health).and_then(wait).is_err());
+
+        health.tcp = None;
+        health.unix = Some("/nonexistent.sock".to_string());
+        assert!(start(&service, &health).and_then(wait).is_err());
+
+        health.unix = None;
+        health.command = Some("true".to_string());
+        assert!(wait(start(&service, &health).unwrap()).is_ok());
+
+        health.command = Some("false".to_string());
+        assert!(wait(start(&service, &health).unwrap()).is_err());
+    }
+
+    #[test]
+    fn timeout_test() {
+        // the check command that outlives its timeout is killed
+        let mut health: HealthSection = toml::from_str("command = \"sleep 10\"").unwrap();
+        health.timeout = Some(0);
+
+        let mut probe = start(&Service::default(), &health).unwrap();
+        let res = probe.poll().unwrap();
+        assert_eq!(res.unwrap_err().to_string(), "`sleep 10` timed out");
+
+        let pid = Pid::from_raw(probe.pid().unwrap() as i32);
+        let status = waitpid(pid, None).unwrap();
+        assert!(probe.exited(status).is_err());
+    }
+}
@@ -6,6 +6,7 @@
 //! - `/sbin/service` - программа для управления сервисами;
 //! - `/sbin/poweroff` - останавливает все запущенные сервисы и выключает систему;
 //! - `/sbin/reboot` - останавливает все запущенные сервисы и перезагружает систему;
+//! - `/sbin/telinit` - переключает работающую систему на другой уровень запуска;
 //!
 //! ## Примитивы
 //! LFA init использует два понятия: *сервис* и *уровень запуска*.
@@ -20,8 +21,8 @@
 //!
 //! - `rl0` - выключение системы. Не содержит никаких сервисов.
 //! - `rl1` - загрузка системы в однопользовательском режиме. Смонтированы указанные в `/etc/fstab`
-//!   разделы, а также ряд виртуальных файловых систем (см. `data/rl1/mount_*.toml`), в системе
-//!   присутствует только пользователь `root`.
+//!   разделы, а также виртуальные файловые системы (их монтирует сама `init`, см. секцию
+//!   `[early_mount]` в `init.toml`), в системе присутствует только пользователь `root`.
 //! - `rl2` - многопользовательский режим **без** поддержки сети.
 //! - `rl3` - многопользовательский режим с поддержкой сети. Используется по умолчанию в LFA.
 //! - `rl4` - многопользовательский режим с поддержкой графики (X.org/Wayland). На данный момент не
@@ -33,98 +34,386 @@
 //! конфигурационный файл `/etc/init/init.toml`, содержащий сведения об уровнях запуска и порядке
 //! загрузки ассоциированных с ними сервисов, файл `/etc/init/console.toml`, содержащий сведения
 //! о консолях (TTY), которые нужно использовать и директории `/etc/init/rl[0..5]` с сервисами.
+//!
+//! Все пути отсчитываются от корневого каталога (`/` по умолчанию), который можно изменить
+//! переменной окружения `LFA_INIT_ROOT` или параметром `--root` программ `service`, `telinit`,
+//! `poweroff` и `reboot` (см. `paths::Paths`). Это позволяет проверять корневую ФС образа и
+//! запускать весь процесс загрузки во временном каталоге в тестах.
+//!
+//! ## Состояние сервисов
+//! Работающая `init` хранит состояние запущенных сервисов в памяти и дублирует его в директорию
+//! `/run/init/state` (по одному TOML-файлу на каждый сервис). `/run` находится в tmpfs, поэтому
+//! состояние не переживает перезагрузку.
 
 pub mod config;
 pub mod consts;
 pub mod service;
+pub mod state;
 pub mod traits;
 
+pub mod boot;
+pub mod builtin;
+pub mod check;
+pub mod client;
 pub mod cmd;
+pub mod cmdline;
+pub mod conditions;
+pub mod control;
+pub mod devices;
+pub mod emergency;
+pub mod fsck;
+pub mod fstab;
+pub mod graph;
+pub mod guard;
+pub mod health;
+pub mod modules;
+pub mod mount;
 pub mod msg;
-
+pub mod paths;
+pub mod plan;
+pub mod ready;
+pub mod supervisor;
+pub mod swap;
+pub mod timeline;
+pub mod tmpfiles;
+
+#[cfg(test)]
+mod testutil;
+
+use clap::Parser;
 use colored::Colorize;
-use std::fmt::Display;
-use std::process::Command;
+use std::process::Child;
+use std::thread::sleep;
+use std::time::Duration;
 
+use nix::poll::poll;
+use nix::poll::PollFd;
+use nix::poll::PollFlags;
 use nix::sys::reboot::reboot;
 use nix::sys::reboot::RebootMode;
+use nix::sys::wait::waitpid;
+use nix::sys::wait::WaitPidFlag;
+use nix::unistd::sync;
 
-use service::OnError;
 use traits::TomlConfig;
 
-use config::Action;
+use cmdline::BootOptions;
+use msg::Verbosity;
+
+use config::runlevel_dir;
 use config::Config;
-use config::LdSrv;
-use config::LoadedServices;
 
-use service::ExecType;
-use service::Service;
+use client::NotRunning;
+use control::Command;
+use control::ControlServer;
+use control::Response;
 
-use consts::INIT_MASTER_CONF_FILE;
-use consts::LOADED_SERVICES_CONF_FILE;
+use state::ServiceRecord;
+use state::ServiceState;
+use supervisor::Supervisor;
+
+use paths::Paths;
 
 pub fn init_main() {
-    println!("Starting init...");
+    if std::env::args().skip(1).any(|arg| arg == "--simulate") {
+        let cli = cmd::Simulate::parse();
+        return plan_main(&Paths::from_cli(cli.root), cli.cmdline, cli.json);
+    }
+
+    guard::install_panic_hook();
+    guard::run(init_loop);
+}
 
-    let conf = parse_master_conf();
-    let final_rl = &conf.final_runlevel;
+fn init_loop() {
+    let paths = Paths::from_env();
+    let opts = BootOptions::read(&paths);
+    if opts.debug {
+        msg::set_verbosity(Verbosity::Debug);
+    } else if opts.quiet {
+        msg::set_verbosity(Verbosity::Quiet);
+    }
 
-    let mut login_shell = "/bin/ash".to_string();
-    let mut is_continue = true;
+    progress!("Starting init...\n");
 
-    let mut ld_services = LoadedServices { service: vec![] };
+    let mut supervisor = Supervisor::new(parse_master_conf(&paths), paths.clone());
+    supervisor.apply_boot_options(opts);
+    supervisor.boot();
 
-    for rl in &conf.runlevel {
-        if !rl.r#use.unwrap_or(true) {
-            println!("\nSkiping runlevel {}", &rl.dir);
-            continue;
+    let mut control = match ControlServer::bind(&paths) {
+        Ok(control) => Some(control),
+        Err(why) => {
+            eprintln!(
+                "init: failed to create {} ({why}), init can't be controlled!",
+                paths.control_socket().display()
+            );
+            None
+        }
+    };
+
+    let mut login_shell = run_login_shell(&supervisor.login_shell());
+
+    loop {
+        wait_events(control.as_ref(), &supervisor, 1000);
+        if let Some(control) = &mut control {
+            control.serve(&mut supervisor);
         }
+        supervisor.handle_devices();
 
-        if &rl.dir == final_rl {
-            if let Some(sh) = &rl.login_shell {
-                login_shell = sh.to_string();
+        supervisor.check_health();
+
+        // Init is the parent of all orphaned processes, so it has to reap
+        // them. The login shell is started again when it exits, exits of
+        // the main processes of services change their state.
+        while let Ok(status) = waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+            let Some(pid) = status.pid() else {
+                break;
+            };
+
+            if supervisor.reap(status) {
+                continue;
+            }
+
+            if login_shell
+                .as_ref()
+                .is_some_and(|sh| sh.id() == pid.as_raw() as u32)
+            {
+                login_shell = run_login_shell(&supervisor.login_shell());
             }
-            is_continue = false;
         }
+    }
+}
+
+/// Waits up to `timeout` milliseconds for connections and requests to the
+/// control socket or for uevents
+fn wait_events(control: Option<&ControlServer>, supervisor: &Supervisor, timeout: u16) {
+    let mut fds: Vec<PollFd> = control
+        .map(|control| control.fds())
+        .into_iter()
+        .flatten()
+        .chain(supervisor.devices_fd())
+        .map(|fd| PollFd::new(fd, PollFlags::POLLIN))
+        .collect();
+
+    if fds.is_empty() {
+        sleep(Duration::from_millis(timeout.into()));
+    } else {
+        let _ = poll(&mut fds, timeout);
+    }
+}
+
+pub fn telinit_main() {
+    let cli = cmd::Telinit::parse();
+    let paths = Paths::from_cli(cli.root);
+
+    let runlevel = runlevel_dir(&cli.runlevel);
+    if let Err(why) = client::request(&paths, Command::Runlevel { runlevel }) {
+        eprintln!("telinit: {why}");
+        std::process::exit(1);
+    }
+}
 
-        println!("\nSwitching to the {} runlevel...", &rl.dir);
-
-        if let Action::run_services = rl.action.unwrap_or_default() {
-            if let Some(services) = &rl.services {
-                for service in services {
-                    print!("  -> running {service} service...");
-                    let mut code = 0;
-
-                    match Service::new(service, &rl.dir) {
-                        Ok(service) => code = exec_service(service, ExecType::Start),
-                        Err(why) => println!("ERROR: {why}"),
-                    }
-
-                    ld_services.service.push(LdSrv {
-                        rl: rl.dir.clone(),
-                        name: service.to_string(),
-                        code,
-                    });
-                }
-            } else {
-                eprintln!("init: error: services not found!");
+pub fn service_main() {
+    let cli = cmd::Service::parse();
+    let paths = Paths::from_cli(cli.root);
+
+    let command = match cli.command {
+        Some(cmd::Commands::Start { service }) => Command::Start { service },
+        Some(cmd::Commands::Stop { service }) => Command::Stop { service },
+        Some(cmd::Commands::Restart { service, force }) => Command::Restart { service, force },
+        Some(cmd::Commands::Reload { service }) => Command::Reload { service },
+        Some(cmd::Commands::Status { service }) => Command::Status { service },
+        Some(cmd::Commands::List) => Command::List,
+        Some(cmd::Commands::Runlevel { runlevel }) => Command::Runlevel {
+            runlevel: runlevel_dir(&runlevel),
+        },
+        Some(cmd::Commands::Check) => return check_main(&paths),
+        Some(cmd::Commands::Plan { cmdline, json }) => return plan_main(&paths, cmdline, json),
+        Some(cmd::Commands::Graph { runlevel, format }) => {
+            return graph_main(&paths, runlevel.as_deref().map(runlevel_dir), format)
+        }
+        Some(cmd::Commands::Blame) => return blame_main(&paths),
+        Some(cmd::Commands::CriticalChain) => return critical_chain_main(&paths),
+        Some(cmd::Commands::Timeline { format }) => return timeline_main(&paths, format),
+        None => return,
+    };
+
+    match client::request(&paths, command) {
+        Ok(Response::Status { service }) => print_service_status(&service),
+        Ok(Response::List { runlevel, services }) => {
+            println!("Runlevel: {}", runlevel.as_deref().unwrap_or("none"));
+            for service in &services {
+                print_service(service);
             }
         }
+        Ok(_) => {}
+        Err(why) => {
+            eprintln!("service: {why}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Validates the configuration and exits with non-zero code on errors
+fn check_main(paths: &Paths) {
+    let report = check::check(paths);
+
+    for warning in &report.warnings {
+        eprintln!("{}: {warning}", "warning".bold().yellow());
+    }
+    for error in &report.errors {
+        eprintln!("{}: {error}", "error".bold().red());
+    }
+
+    if !report.is_ok() {
+        eprintln!(
+            "\n{} errors, {} warnings",
+            report.errors.len(),
+            report.warnings.len()
+        );
+        std::process::exit(1);
+    }
+    println!("Configuration is OK ({} warnings)", report.warnings.len());
+}
+
+/// Prints the steps of the boot without executing anything. Boot options
+/// are taken from `cmdline` or from `/proc/cmdline` of the root directory
+fn plan_main(paths: &Paths, cmdline: Option<String>, json: bool) {
+    let opts = match cmdline {
+        Some(cmdline) => BootOptions::parse(&cmdline),
+        None if paths.kernel_cmdline().exists() => BootOptions::read(paths),
+        None => BootOptions::default(),
+    };
+
+    // messages of the supervisor would be mixed with the plan
+    msg::set_verbosity(Verbosity::Quiet);
+
+    let plan = match plan::simulate(paths, opts) {
+        Ok(plan) => plan,
+        Err(why) => {
+            eprintln!("init: {why}");
+            std::process::exit(1);
+        }
+    };
+
+    if json {
+        match serde_json::to_string_pretty(&plan) {
+            Ok(json) => println!("{json}"),
+            Err(why) => {
+                eprintln!("init: {why}");
+                std::process::exit(1);
+            }
+        }
+    } else {
+        plan::print(&plan);
+    }
+}
+
+/// Prints the dependency graph of the services. The state of the services
+/// is taken from the last boot if init is running
+fn graph_main(paths: &Paths, runlevel: Option<String>, format: cmd::GraphFormat) {
+    let records = state::load(paths).unwrap_or_default();
+    let timings = boot::load(paths).unwrap_or_default();
+
+    let graph = match graph::Graph::build(paths, runlevel.as_deref(), &records, &timings) {
+        Ok(graph) => graph,
+        Err(why) => {
+            eprintln!("service: {why}");
+            std::process::exit(1);
+        }
+    };
+
+    match format {
+        cmd::GraphFormat::Dot => print!("{}", graph.to_dot()),
+        cmd::GraphFormat::Mermaid => print!("{}", graph.to_mermaid()),
+        cmd::GraphFormat::Json => match serde_json::to_string_pretty(&graph) {
+            Ok(json) => println!("{json}"),
+            Err(why) => {
+                eprintln!("service: {why}");
+                std::process::exit(1);
+            }
+        },
+    }
+}
+
+/// Prints the services sorted by the time of their start
+fn blame_main(paths: &Paths) {
+    let timings = load_boot_record(paths);
+
+    for timing in timings.blame() {
+        let time = timing.duration().map(boot::secs).unwrap_or_default();
+        let failed = match timing.ready {
+            Some(_) => "".normal(),
+            None => " (failed)".red(),
+        };
+        println!(
+            "{time:>10} {}/{}{failed}",
+            &timing.runlevel, &timing.service
+        );
+    }
+}
+
+/// Prints the longest chain of dependencies with the time each service
+/// has become ready at and the time it took to start
+fn critical_chain_main(paths: &Paths) {
+    let timings = load_boot_record(paths);
+    let records = state::load(paths).unwrap_or_default();
 
-        if !is_continue {
-            break;
+    let graph = match graph::Graph::build(paths, None, &records, &timings) {
+        Ok(graph) => graph,
+        Err(why) => {
+            eprintln!("service: {why}");
+            std::process::exit(1);
         }
+    };
+
+    println!(
+        "init started at {}, boot finished at {}",
+        boot::secs(timings.init_start),
+        timings.finished.map(boot::secs).as_deref().unwrap_or("-")
+    );
+
+    // the chain is printed from the last service like `systemd-analyze`
+    for (depth, name) in graph.critical_path.iter().rev().enumerate() {
+        let Some(timing) = timings.service(name) else {
+            continue;
+        };
+        let at = timing.ready.or(timing.failed).unwrap_or(timing.start);
+        let time = timing.duration().map(boot::secs).unwrap_or_default();
+
+        println!(
+            "{:indent$}{} @{} +{}",
+            "",
+            name.bold(),
+            boot::secs(at),
+            time.red(),
+            indent = depth * 2
+        );
     }
+}
 
-    if let Err(why) = ld_services.write(LOADED_SERVICES_CONF_FILE) {
-        eprintln!("init: failed to write boot log to the {LOADED_SERVICES_CONF_FILE} ({why})!");
+/// Prints the timeline of the last boot as an SVG image or an HTML page
+fn timeline_main(paths: &Paths, format: cmd::TimelineFormat) {
+    let timings = load_boot_record(paths);
+
+    match format {
+        cmd::TimelineFormat::Svg => print!("{}", timeline::to_svg(&timings)),
+        cmd::TimelineFormat::Html => print!("{}", timeline::to_html(&timings)),
     }
+}
 
-    println!("\nRunning login shell ({})...", &login_shell);
-    run_login_shell(&login_shell);
+fn load_boot_record(paths: &Paths) -> boot::BootRecord {
+    match boot::load(paths) {
+        Ok(timings) => timings,
+        Err(why) => {
+            eprintln!("service: {why}");
+            std::process::exit(1);
+        }
+    }
 }
 
 pub fn poweroff_main() {
+    let cli = cmd::Power::parse();
+
     println!("The system will now be shut down...\n");
 
     println!(
@@ -152,33 +441,33 @@ pub fn poweroff_main() {
             .blue()
     );
 
-    stop_services();
-
-    if let Err(why) = reboot(RebootMode::RB_POWER_OFF) {
-        eprintln!("Power off error: {why}");
-        loop {}
-    }
+    power(
+        &Paths::from_cli(cli.root),
+        cli.force,
+        RebootMode::RB_POWER_OFF,
+    );
 }
 
 pub fn reboot_main() {
-    println!("The system will now be reboot...");
+    let cli = cmd::Power::parse();
 
-    stop_services();
+    println!("The system will now be reboot...");
 
-    if let Err(why) = reboot(RebootMode::RB_AUTOBOOT) {
-        eprintln!("Reboot error: {why}");
-        loop {}
-    }
+    power(
+        &Paths::from_cli(cli.root),
+        cli.force,
+        RebootMode::RB_AUTOBOOT,
+    );
 }
 
 /*****************************************************************************
  *****************************************************************************/
 
-fn parse_master_conf() -> Config {
-    match Config::parse(INIT_MASTER_CONF_FILE) {
+fn parse_master_conf(paths: &Paths) -> Config {
+    match Config::parse(paths.master_conf()) {
         Ok(conf) => conf,
         Err(why) => {
-            eprintln!("init: {INIT_MASTER_CONF_FILE} parsing error: {why}");
+            eprintln!("init: parsing error: {why}");
             eprintln!("Using default configurations...");
 
             Config::default()
@@ -186,77 +475,88 @@ fn parse_master_conf() -> Config {
     }
 }
 
-fn parse_ld_srv_conf() -> LoadedServices {
-    match LoadedServices::parse(LOADED_SERVICES_CONF_FILE) {
-        Ok(srv) => srv,
-        Err(why) => {
-            eprintln!("init: {LOADED_SERVICES_CONF_FILE} parsing error: {why}");
-            eprintln!("Using default configurations...");
-
-            LoadedServices::default()
-        }
-    }
-}
-
-fn exec_service(service: Service, exec_type: ExecType) -> i32 {
-    match service.exec(exec_type) {
-        Ok(run) => {
-            if run == 0 {
-                println!("ok");
-            } else {
-                on_error(
-                    &service.init.on_error,
-                    format!("non-zero return code ({run})"),
-                );
+/// Asks init to stop all services and power off or reboot the system.
+/// If init is not running or `force = true`, does it by itself
+fn power(paths: &Paths, force: bool, mode: RebootMode) {
+    if !force {
+        let command = Command::Shutdown {
+            reboot: mode == RebootMode::RB_AUTOBOOT,
+        };
+        match client::request(paths, command) {
+            Ok(_) => return,
+            Err(why) if why.is::<NotRunning>() => {}
+            Err(why) => {
+                eprintln!("Shutdown error: {why}");
+                std::process::exit(1);
             }
-            run
-        }
-        Err(why) => {
-            on_error(&service.init.on_error, why);
-            1
         }
-    }
-}
 
-fn on_error<D: Display>(err: &Option<OnError>, err_txt: D) {
-    if let Some(err) = err {
-        match err {
-            OnError::ignore => println!("ERROR"),
-            OnError::error => {
-                eprintln!("ERROR: {err_txt}");
-            }
-            OnError::abort => {
-                eprintln!("ERROR: {err_txt}");
-                std::process::exit(1);
+        match state::load(paths) {
+            Ok(services) => {
+                eprintln!("init doesn't answer, stopping services...");
+                let mut supervisor = Supervisor::new(parse_master_conf(paths), paths.clone());
+                supervisor.services = services;
+                supervisor.stop_all();
             }
+            Err(why) => eprintln!("Warning: {why}"),
         }
-    } else {
-        on_error(&Some(OnError::default()), err_txt);
     }
+
+    sync();
+    let Err(why) = reboot(mode);
+    eprintln!("Shutdown error: {why}");
+    std::process::exit(1);
 }
 
-fn run_login_shell(lsh: &str) {
-    let _ = Command::new(lsh).status().unwrap();
+fn print_service(service: &ServiceRecord) {
+    let state = match service.state {
+        ServiceState::running => service.state.to_string().green(),
+        ServiceState::failed => service.state.to_string().red(),
+        ServiceState::unhealthy => service.state.to_string().yellow(),
+        ServiceState::skipped => service.state.to_string().dimmed(),
+        _ => service.state.to_string().normal(),
+    };
+    let pid = match service.pid {
+        Some(pid) => format!(", PID {pid}"),
+        None => String::new(),
+    };
+    println!("{}/{}: {state}{pid}", &service.rl, &service.name);
 }
 
-fn stop_services() {
-    let services = parse_ld_srv_conf();
-    let mut i = services.service.len() - 1;
+fn print_service_status(service: &ServiceRecord) {
+    print_service(service);
 
-    while i > 0 {
-        let service = &services.service[i];
+    if let Some(code) = service.last_exit {
+        println!("  last exit code: {code}");
+    }
+    if let Some(failure) = &service.failure {
+        println!("  last failure: {}", failure.red());
+    }
+    if service.failed_checks > 0 {
+        println!("  failed health checks: {}", service.failed_checks);
+    }
+    if service.restarts > 0 {
+        println!("  automatic restarts: {}", service.restarts);
+    }
 
-        if service.code == 0 {
-            print!("  -> stoping {}/{} service...", &service.rl, &service.name);
+    let now = state::now();
+    for transition in &service.transitions {
+        println!(
+            "  {:>10} {} s ago",
+            transition.state.to_string(),
+            now.saturating_sub(transition.at)
+        );
+    }
+}
 
-            match Service::new(&service.name, &service.rl) {
-                Ok(srv) => {
-                    let _ = exec_service(srv, ExecType::Stop);
-                }
-                Err(why) => println!("ERROR: {why}"),
-            }
-        }
+fn run_login_shell(lsh: &str) -> Option<Child> {
+    progress!("\nRunning login shell ({lsh})...\n");
 
-        i -= 1;
+    match std::process::Command::new(lsh).spawn() {
+        Ok(child) => Some(child),
+        Err(why) => {
+            eprintln!("init: failed to run login shell {lsh}: {why}");
+            None
+        }
     }
 }
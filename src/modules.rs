@@ -0,0 +1,423 @@
+//! Loading of kernel modules
+//!
+//! Before the first runlevel init loads the modules listed in
+//! `/etc/modules`, `/etc/modules-load.d/*.conf` (in lexical order) and the
+//! `[modules]` section. Each line contains the module name optionally
+//! followed by its parameters (`g_ether host_addr=02:00:00:00:00:01`);
+//! lines starting with `#` or `;` are comments.
+//!
+//! Modules are loaded with `finit_module(2)` after their dependencies
+//! found in `/lib/modules/$(uname -r)/modules.dep`, or with the `modprobe`
+//! command from the config. Loaded and built-in modules are skipped. Each
+//! module is reported as the `NAME.module` service of the `early`
+//! pseudo-runlevel.
+
+use anyhow::anyhow;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::fs;
+use std::fs::File;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+use nix::errno::Errno;
+use nix::kmod::finit_module;
+use nix::kmod::ModuleInitFlags;
+use nix::sys::utsname::uname;
+
+use crate::config::Modules;
+use crate::consts::MODULES_DIR;
+use crate::paths::Paths;
+use crate::service::split_args;
+
+/// `MODULE_INIT_COMPRESSED_FILE` from `<linux/module.h>`: the kernel
+/// decompresses the module itself
+const MODULE_INIT_COMPRESSED_FILE: u32 = 4;
+
+/// Module requested to be loaded
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub name: String,
+
+    /// Space-separated parameters (`key=value`)
+    pub params: String,
+}
+
+/// Dependencies of the modules of the running kernel
+#[derive(Debug, Default)]
+pub struct ModuleDb {
+    /// `/lib/modules/$(uname -r)`
+    dir: PathBuf,
+
+    /// Module name -> its file and the files of its dependencies in the
+    /// order of `modules.dep`
+    deps: HashMap<String, (String, Vec<String>)>,
+
+    /// Modules built into the kernel
+    builtin: HashSet<String>,
+
+    /// Patterns of the device aliases (`MODALIAS`) from `modules.alias`
+    /// and the modules handling them
+    aliases: Vec<(String, String)>,
+}
+
+/// Returns the module name for the file or the name given by the user:
+/// dashes are replaced with underscores
+/// (`kernel/drivers/spi/spi-dev.ko.xz` -> `spi_dev`)
+pub fn module_name(file: &str) -> String {
+    let name = file.rsplit('/').next().unwrap_or(file);
+    let name = name.split_once(".ko").map_or(name, |(name, _)| name);
+    name.replace('-', "_")
+}
+
+/// Matches the string against the shell-style pattern with `*`, `?` and
+/// `[...]` classes (`[!...]` negates the class)
+pub fn glob_match(pattern: &str, s: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+
+    // position after the last `*` in the pattern and in the string, used to
+    // backtrack when the rest doesn't match
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut i) = (0, 0);
+    while i < s.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, i));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(1),
+            Some('[') => match_class(&pattern[p..], s[i]),
+            Some(c) if *c == s[i] => Some(1),
+            _ => None,
+        };
+
+        match (step, star) {
+            (Some(len), _) => {
+                p += len;
+                i += 1;
+            }
+            (None, Some((star_p, star_i))) => {
+                p = star_p;
+                i = star_i + 1;
+                star = Some((star_p, star_i + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Matches the character against the `[...]` class at the start of the
+/// pattern. Returns the length of the class if it matches
+fn match_class(pattern: &[char], c: char) -> Option<usize> {
+    let end = pattern.iter().skip(2).position(|ch| *ch == ']')? + 2;
+    let (negate, class) = match pattern[1] {
+        '!' | '^' => (true, &pattern[2..end]),
+        _ => (false, &pattern[1..end]),
+    };
+
+    let mut found = false;
+    let mut idx = 0;
+    while idx < class.len() {
+        if idx + 2 < class.len() && class[idx + 1] == '-' {
+            found |= (class[idx]..=class[idx + 2]).contains(&c);
+            idx += 3;
+        } else {
+            found |= class[idx] == c;
+            idx += 1;
+        }
+    }
+
+    (found != negate).then_some(end + 1)
+}
+
+/// Parses the list of modules
+pub fn parse_list(content: &str) -> Vec<Request> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#') && !line.starts_with(';'))
+        .map(|line| {
+            let (name, params) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            Request {
+                name: name.to_string(),
+                params: params.trim().to_string(),
+            }
+        })
+        .collect()
+}
+
+/// Returns the modules to load: from `/etc/modules`, then from
+/// `/etc/modules-load.d/*.conf` and from the config. Duplicates are
+/// removed
+pub fn requested(paths: &Paths, conf: &Modules) -> Result<Vec<Request>> {
+    let mut files = vec![paths.modules()];
+    let mut conf_files: Vec<PathBuf> = fs::read_dir(paths.modules_load_dir())
+        .map(|dir| {
+            dir.flatten()
+                .map(|entry| entry.path())
+                .filter(|pth| pth.extension().is_some_and(|ext| ext == "conf"))
+                .collect()
+        })
+        .unwrap_or_default();
+    conf_files.sort();
+    files.extend(conf_files);
+
+    let mut requests = vec![];
+    for pth in files.iter().filter(|pth| pth.is_file()) {
+        let content = fs::read_to_string(pth).map_err(|why| anyhow!("{}: {why}", pth.display()))?;
+        requests.extend(parse_list(&content));
+    }
+    requests.extend(parse_list(
+        &conf.load.clone().unwrap_or_default().join("\n"),
+    ));
+
+    let mut seen = HashSet::new();
+    requests.retain(|req| seen.insert(module_name(&req.name)));
+
+    Ok(requests)
+}
+
+/// Returns the names of the loaded modules
+pub fn loaded(paths: &Paths) -> HashSet<String> {
+    fs::read_to_string(paths.proc_modules())
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .map(module_name)
+        .collect()
+}
+
+impl ModuleDb {
+    /// Reads `modules.dep` and `modules.builtin` of the running kernel
+    pub fn load(paths: &Paths) -> Result<Self> {
+        let release = uname()?.release().to_string_lossy().to_string();
+        let dir = Path::new(MODULES_DIR).join(release);
+
+        let dep_file = paths.resolve(dir.join("modules.dep"));
+        let deps = fs::read_to_string(&dep_file)
+            .map_err(|why| anyhow!("{}: {why}", dep_file.display()))?;
+        let builtin =
+            fs::read_to_string(paths.resolve(dir.join("modules.builtin"))).unwrap_or_default();
+        let aliases =
+            fs::read_to_string(paths.resolve(dir.join("modules.alias"))).unwrap_or_default();
+
+        Ok(Self::parse(dir, &deps, &builtin, &aliases))
+    }
+
+    /// Parses the `modules.dep` lines
+    /// (`kernel/a.ko: kernel/b.ko kernel/c.ko`), the `modules.builtin`
+    /// list of files and the `modules.alias` lines
+    /// (`alias usb:v067Bp2303d*dc*dsc*dp*ic*isc*ip*in* pl2303`)
+    fn parse(dir: PathBuf, deps: &str, builtin: &str, aliases: &str) -> Self {
+        let deps = deps
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .map(|(file, deps)| {
+                let deps = deps.split_whitespace().map(str::to_string).collect();
+                (module_name(file), (file.to_string(), deps))
+            })
+            .collect();
+        let builtin = builtin.lines().map(module_name).collect();
+        let aliases = aliases
+            .lines()
+            .filter_map(
+                |line| match line.split_whitespace().collect::<Vec<_>>()[..] {
+                    ["alias", pattern, module] => Some((pattern.to_string(), module_name(module))),
+                    _ => None,
+                },
+            )
+            .collect();
+
+        Self {
+            dir,
+            deps,
+            builtin,
+            aliases,
+        }
+    }
+
+    /// Returns the modules handling the device with the `modalias`
+    pub fn resolve_alias(&self, modalias: &str) -> Vec<String> {
+        let mut modules: Vec<String> = vec![];
+        for (pattern, module) in &self.aliases {
+            if glob_match(pattern, modalias) && !modules.contains(module) {
+                modules.push(module.clone());
+            }
+        }
+
+        modules
+    }
+
+    pub fn is_builtin(&self, name: &str) -> bool {
+        self.builtin.contains(&module_name(name))
+    }
+
+    /// Returns the files to load: the dependencies (the last one listed in
+    /// `modules.dep` is loaded first) and then the module itself
+    pub fn load_order(&self, name: &str) -> Result<Vec<PathBuf>> {
+        let (file, deps) = self
+            .deps
+            .get(&module_name(name))
+            .ok_or_else(|| anyhow!("module {name} not found in modules.dep"))?;
+
+        Ok(deps
+            .iter()
+            .rev()
+            .chain([file])
+            .map(|file| match file.starts_with('/') {
+                true => PathBuf::from(file),
+                false => self.dir.join(file),
+            })
+            .collect())
+    }
+}
+
+/// Loads the module and its dependencies that are not loaded yet
+pub fn load(paths: &Paths, db: &ModuleDb, name: &str, params: &str) -> Result<()> {
+    if db.is_builtin(name) {
+        return Ok(());
+    }
+
+    let loaded = loaded(paths);
+    let files = db.load_order(name)?;
+    let last = files.len() - 1;
+    for (idx, file) in files.iter().enumerate() {
+        if loaded.contains(&module_name(&file.to_string_lossy())) {
+            continue;
+        }
+        insmod(paths, file, if idx == last { params } else { "" })?;
+    }
+
+    Ok(())
+}
+
+/// Loads the module from the file. Modules that are already loaded are
+/// not an error
+pub fn insmod(paths: &Paths, file: &Path, params: &str) -> Result<()> {
+    let fd = File::open(paths.resolve(file)).map_err(|why| anyhow!("{}: {why}", file.display()))?;
+    let flags = match file.extension().is_some_and(|ext| ext != "ko") {
+        true => ModuleInitFlags::from_bits_retain(MODULE_INIT_COMPRESSED_FILE),
+        false => ModuleInitFlags::empty(),
+    };
+
+    match finit_module(&fd, &CString::new(params)?, flags) {
+        Ok(()) | Err(Errno::EEXIST) => Ok(()),
+        Err(why) => Err(anyhow!("failed to load {}: {why}", file.display())),
+    }
+}
+
+/// Returns the `modprobe` command line loading the module
+pub fn modprobe_command(modprobe: &str, req: &Request) -> Result<Vec<String>> {
+    let mut argv = split_args(modprobe)?;
+    argv.push(req.name.clone());
+    argv.extend(req.params.split_whitespace().map(str::to_string));
+
+    Ok(argv)
+}
+
+/// Runs the `modprobe` command
+pub fn modprobe(argv: &[String]) -> Result<()> {
+    let (prog, args) = argv
+        .split_first()
+        .ok_or_else(|| anyhow!("modprobe command is empty"))?;
+
+    let status = Command::new(prog)
+        .args(args)
+        .status()
+        .map_err(|why| anyhow!("failed to run {prog}: {why}"))?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(anyhow!("{} returned {status}", argv.join(" "))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_test() {
+        let requests = parse_list("# gadget\ng_ether host_addr=02:00:00:00:00:01\n\n; x\nspidev\n");
+        assert_eq!(
+            requests,
+            [
+                Request {
+                    name: "g_ether".to_string(),
+                    params: "host_addr=02:00:00:00:00:01".to_string(),
+                },
+                Request {
+                    name: "spidev".to_string(),
+                    params: String::new(),
+                },
+            ]
+        );
+
+        assert_eq!(module_name("kernel/drivers/spi/spi-dev.ko.xz"), "spi_dev");
+        assert_eq!(module_name("g-ether"), "g_ether");
+
+        assert!(glob_match("ttyUSB*", "ttyUSB0"));
+        assert!(glob_match("sd[a-c]?", "sdb1"));
+        assert!(!glob_match("sd[!a-c]?", "sdb1"));
+        assert!(glob_match("*a*b", "xaxxab"));
+        assert!(!glob_match("tty?", "tty10"));
+    }
+
+    #[test]
+    fn dep_test() {
+        let db = ModuleDb::parse(
+            PathBuf::from("/lib/modules/6.1.0"),
+            "kernel/drivers/usb/gadget/legacy/g_ether.ko: \
+             kernel/drivers/usb/gadget/function/u_ether.ko kernel/drivers/usb/gadget/libcomposite.ko\n\
+             kernel/drivers/usb/gadget/function/u_ether.ko: kernel/drivers/usb/gadget/libcomposite.ko\n\
+             kernel/drivers/usb/gadget/libcomposite.ko:\n\
+             kernel/drivers/spi/spidev.ko.xz:\n",
+            "kernel/drivers/usb/core/usbcore.ko\n",
+            "alias usb:v067Bp2303d*dc*dsc*dp*ic*isc*ip*in* pl2303\n\
+             alias usb:v*p*d*dc*dsc*dp*ic02isc02ip*in* cdc_acm\n\
+             alias of:N*T*Cspidev spidev\n",
+        );
+
+        let order: Vec<String> = db
+            .load_order("g-ether")
+            .unwrap()
+            .iter()
+            .map(|pth| pth.display().to_string())
+            .collect();
+        assert_eq!(
+            order,
+            [
+                "/lib/modules/6.1.0/kernel/drivers/usb/gadget/libcomposite.ko",
+                "/lib/modules/6.1.0/kernel/drivers/usb/gadget/function/u_ether.ko",
+                "/lib/modules/6.1.0/kernel/drivers/usb/gadget/legacy/g_ether.ko",
+            ]
+        );
+        assert_eq!(db.load_order("spidev").unwrap().len(), 1);
+        assert!(db.load_order("unknown").is_err());
+        assert!(db.is_builtin("usbcore"));
+        assert_eq!(
+            db.resolve_alias("usb:v067Bp2303d0300dc00dsc00dp00icFFisc00ip00in00"),
+            ["pl2303"]
+        );
+        assert_eq!(
+            db.resolve_alias("usb:v1546p01A7d0100dc02dsc00dp00ic02isc02ip01in00"),
+            ["cdc_acm"]
+        );
+        assert!(db.resolve_alias("pci:v00008086d00001502").is_empty());
+
+        let req = Request {
+            name: "spidev".to_string(),
+            params: "bufsiz=8192".to_string(),
+        };
+        assert_eq!(
+            modprobe_command("/sbin/modprobe -q", &req).unwrap(),
+            ["/sbin/modprobe", "-q", "spidev", "bufsiz=8192"]
+        );
+    }
+}
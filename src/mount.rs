@@ -0,0 +1,277 @@
+//! Mounting of filesystems by init itself
+//!
+//! The API filesystems (`/proc`, `/sys`, `/dev` etc.) are mounted with
+//! `mount(2)` before the first runlevel is entered, so the system gets them
+//! even if `/bin/mount` is broken. Targets that are already mounted (e.g.
+//! by the initramfs) are skipped.
+
+use anyhow::anyhow;
+use anyhow::Result;
+use std::fs;
+use std::os::unix::fs::DirBuilderExt;
+
+use nix::mount::MsFlags;
+
+use crate::config::MountPoint;
+use crate::paths::Paths;
+
+/// Pseudo-runlevel of the filesystems mounted by init
+pub const EARLY_RUNLEVEL: &str = "early";
+
+/// API filesystems mounted if `early_mount.mount` is not specified:
+/// source, target, type and options
+const DEFAULT_MOUNTS: [(&str, &str, &str, &str); 7] = [
+    ("proc", "/proc", "proc", "nosuid,nodev,noexec"),
+    ("sysfs", "/sys", "sysfs", "nosuid,nodev,noexec"),
+    ("devtmpfs", "/dev", "devtmpfs", "nosuid,mode=0755"),
+    (
+        "devpts",
+        "/dev/pts",
+        "devpts",
+        "nosuid,noexec,gid=5,mode=0620",
+    ),
+    ("tmpfs", "/dev/shm", "tmpfs", "nosuid,nodev,mode=1777"),
+    ("tmpfs", "/run", "tmpfs", "nosuid,nodev,mode=0755"),
+    ("tmpfs", "/tmp", "tmpfs", "nosuid,nodev,mode=1777"),
+];
+
+/// Mounted filesystem from `/proc/self/mountinfo`
+#[derive(Debug, Clone, PartialEq)]
+pub struct MountInfo {
+    pub source: String,
+    pub target: String,
+    pub fstype: String,
+    pub options: String,
+}
+
+/// Returns the filesystems mounted by default
+pub fn default_mounts() -> Vec<MountPoint> {
+    DEFAULT_MOUNTS
+        .iter()
+        .map(|(source, target, fstype, options)| MountPoint {
+            source: Some(source.to_string()),
+            target: target.to_string(),
+            fstype: fstype.to_string(),
+            options: Some(options.to_string()),
+        })
+        .collect()
+}
+
+/// Returns the name the mount is reported with (`/dev/shm` ->
+/// `dev-shm.mount`)
+pub fn unit_name(target: &str) -> String {
+    let name = target.trim_matches('/').replace('/', "-");
+    match name.is_empty() {
+        true => "-.mount".to_string(),
+        false => format!("{name}.mount"),
+    }
+}
+
+/// Reads the table of mounted filesystems. The table is empty if `/proc`
+/// is not mounted yet
+pub fn mountinfo(paths: &Paths) -> Result<Vec<MountInfo>> {
+    let pth = paths.mountinfo();
+    if !pth.exists() {
+        return Ok(vec![]);
+    }
+
+    let content = fs::read_to_string(&pth)?;
+    content
+        .lines()
+        .map(|line| {
+            parse_mountinfo(line).ok_or_else(|| anyhow!("{}: invalid line `{line}`", pth.display()))
+        })
+        .collect()
+}
+
+/// Parses the line of `/proc/self/mountinfo`:
+///
+/// ```text
+/// 36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw,errors=continue
+/// ```
+fn parse_mountinfo(line: &str) -> Option<MountInfo> {
+    let (mount, fs) = line.split_once(" - ")?;
+    let mut mount = mount.split_whitespace();
+    let mut fs = fs.split_whitespace();
+
+    let target = mount.nth(4)?;
+    let options = mount.next()?;
+    let fstype = fs.next()?;
+    let source = fs.next()?;
+
+    Some(MountInfo {
+        source: unescape(source),
+        target: unescape(target),
+        fstype: fstype.to_string(),
+        options: options.to_string(),
+    })
+}
+
+/// Decodes the octal escapes (`\040` - space) used by the kernel in the
+/// mount tables
+pub fn unescape(field: &str) -> String {
+    let mut res = String::with_capacity(field.len());
+    let mut rest = field;
+
+    while let Some(pos) = rest.find('\\') {
+        res.push_str(&rest[..pos]);
+        let code = rest
+            .get(pos + 1..pos + 4)
+            .and_then(|code| u8::from_str_radix(code, 8).ok());
+        match code {
+            Some(code) => {
+                res.push(code as char);
+                rest = &rest[pos + 4..];
+            }
+            None => {
+                res.push('\\');
+                rest = &rest[pos + 1..];
+            }
+        }
+    }
+    res.push_str(rest);
+
+    res
+}
+
+/// Splits the comma-separated mount options into the flags of `mount(2)`
+/// and the filesystem-specific data. `defaults` is ignored
+pub fn parse_options(options: &str) -> (MsFlags, String) {
+    let mut flags = MsFlags::empty();
+    let mut data = vec![];
+
+    for opt in options.split(',').filter(|opt| !opt.is_empty()) {
+        match opt {
+            "defaults" => {}
+            "ro" => flags.insert(MsFlags::MS_RDONLY),
+            "rw" => flags.remove(MsFlags::MS_RDONLY),
+            "nosuid" => flags.insert(MsFlags::MS_NOSUID),
+            "suid" => flags.remove(MsFlags::MS_NOSUID),
+            "nodev" => flags.insert(MsFlags::MS_NODEV),
+            "dev" => flags.remove(MsFlags::MS_NODEV),
+            "noexec" => flags.insert(MsFlags::MS_NOEXEC),
+            "exec" => flags.remove(MsFlags::MS_NOEXEC),
+            "sync" => flags.insert(MsFlags::MS_SYNCHRONOUS),
+            "async" => flags.remove(MsFlags::MS_SYNCHRONOUS),
+            "dirsync" => flags.insert(MsFlags::MS_DIRSYNC),
+            "noatime" => flags.insert(MsFlags::MS_NOATIME),
+            "atime" => flags.remove(MsFlags::MS_NOATIME),
+            "nodiratime" => flags.insert(MsFlags::MS_NODIRATIME),
+            "relatime" => flags.insert(MsFlags::MS_RELATIME),
+            "strictatime" => flags.insert(MsFlags::MS_STRICTATIME),
+            "lazytime" => flags.insert(MsFlags::MS_LAZYTIME),
+            "remount" => flags.insert(MsFlags::MS_REMOUNT),
+            "bind" => flags.insert(MsFlags::MS_BIND),
+            "rbind" => flags.insert(MsFlags::MS_BIND | MsFlags::MS_REC),
+            opt => data.push(opt),
+        }
+    }
+
+    (flags, data.join(","))
+}
+
+/// Mounts the filesystem, creating the target directory if needed
+pub fn mount(paths: &Paths, point: &MountPoint) -> Result<()> {
+    let target = paths.resolve(&point.target);
+    if !target.is_dir() {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o755)
+            .create(&target)?;
+    }
+
+    let source = point.source.as_deref().unwrap_or(&point.fstype);
+    let (flags, data) = parse_options(point.options.as_deref().unwrap_or_default());
+    let data = Some(data.as_str()).filter(|data| !data.is_empty());
+
+    nix::mount::mount(
+        Some(source),
+        &target,
+        Some(point.fstype.as_str()),
+        flags,
+        data,
+    )
+    .map_err(|why| anyhow!("failed to mount {}: {why}", &point.target))?;
+
+    // flags of a bind mount (e.g. `ro`) are applied only on remount
+    let bind_flags = MsFlags::MS_BIND | MsFlags::MS_REC;
+    if flags.contains(MsFlags::MS_BIND) && !(flags - bind_flags).is_empty() {
+        nix::mount::mount(
+            None::<&str>,
+            &target,
+            None::<&str>,
+            MsFlags::MS_REMOUNT | MsFlags::MS_BIND | (flags - bind_flags),
+            None::<&str>,
+        )
+        .map_err(|why| anyhow!("failed to remount {}: {why}", &point.target))?;
+    }
+
+    Ok(())
+}
+
+/// Remounts the mounted filesystem with the new `options` (e.g. `/`,
+/// mounted read-only by the kernel, with its options from `/etc/fstab`)
+pub fn remount(paths: &Paths, target: &str, options: &str) -> Result<()> {
+    let (flags, data) = parse_options(options);
+    let data = Some(data.as_str()).filter(|data| !data.is_empty());
+
+    nix::mount::mount(
+        None::<&str>,
+        &paths.resolve(target),
+        None::<&str>,
+        MsFlags::MS_REMOUNT | flags,
+        data,
+    )
+    .map_err(|why| anyhow!("failed to remount {target}: {why}"))
+}
+
+/// Unmounts the filesystem. If it is busy, it is remounted read-only so
+/// that no data is lost at power off
+pub fn unmount(paths: &Paths, target: &str) -> Result<()> {
+    let pth = paths.resolve(target);
+
+    if let Err(why) = nix::mount::umount(&pth) {
+        nix::mount::mount(
+            None::<&str>,
+            &pth,
+            None::<&str>,
+            MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
+            None::<&str>,
+        )
+        .map_err(|_| anyhow!("failed to unmount {target}: {why}"))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mountinfo_test() {
+        let info = parse_mountinfo(
+            "36 35 98:0 /mnt1 /mnt\\040data rw,noatime master:1 - ext4 /dev/sda1 rw,errors=continue",
+        )
+        .unwrap();
+        assert_eq!(info.target, "/mnt data");
+        assert_eq!(info.source, "/dev/sda1");
+        assert_eq!(info.fstype, "ext4");
+        assert_eq!(info.options, "rw,noatime");
+        assert!(parse_mountinfo("garbage").is_none());
+
+        assert_eq!(unit_name("/dev/shm"), "dev-shm.mount");
+        assert_eq!(unit_name("/"), "-.mount");
+    }
+
+    #[test]
+    fn options_test() {
+        let (flags, data) = parse_options("defaults,nosuid,nodev,mode=1777,size=10%");
+        assert_eq!(flags, MsFlags::MS_NOSUID | MsFlags::MS_NODEV);
+        assert_eq!(data, "mode=1777,size=10%");
+
+        let (flags, data) = parse_options("ro,noexec,rw");
+        assert_eq!(flags, MsFlags::MS_NOEXEC);
+        assert!(data.is_empty());
+    }
+}
@@ -2,6 +2,60 @@
 
 use std::env::args;
 use std::fmt::Display;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::atomic::AtomicU8;
+use std::sync::atomic::Ordering;
+
+use crate::paths::Paths;
+
+/// Amount of messages printed by the program
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Verbosity {
+    /// Only warnings and errors
+    Quiet = 0,
+    Normal = 1,
+    /// All messages including `MsgType::Debug`
+    Debug = 2,
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+/// Sets the amount of messages printed by the program
+pub fn set_verbosity(verbosity: Verbosity) {
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+}
+
+/// Returns the amount of messages printed by the program
+pub fn verbosity() -> Verbosity {
+    match VERBOSITY.load(Ordering::Relaxed) {
+        0 => Verbosity::Quiet,
+        1 => Verbosity::Normal,
+        _ => Verbosity::Debug,
+    }
+}
+
+/// Writes the message to the kernel log buffer (`/dev/kmsg`) with the
+/// specified syslog `level` (`0` - emergency ... `7` - debug)
+pub fn kmsg<M: Display>(level: u8, msg: M) {
+    if let Ok(mut kmsg) = OpenOptions::new()
+        .write(true)
+        .open(Paths::from_env().kmsg())
+    {
+        let _ = writeln!(kmsg, "<{level}>init: {msg}");
+    }
+}
+
+/// Prints a progress message to stdout unless the verbosity is
+/// `Verbosity::Quiet`. Takes the same arguments as `print!`
+#[macro_export]
+macro_rules! progress {
+    ($($arg:tt)*) => {
+        if $crate::msg::verbosity() > $crate::msg::Verbosity::Quiet {
+            print!($($arg)*);
+        }
+    };
+}
 
 pub struct Msg {
     indent: usize,
@@ -42,6 +96,12 @@ impl Msg {
 
     /// Output a message to the terminal
     pub fn print<M: Display>(&self, msg: M) {
+        match self.msg_type {
+            MsgType::Debug if verbosity() < Verbosity::Debug => return,
+            MsgType::Info if verbosity() == Verbosity::Quiet => return,
+            _ => {}
+        }
+
         let msg_type = match self.msg_type {
             MsgType::Debug => "DEBUG:",
             MsgType::Info => "", // для info нам не нужно выводить никаких типов
@@ -59,7 +119,9 @@ impl Msg {
             ),
             MsgType::Emerg => {
                 eprintln!("{prefix}: {msg_type} {msg}", prefix = &self.prefix);
-                loop {}
+                loop {
+                    std::thread::park();
+                }
             }
             _ => eprintln!(
                 "{:>indent$}{prefix}: {msg_type} {msg}",
@@ -0,0 +1,288 @@
+//! Paths of the files used by init and its tools
+//!
+//! All paths from `consts` are resolved inside the root directory (`/` by
+//! default). This way the tools can work with the rootfs of an image on
+//! the build host, and the whole boot sequence can be run against a
+//! temporary directory tree in tests. The root directory is taken from
+//! the `--root` option or the `LFA_INIT_ROOT` environment variable, or
+//! set with `Paths::with_root()`.
+
+use std::env;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::consts::BOOT_RECORD_FILE;
+use crate::consts::CONF_DIR;
+use crate::consts::CONTAINERENV_FILE;
+use crate::consts::CONTROL_SOCKET;
+use crate::consts::CPUINFO_FILE;
+use crate::consts::DEVICES_CONF_FILE;
+use crate::consts::DISK_DIR;
+use crate::consts::DMI_DIR;
+use crate::consts::DOCKERENV_FILE;
+use crate::consts::DT_COMPATIBLE_FILE;
+use crate::consts::DT_HYPERVISOR_FILE;
+use crate::consts::FSTAB_FILE;
+use crate::consts::GROUP_FILE;
+use crate::consts::HOSTNAME_FILE;
+use crate::consts::HOTPLUG_FILE;
+use crate::consts::HYPERVISOR_FILE;
+use crate::consts::INIT_MASTER_CONF_FILE;
+use crate::consts::KERNEL_CMDLINE_FILE;
+use crate::consts::KMSG_FILE;
+use crate::consts::MEMINFO_FILE;
+use crate::consts::MODULES_FILE;
+use crate::consts::MODULES_LOAD_DIR;
+use crate::consts::MOUNTINFO_FILE;
+use crate::consts::NOTIFY_DIR;
+use crate::consts::PASSWD_FILE;
+use crate::consts::PROC_MODULES_FILE;
+use crate::consts::PROC_SYS_DIR;
+use crate::consts::SHADOW_FILE;
+use crate::consts::STATE_DIR;
+use crate::consts::SWAPS_FILE;
+use crate::consts::SYSCTL_DIR;
+use crate::consts::SYSCTL_FILE;
+use crate::consts::SYS_BLOCK_DIR;
+use crate::consts::SYS_DEVICES_DIR;
+use crate::consts::SYS_NET_DIR;
+use crate::consts::TMPFILES_DIR;
+use crate::consts::UUID_FILE;
+use crate::consts::ZRAM_DEVICE;
+use crate::consts::ZRAM_SYS_DIR;
+
+/// Environment variable with the root directory
+pub const ROOT_ENV: &str = "LFA_INIT_ROOT";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Paths {
+    root: PathBuf,
+}
+
+impl Default for Paths {
+    fn default() -> Self {
+        Self::with_root("/")
+    }
+}
+
+impl Paths {
+    /// Resolves all paths inside the `root` directory
+    pub fn with_root<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// Takes the root directory from the `LFA_INIT_ROOT` environment
+    /// variable. Uses `/` if it is not set
+    pub fn from_env() -> Self {
+        match env::var_os(ROOT_ENV) {
+            Some(root) if !root.is_empty() => Self::with_root(root),
+            _ => Self::default(),
+        }
+    }
+
+    /// Uses the root directory passed with the `--root` option or
+    /// falls back to `Paths::from_env()`
+    pub fn from_cli(root: Option<PathBuf>) -> Self {
+        match root {
+            Some(root) => Self::with_root(root),
+            None => Self::from_env(),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Returns the location of the absolute `pth` inside the root directory
+    pub fn resolve<P: AsRef<Path>>(&self, pth: P) -> PathBuf {
+        let pth = pth.as_ref();
+        self.root.join(pth.strip_prefix("/").unwrap_or(pth))
+    }
+
+    /// Directory with the configuration of init and services
+    pub fn conf_dir(&self) -> PathBuf {
+        self.resolve(CONF_DIR)
+    }
+
+    /// Master config file (`init.toml`)
+    pub fn master_conf(&self) -> PathBuf {
+        self.resolve(INIT_MASTER_CONF_FILE)
+    }
+
+    /// File of the `name` service from the `rl` runlevel
+    pub fn service_file(&self, rl: &str, name: &str) -> PathBuf {
+        self.conf_dir().join(rl).join(format!("{name}.toml"))
+    }
+
+    pub fn state_dir(&self) -> PathBuf {
+        self.resolve(STATE_DIR)
+    }
+
+    pub fn boot_record(&self) -> PathBuf {
+        self.resolve(BOOT_RECORD_FILE)
+    }
+
+    pub fn control_socket(&self) -> PathBuf {
+        self.resolve(CONTROL_SOCKET)
+    }
+
+    pub fn notify_dir(&self) -> PathBuf {
+        self.resolve(NOTIFY_DIR)
+    }
+
+    pub fn kernel_cmdline(&self) -> PathBuf {
+        self.resolve(KERNEL_CMDLINE_FILE)
+    }
+
+    pub fn shadow(&self) -> PathBuf {
+        self.resolve(SHADOW_FILE)
+    }
+
+    pub fn kmsg(&self) -> PathBuf {
+        self.resolve(KMSG_FILE)
+    }
+
+    pub fn tmpfiles_dir(&self) -> PathBuf {
+        self.resolve(TMPFILES_DIR)
+    }
+
+    pub fn devices_conf(&self) -> PathBuf {
+        self.resolve(DEVICES_CONF_FILE)
+    }
+
+    pub fn passwd(&self) -> PathBuf {
+        self.resolve(PASSWD_FILE)
+    }
+
+    pub fn group(&self) -> PathBuf {
+        self.resolve(GROUP_FILE)
+    }
+
+    pub fn mountinfo(&self) -> PathBuf {
+        self.resolve(MOUNTINFO_FILE)
+    }
+
+    pub fn fstab(&self) -> PathBuf {
+        self.resolve(FSTAB_FILE)
+    }
+
+    pub fn disk_dir(&self) -> PathBuf {
+        self.resolve(DISK_DIR)
+    }
+
+    pub fn sys_block_dir(&self) -> PathBuf {
+        self.resolve(SYS_BLOCK_DIR)
+    }
+
+    pub fn swaps(&self) -> PathBuf {
+        self.resolve(SWAPS_FILE)
+    }
+
+    pub fn meminfo(&self) -> PathBuf {
+        self.resolve(MEMINFO_FILE)
+    }
+
+    pub fn random_uuid(&self) -> PathBuf {
+        self.resolve(UUID_FILE)
+    }
+
+    pub fn zram_device(&self) -> PathBuf {
+        self.resolve(ZRAM_DEVICE)
+    }
+
+    pub fn zram_sys_dir(&self) -> PathBuf {
+        self.resolve(ZRAM_SYS_DIR)
+    }
+
+    pub fn hostname(&self) -> PathBuf {
+        self.resolve(HOSTNAME_FILE)
+    }
+
+    pub fn sysctl_conf(&self) -> PathBuf {
+        self.resolve(SYSCTL_FILE)
+    }
+
+    pub fn sysctl_dir(&self) -> PathBuf {
+        self.resolve(SYSCTL_DIR)
+    }
+
+    pub fn proc_sys_dir(&self) -> PathBuf {
+        self.resolve(PROC_SYS_DIR)
+    }
+
+    pub fn sys_net_dir(&self) -> PathBuf {
+        self.resolve(SYS_NET_DIR)
+    }
+
+    pub fn modules(&self) -> PathBuf {
+        self.resolve(MODULES_FILE)
+    }
+
+    pub fn modules_load_dir(&self) -> PathBuf {
+        self.resolve(MODULES_LOAD_DIR)
+    }
+
+    pub fn proc_modules(&self) -> PathBuf {
+        self.resolve(PROC_MODULES_FILE)
+    }
+
+    pub fn sys_devices_dir(&self) -> PathBuf {
+        self.resolve(SYS_DEVICES_DIR)
+    }
+
+    pub fn hotplug(&self) -> PathBuf {
+        self.resolve(HOTPLUG_FILE)
+    }
+
+    pub fn dt_compatible(&self) -> PathBuf {
+        self.resolve(DT_COMPATIBLE_FILE)
+    }
+
+    pub fn dt_hypervisor(&self) -> PathBuf {
+        self.resolve(DT_HYPERVISOR_FILE)
+    }
+
+    pub fn hypervisor_type(&self) -> PathBuf {
+        self.resolve(HYPERVISOR_FILE)
+    }
+
+    pub fn dmi_dir(&self) -> PathBuf {
+        self.resolve(DMI_DIR)
+    }
+
+    pub fn cpuinfo(&self) -> PathBuf {
+        self.resolve(CPUINFO_FILE)
+    }
+
+    pub fn dockerenv(&self) -> PathBuf {
+        self.resolve(DOCKERENV_FILE)
+    }
+
+    pub fn containerenv(&self) -> PathBuf {
+        self.resolve(CONTAINERENV_FILE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_test() {
+        assert_eq!(
+            Paths::default().master_conf(),
+            Path::new("/etc/init/init.toml")
+        );
+
+        let paths = Paths::with_root("/tmp/rootfs");
+        assert_eq!(
+            paths.service_file("rl1", "mount_procfs"),
+            Path::new("/tmp/rootfs/etc/init/rl1/mount_procfs.toml")
+        );
+        assert_eq!(paths.state_dir(), Path::new("/tmp/rootfs/run/init/state"));
+        assert_eq!(
+            Paths::from_cli(Some("/mnt".into())).control_socket(),
+            Path::new("/mnt/run/init.sock")
+        );
+    }
+}
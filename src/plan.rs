@@ -0,0 +1,602 @@
+//! Boot simulation
+//!
+//! Runs the supervisor in the dry-run mode: runlevels, dependencies and
+//! kernel command line options are resolved exactly like at boot, but
+//! instead of executing anything the supervisor records the steps it
+//! would perform. Used by `init --simulate` and `service plan` to see
+//! what init would do with a rootfs before flashing it.
+
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use crate::cmdline::BootOptions;
+use crate::config::Action;
+use crate::config::Config;
+use crate::paths::Paths;
+use crate::ready::DEFAULT_READY_TIMEOUT;
+use crate::service::split_args;
+use crate::service::Builtin;
+use crate::service::PathSection;
+use crate::service::PathType;
+use crate::service::Ready;
+use crate::service::Service;
+use crate::supervisor::Supervisor;
+use crate::tmpfiles;
+use crate::traits::TomlConfig;
+
+/// User the commands of services are run as
+const USER: &str = "root";
+
+/// What init would do at boot
+#[derive(Debug, Serialize)]
+pub struct Plan {
+    /// Root directory of the simulated system
+    pub root: PathBuf,
+
+    /// Runlevel to boot into (with kernel command line overrides applied)
+    pub final_runlevel: String,
+
+    /// Steps in the order of their execution
+    pub steps: Vec<Step>,
+}
+
+/// Single action of init
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(tag = "step", rename_all = "snake_case")]
+pub enum Step {
+    /// Mounting the filesystem by init itself
+    Mount {
+        source: String,
+        target: String,
+        fstype: String,
+        options: String,
+    },
+
+    /// Checking the filesystem before mounting it
+    Fsck { target: String, argv: Vec<String> },
+
+    /// Remounting the mounted filesystem with new options (`/` is also
+    /// remounted read-only at shutdown)
+    Remount { target: String, options: String },
+
+    /// Unmounting the filesystem at shutdown
+    Unmount { target: String },
+
+    /// Loading the kernel module from the file
+    Insmod {
+        module: String,
+        file: String,
+        params: String,
+    },
+
+    /// Loading the kernel module with the `modprobe` command
+    Modprobe { module: String, argv: Vec<String> },
+
+    /// Creating the file, directory, symlink or device node
+    Path { entry: PathSection },
+
+    /// Setting up the zram device
+    Zram {
+        device: String,
+        size: String,
+        algorithm: Option<String>,
+    },
+
+    /// Creating the swap file
+    Mkswap { path: String, size: String },
+
+    /// Activating the swap area
+    Swapon {
+        source: String,
+        priority: Option<i32>,
+    },
+
+    /// Deactivating the swap area at shutdown
+    Swapoff { source: String },
+
+    /// Switching to the runlevel
+    Runlevel { runlevel: String, action: Action },
+
+    /// Starting the service
+    Start {
+        runlevel: String,
+        service: String,
+        ready: Ready,
+        commands: Vec<PlannedCommand>,
+
+        /// Task performed by init instead of the commands
+        #[serde(skip_serializing_if = "Option::is_none")]
+        builtin: Option<Builtin>,
+    },
+
+    /// Stopping the service with its stop commands or by sending
+    /// `SIGTERM` to its main process
+    Stop {
+        runlevel: String,
+        service: String,
+        commands: Vec<PlannedCommand>,
+        signal: Option<String>,
+    },
+
+    /// The service is not started
+    Skip {
+        runlevel: String,
+        service: String,
+        reason: String,
+    },
+
+    /// The service can't be started
+    Fail {
+        runlevel: String,
+        service: String,
+        reason: String,
+    },
+
+    /// Entering the emergency mode
+    Emergency { reason: String },
+
+    /// Replacing init with the program
+    Exec { argv: Vec<String> },
+
+    /// Running the shell and waiting for it to exit
+    Shell { shell: String },
+
+    /// Powering off or rebooting the system
+    Shutdown { reboot: bool },
+
+    /// Running the login shell after boot
+    LoginShell { shell: String },
+}
+
+/// Command that would be executed
+#[derive(Debug, Serialize, PartialEq)]
+pub struct PlannedCommand {
+    pub argv: Vec<String>,
+
+    /// Variables added to the environment of init
+    pub env: BTreeMap<String, String>,
+
+    pub user: String,
+
+    /// Time in seconds to wait for the service to become ready. Commands
+    /// without a timeout are waited for until they exit
+    pub timeout: Option<u64>,
+}
+
+/// Simulates the boot of the system in the root directory with the
+/// `opts` from the kernel command line
+pub fn simulate(paths: &Paths, opts: BootOptions) -> Result<Plan> {
+    let conf = Config::parse(paths.master_conf())?;
+
+    let mut sv = Supervisor::new(conf, paths.clone()).dry_run();
+    sv.apply_boot_options(opts);
+    sv.boot();
+
+    let shell = sv.login_shell();
+    let mut steps = sv.take_plan();
+    steps.push(Step::LoginShell { shell });
+
+    Ok(Plan {
+        root: paths.root().to_path_buf(),
+        final_runlevel: sv.conf.final_runlevel,
+        steps,
+    })
+}
+
+/// Returns the commands that start the service
+pub fn start_commands(paths: &Paths, service: &Service, name: &str) -> Result<Vec<PlannedCommand>> {
+    // the start commands of builtin tasks are not executed
+    if service.service.builtin.is_some() {
+        return Ok(vec![]);
+    }
+
+    let ready = service.service.ready.clone().unwrap_or_default();
+    let cmds = service.service.start.as_deref().unwrap_or_default();
+
+    let mut commands = planned_commands(cmds)?;
+
+    // the last command is the main process unless the service is ready
+    // when all commands have exited
+    if let Some(main) = commands.last_mut().filter(|_| ready != Ready::Exit) {
+        main.timeout = Some(
+            service
+                .service
+                .ready_timeout
+                .unwrap_or(DEFAULT_READY_TIMEOUT),
+        );
+
+        if ready == Ready::Notify {
+            let sock = paths.notify_dir().join(format!("{name}.sock"));
+            main.env
+                .insert("NOTIFY_SOCKET".to_string(), sock.display().to_string());
+        }
+    }
+
+    Ok(commands)
+}
+
+/// Returns the `cmds` as they would be executed
+pub fn planned_commands(cmds: &[String]) -> Result<Vec<PlannedCommand>> {
+    cmds.iter()
+        .map(|cmd| {
+            Ok(PlannedCommand {
+                argv: split_args(cmd)?,
+                env: BTreeMap::new(),
+                user: USER.to_string(),
+                timeout: None,
+            })
+        })
+        .collect()
+}
+
+/// Prints the plan in human-readable form
+pub fn print(plan: &Plan) {
+    println!(
+        "Boot plan for {} (final runlevel: {})",
+        plan.root.display(),
+        &plan.final_runlevel
+    );
+
+    for step in &plan.steps {
+        match step {
+            Step::Mount {
+                source,
+                target,
+                fstype,
+                options,
+            } => println!("  mount -t {fstype} -o {options} {source} {target}"),
+            Step::Fsck { argv, .. } => println!("  {}", quote_argv(argv)),
+            Step::Remount { target, options } => {
+                println!("  mount -o remount,{options} {target}")
+            }
+            Step::Unmount { target } => println!("  umount {target}"),
+            Step::Insmod { file, params, .. } => match params.is_empty() {
+                true => println!("  insmod {file}"),
+                false => println!("  insmod {file} {params}"),
+            },
+            Step::Modprobe { argv, .. } => println!("  {}", quote_argv(argv)),
+            Step::Path { entry } => println!("  {}", path_commands(entry).join(" && ")),
+            Step::Zram {
+                device,
+                size,
+                algorithm,
+            } => match algorithm {
+                Some(algorithm) => {
+                    println!("  zramctl --size {size} --algorithm {algorithm} {device}")
+                }
+                None => println!("  zramctl --size {size} {device}"),
+            },
+            Step::Mkswap { path, size } => println!("  mkswap --file --size {size} {path}"),
+            Step::Swapon { source, priority } => match priority {
+                Some(priority) => println!("  swapon -p {priority} {source}"),
+                None => println!("  swapon {source}"),
+            },
+            Step::Swapoff { source } => println!("  swapoff {source}"),
+            Step::Runlevel { runlevel, action } => {
+                println!("\n{runlevel}: {}", action_name(*action));
+            }
+            Step::Start {
+                service,
+                builtin: Some(builtin),
+                ..
+            } => println!("  start {service} (builtin: {builtin:?})"),
+            Step::Start {
+                service,
+                ready,
+                commands,
+                ..
+            } => {
+                println!("  start {service} (ready: {})", ready_name(ready));
+                print_commands(commands);
+            }
+            Step::Stop {
+                service,
+                commands,
+                signal,
+                ..
+            } => {
+                println!("  stop {service}");
+                print_commands(commands);
+                if let Some(sig) = signal {
+                    println!("    send {sig} to the main process");
+                }
+            }
+            Step::Skip {
+                service, reason, ..
+            } => println!("  skip {service}: {reason}"),
+            Step::Fail {
+                service, reason, ..
+            } => println!("  FAIL {service}: {reason}"),
+            Step::Emergency { reason } => println!("  EMERGENCY MODE: {reason}"),
+            Step::Exec { argv } => println!("  exec {}", quote_argv(argv)),
+            Step::Shell { shell } => println!("  run shell {shell}"),
+            Step::Shutdown { reboot } => match reboot {
+                true => println!("  reboot the system"),
+                false => println!("  power off the system"),
+            },
+            Step::LoginShell { shell } => println!("\nlogin shell: {shell}"),
+        }
+    }
+}
+
+fn print_commands(commands: &[PlannedCommand]) {
+    for cmd in commands {
+        let env: String = cmd
+            .env
+            .iter()
+            .map(|(key, val)| format!("{key}={} ", quote(val)))
+            .collect();
+        let timeout = match cmd.timeout {
+            Some(timeout) => format!(" (timeout {timeout} s)"),
+            None => String::new(),
+        };
+
+        println!("    {}$ {env}{}{timeout}", &cmd.user, quote_argv(&cmd.argv));
+    }
+}
+
+fn action_name(action: Action) -> &'static str {
+    match action {
+        Action::run_services => "run services",
+        Action::power_off => "power off",
+        Action::reboot => "reboot",
+        Action::exec => "exec",
+        Action::shell => "shell",
+    }
+}
+
+fn ready_name(ready: &Ready) -> String {
+    match ready {
+        Ready::Exit => "exit".to_string(),
+        Ready::Pidfile => "pidfile".to_string(),
+        Ready::Notify => "notify".to_string(),
+        Ready::Fd(fd) => format!("fd:{fd}"),
+        Ready::Socket(path) => format!("socket {path}"),
+    }
+}
+
+/// Returns the shell commands equivalent to applying the `tmpfiles` entry
+fn path_commands(entry: &PathSection) -> Vec<String> {
+    let path = &entry.path;
+    let argument = entry.argument.clone().unwrap_or_default();
+
+    let mut commands = vec![match entry.kind {
+        PathType::directory => format!("mkdir -p {path}"),
+        PathType::file => match argument.is_empty() {
+            true => format!("touch {path}"),
+            false => format!("echo -n {} > {path}", quote_argv(&[argument])),
+        },
+        PathType::symlink => format!("ln -s {argument} {path}"),
+        PathType::char => format!("mknod {path} c {}", argument.replace(':', " ")),
+        PathType::block => format!("mknod {path} b {}", argument.replace(':', " ")),
+        PathType::clean => format!("test -d {path}"),
+    }];
+    if entry.kind == PathType::symlink {
+        return commands;
+    }
+
+    if let Some(mode) = &entry.mode {
+        commands.push(format!("chmod {mode} {path}"));
+    }
+    match (&entry.user, &entry.group) {
+        (Some(user), Some(group)) => commands.push(format!("chown {user}:{group} {path}")),
+        (Some(user), None) => commands.push(format!("chown {user} {path}")),
+        (None, Some(group)) => commands.push(format!("chgrp {group} {path}")),
+        (None, None) => {}
+    }
+    if let Ok(age) = tmpfiles::parse_age(entry.age.as_deref().unwrap_or_default()) {
+        let mins = age.as_secs() / 60;
+        commands.push(format!(
+            "find {path} -mindepth 1 -amin +{mins} -mmin +{mins} -delete"
+        ));
+    }
+
+    commands
+}
+
+fn quote_argv(argv: &[String]) -> String {
+    argv.iter()
+        .map(|arg| quote(arg))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Quotes the argument so that `split_args()` returns it unchanged
+fn quote(arg: &str) -> String {
+    let is_plain = !arg.is_empty()
+        && !arg
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '\'' | '"' | '\\'));
+
+    match is_plain {
+        true => arg.to_string(),
+        false => format!("'{}'", arg.replace('\'', r#"'"'"'"#)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_root;
+    use crate::testutil::write;
+    use std::fs;
+
+    #[test]
+    fn simulate_test() {
+        let root = temp_root("plan");
+        let paths = Paths::with_root(&root);
+
+        write(
+            &paths,
+            "/etc/init/init.toml",
+            r#"
+final_runlevel = "rl2"
+
+[[early_mount.mount]]
+target = "/proc"
+fstype = "proc"
+options = "nosuid,nodev,noexec"
+
+[fsck]
+args = ["-p"]
+
+[swap]
+
+[[runlevel]]
+dir = "rl1"
+description = "Single user mode"
+services = ["mount", "debug"]
+
+[[runlevel]]
+dir = "rl2"
+description = "Multi user mode"
+services = ["daemon", "netplugd"]
+"#,
+        );
+        write(
+            &paths,
+            "/etc/fstab",
+            "/dev/sda1 / ext4 defaults 1 1\n\
+             /dev/sda2 /home ext4 defaults 1 2\n\
+             /dev/sdb1 /mnt/usb vfat noauto 0 0\n\
+             /dev/sda3 none swap pri=5 0 0\n",
+        );
+        write(
+            &paths,
+            "/etc/init/rl1/mount.toml",
+            "[service]\nstart = [\"/bin/mount -t proc proc /proc\"]\n\n[init]\ndescription = \"mount\"\n",
+        );
+        write(
+            &paths,
+            "/etc/init/rl1/debug.toml",
+            "[service]\nstart = [\"/bin/true\"]\n\n[init]\ndescription = \"debug\"\n",
+        );
+        write(
+            &paths,
+            "/etc/init/rl2/daemon.toml",
+            "[service]\nstart = [\"/sbin/daemon -c 'a b'\"]\nready = \"notify\"\nready_timeout = 10\n\n\
+             [init]\ndescription = \"daemon\"\nrequires = [\"mount\"]\n\n\
+             [[paths]]\npath = \"/run/daemon\"\ntype = \"directory\"\nmode = \"0750\"\n",
+        );
+        write(
+            &paths,
+            "/etc/init/rl2/netplugd.toml",
+            "[service]\nstart = [\"/sbin/netplugd\"]\n\n[init]\ndescription = \"netplugd\"\n\n\
+             [conditions]\npath_exists = [\"/sys/class/net/eth0\"]\n",
+        );
+        write(
+            &paths,
+            "/etc/init/tmpfiles.d/base.conf",
+            "d /run/lock 1777 - - -\n",
+        );
+
+        let opts = BootOptions::parse("init.skip=debug");
+        let plan = simulate(&paths, opts).unwrap();
+        let steps: Vec<String> = plan
+            .steps
+            .iter()
+            .map(|step| match step {
+                Step::Mount { target, .. } => format!("mount {target}"),
+                Step::Fsck { target, .. } => format!("fsck {target}"),
+                Step::Remount { target, .. } => format!("remount {target}"),
+                Step::Swapon { source, .. } => format!("swapon {source}"),
+                Step::Path { entry } => format!("path {}", &entry.path),
+                Step::Runlevel { runlevel, .. } => format!("runlevel {runlevel}"),
+                Step::Start { service, .. } => format!("start {service}"),
+                Step::Skip { service, .. } => format!("skip {service}"),
+                Step::LoginShell { shell } => format!("login {shell}"),
+                step => panic!("unexpected step: {step:?}"),
+            })
+            .collect();
+        assert_eq!(
+            steps,
+            [
+                "mount /proc",
+                "fsck /",
+                "remount /",
+                "fsck /home",
+                "mount /home",
+                "swapon /dev/sda3",
+                "path /run/lock",
+                "runlevel rl1",
+                "start mount",
+                "skip debug",
+                "runlevel rl2",
+                "path /run/daemon",
+                "start daemon",
+                "skip netplugd",
+                "login /bin/ash",
+            ]
+        );
+
+        assert_eq!(
+            plan.steps[1],
+            Step::Fsck {
+                target: "/".to_string(),
+                argv: ["/sbin/fsck", "-p", "-t", "ext4", "/dev/sda1"]
+                    .map(String::from)
+                    .to_vec(),
+            }
+        );
+
+        let Some(Step::Path { entry }) = plan.steps.get(11) else {
+            panic!("/run/daemon is not created");
+        };
+        assert_eq!(
+            path_commands(entry),
+            ["mkdir -p /run/daemon", "chmod 0750 /run/daemon"]
+        );
+
+        let Some(Step::Start { commands, .. }) = plan.steps.get(12) else {
+            panic!("daemon is not started");
+        };
+        assert_eq!(commands[0].argv, ["/sbin/daemon", "-c", "a b"]);
+        assert_eq!(commands[0].timeout, Some(10));
+        assert_eq!(
+            commands[0].env["NOTIFY_SOCKET"],
+            root.join("run/init/notify/daemon.sock")
+                .display()
+                .to_string()
+        );
+
+        let Some(Step::Skip { reason, .. }) = plan.steps.get(13) else {
+            panic!("netplugd is not skipped");
+        };
+        assert_eq!(reason, "condition path_exists = /sys/class/net/eth0");
+
+        // the filesystems are unmounted and `/` is left read-only
+        let conf = Config::parse(paths.master_conf()).unwrap();
+        let mut sv = Supervisor::new(conf, paths.clone()).dry_run();
+        sv.boot();
+        sv.shutdown(nix::sys::reboot::RebootMode::RB_POWER_OFF)
+            .unwrap();
+        let steps = sv.take_plan();
+        let tail: Vec<&Step> = steps.iter().rev().take(4).rev().collect();
+        assert!(matches!(tail[0], Step::Swapoff { source } if source == "/dev/sda3"));
+        assert!(matches!(tail[1], Step::Unmount { target } if target == "/home"));
+        assert!(
+            matches!(tail[2], Step::Remount { target, options } if target == "/" && options == "ro")
+        );
+        assert_eq!(tail[3], &Step::Shutdown { reboot: false });
+
+        // nothing is executed and written
+        assert!(!paths.state_dir().exists());
+        assert!(!paths.resolve("/run/daemon").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn quote_test() {
+        let argv: Vec<String> = ["/bin/sh", "-c", "echo 'a b'", ""]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+
+        let line = quote_argv(&argv);
+        assert_eq!(line, r#"/bin/sh -c 'echo '"'"'a b'"'"'' ''"#);
+        assert_eq!(split_args(&line).unwrap(), argv);
+    }
+}
@@ -0,0 +1,350 @@
+//! Readiness notification of services
+//!
+//! By default the service is ready when its start commands have exited.
+//! Daemons that keep running in the foreground report readiness through
+//! the `sd_notify(3)`-compatible `NOTIFY_SOCKET`, a file descriptor (like
+//! s6 does) or by creating their socket. Forking daemons are ready when
+//! they have written their PID file.
+
+use anyhow::anyhow;
+use anyhow::Result;
+use std::fs;
+use std::fs::File;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::os::fd::AsFd;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Child;
+use std::thread::sleep;
+use std::time::Duration;
+use std::time::Instant;
+
+use nix::fcntl::fcntl;
+use nix::fcntl::FcntlArg;
+use nix::fcntl::FdFlag;
+use nix::fcntl::OFlag;
+use nix::poll::poll;
+use nix::poll::PollFd;
+use nix::poll::PollFlags;
+use nix::sys::signal::kill;
+use nix::sys::signal::Signal;
+use nix::unistd::dup2;
+use nix::unistd::pipe2;
+use nix::unistd::Pid;
+
+use crate::paths::Paths;
+use crate::service::ExecType;
+use crate::service::Ready;
+use crate::service::Service;
+
+/// Time to wait for readiness if `ready_timeout` is not specified
+pub const DEFAULT_READY_TIMEOUT: u64 = 30;
+
+/// Interval between readiness checks
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Result of the service start
+pub struct Started {
+    /// Return code of the start commands
+    pub code: i32,
+
+    /// PID of the main process of the service, if it keeps running
+    pub pid: Option<u32>,
+}
+
+/// Message sent by the service to the `NOTIFY_SOCKET`
+#[derive(Debug, Default, PartialEq)]
+pub struct Notification {
+    /// `READY=1`
+    pub ready: bool,
+
+    /// `MAINPID=...`
+    pub main_pid: Option<u32>,
+
+    /// `STATUS=...`
+    pub status: Option<String>,
+}
+
+impl Notification {
+    /// Parses the `sd_notify(3)` message: newline-separated `KEY=VALUE`
+    /// pairs. Unknown keys are ignored
+    pub fn parse(msg: &str) -> Self {
+        let mut notification = Self::default();
+
+        for (key, val) in msg.lines().filter_map(|line| line.split_once('=')) {
+            match key {
+                "READY" => notification.ready = val == "1",
+                "MAINPID" => notification.main_pid = val.parse().ok(),
+                "STATUS" => notification.status = Some(val.to_string()),
+                _ => {}
+            }
+        }
+
+        notification
+    }
+}
+
+/// Runs the start commands of the `name` service and waits for it to
+/// become ready
+pub fn start(paths: &Paths, service: &Service, name: &str) -> Result<Started> {
+    let ready = service.service.ready.clone().unwrap_or_default();
+    let cmds = service.service.start.clone().unwrap_or_default();
+
+    let Some((last, first)) = cmds.split_last().filter(|_| ready != Ready::Exit) else {
+        return Ok(Started {
+            code: service.exec(ExecType::Start)?,
+            pid: None,
+        });
+    };
+
+    for cmd in first {
+        let code = service.run(cmd)?;
+        if code != 0 {
+            return Ok(Started { code, pid: None });
+        }
+    }
+
+    let timeout = Duration::from_secs(
+        service
+            .service
+            .ready_timeout
+            .unwrap_or(DEFAULT_READY_TIMEOUT),
+    );
+
+    match ready {
+        Ready::Exit => unreachable!(),
+        Ready::Pidfile => {
+            let code = service.run(last)?;
+            if code != 0 {
+                return Ok(Started { code, pid: None });
+            }
+
+            let pidfile =
+                service.service.pidfile.as_ref().ok_or_else(|| {
+                    anyhow!("`ready = \"pidfile\"` requires the `pidfile` parameter")
+                })?;
+            let pid = wait_pidfile(Path::new(pidfile), timeout)?;
+
+            Ok(Started {
+                code: 0,
+                pid: Some(pid),
+            })
+        }
+        Ready::Notify => {
+            let sock = NotifySocket::bind(paths, name)?;
+            let mut cmd = service.command(last)?;
+            cmd.env("NOTIFY_SOCKET", sock.path());
+
+            let mut child = cmd.spawn()?;
+            let mut main_pid = None;
+            wait(&mut child, timeout, || {
+                let notification = sock.recv()?;
+                main_pid = notification.main_pid.or(main_pid);
+                Ok(notification.ready)
+            })?;
+
+            Ok(Started {
+                code: 0,
+                pid: Some(main_pid.unwrap_or(child.id())),
+            })
+        }
+        Ready::Fd(fd) => {
+            // both ends must not leak into the daemon: the notification
+            // fd must be the only writer for its closing to be noticed
+            let (rd, wr) = pipe2(OFlag::O_CLOEXEC)?;
+            let wr_fd = wr.as_raw_fd();
+
+            let mut cmd = service.command(last)?;
+            // SAFETY: only async-signal-safe functions are called in the
+            // child between `fork()` and `exec()`
+            unsafe {
+                cmd.pre_exec(move || {
+                    // `dup2()` clears `FD_CLOEXEC` on the new fd
+                    if wr_fd == fd {
+                        fcntl(fd, FcntlArg::F_SETFD(FdFlag::empty()))?;
+                    } else {
+                        dup2(wr_fd, fd)?;
+                    }
+                    Ok(())
+                });
+            }
+
+            let mut child = cmd.spawn()?;
+            drop(wr);
+
+            let mut rd = File::from(rd);
+            wait(&mut child, timeout, || {
+                let mut fds = [PollFd::new(rd.as_fd(), PollFlags::POLLIN)];
+                if poll(&mut fds, 0u8)? == 0 {
+                    return Ok(false);
+                }
+
+                let mut buf = [0; 64];
+                match rd.read(&mut buf)? {
+                    0 => Err(anyhow!("readiness fd {fd} closed without notification")),
+                    n => Ok(buf[..n].contains(&b'\n')),
+                }
+            })?;
+
+            Ok(Started {
+                code: 0,
+                pid: Some(child.id()),
+            })
+        }
+        Ready::Socket(path) => {
+            let mut child = service.command(last)?.spawn()?;
+            wait(&mut child, timeout, || Ok(Path::new(&path).exists()))?;
+
+            Ok(Started {
+                code: 0,
+                pid: Some(child.id()),
+            })
+        }
+    }
+}
+
+/// Waits until `is_ready` returns `true`. Fails if the main process exits
+/// before or if `timeout` expires. In the latter case the main process is
+/// terminated
+fn wait<F>(child: &mut Child, timeout: Duration, mut is_ready: F) -> Result<()>
+where
+    F: FnMut() -> Result<bool>,
+{
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        if is_ready()? {
+            return Ok(());
+        }
+
+        if let Some(status) = child.try_wait()? {
+            return Err(anyhow!(
+                "main process exited before becoming ready ({status})"
+            ));
+        }
+
+        if Instant::now() >= deadline {
+            let _ = kill(Pid::from_raw(child.id() as i32), Signal::SIGTERM);
+            return Err(anyhow!("not ready after {} s", timeout.as_secs()));
+        }
+
+        sleep(POLL_INTERVAL);
+    }
+}
+
+fn wait_pidfile(pidfile: &Path, timeout: Duration) -> Result<u32> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        if let Ok(pid) = fs::read_to_string(pidfile) {
+            if let Ok(pid) = pid.trim().parse() {
+                return Ok(pid);
+            }
+        }
+
+        if Instant::now() >= deadline {
+            return Err(anyhow!(
+                "{} was not written after {} s",
+                pidfile.display(),
+                timeout.as_secs()
+            ));
+        }
+
+        sleep(POLL_INTERVAL);
+    }
+}
+
+/// `NOTIFY_SOCKET` of the service
+struct NotifySocket {
+    sock: UnixDatagram,
+    path: PathBuf,
+}
+
+impl NotifySocket {
+    fn bind(paths: &Paths, name: &str) -> Result<Self> {
+        let dir = paths.notify_dir();
+        fs::create_dir_all(&dir)?;
+
+        let path = dir.join(format!("{name}.sock"));
+        let _ = fs::remove_file(&path);
+
+        let sock = UnixDatagram::bind(&path)?;
+        sock.set_nonblocking(true)?;
+
+        Ok(Self { sock, path })
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reads all pending messages
+    fn recv(&self) -> Result<Notification> {
+        let mut notification = Notification::default();
+        let mut buf = [0; 4096];
+
+        loop {
+            match self.sock.recv(&mut buf) {
+                Ok(n) => {
+                    let msg = Notification::parse(&String::from_utf8_lossy(&buf[..n]));
+                    notification.ready |= msg.ready;
+                    notification.main_pid = msg.main_pid.or(notification.main_pid);
+                    notification.status = msg.status.or(notification.status);
+                }
+                Err(why) if why.kind() == ErrorKind::WouldBlock => return Ok(notification),
+                Err(why) => return Err(why.into()),
+            }
+        }
+    }
+}
+
+impl Drop for NotifySocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notification_test() {
+        assert_eq!(
+            Notification::parse("READY=1\nSTATUS=Listening on port 80\nMAINPID=42\n"),
+            Notification {
+                ready: true,
+                main_pid: Some(42),
+                status: Some("Listening on port 80".to_string()),
+            }
+        );
+        assert_eq!(
+            Notification::parse("WATCHDOG=1\nREADY=0"),
+            Notification::default()
+        );
+    }
+
+    #[test]
+    fn fd_closed_test() {
+        let service: Service = toml::from_str(
+            "[service]\nstart = [\"/bin/sh -c 'exec 3>&-; sleep 2'\"]\n\
+             ready = \"fd:3\"\nready_timeout = 5\n\n[init]\ndescription = \"fd\"\n",
+        )
+        .unwrap();
+
+        // the daemon is the only writer, closing it is noticed at once
+        let started = Instant::now();
+        let err = start(&Paths::with_root("/nonexistent"), &service, "fd")
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "readiness fd 3 closed without notification"
+        );
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
@@ -3,95 +3,367 @@
 //! Service is a minimal unit in lfa_init, designed
 //! to start and stop the services
 
+use anyhow::anyhow;
 use anyhow::Result;
 use serde::Deserialize;
 use serde::Serialize;
-use std::path::Path;
 use std::process::Command;
+use std::str::FromStr;
 
-use crate::consts::CONF_DIR;
+use nix::sys::signal::Signal;
+
+use crate::msg::Msg;
+use crate::msg::MsgType;
+use crate::paths::Paths;
 use crate::traits::TomlConfig;
 
 #[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct Service {
     pub service: ServiceSection,
     pub init: InitSection,
+    pub health: Option<HealthSection>,
+
+    /// Files and directories created by init before the service is
+    /// started (`[[paths]]`)
+    pub paths: Option<Vec<PathSection>>,
+
+    /// Conditions that must be met to start the service
+    pub conditions: Option<ConditionsSection>,
 }
 
 #[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct ServiceSection {
     pub start: Option<Vec<String>>,
     pub stop: Option<Vec<String>>,
     pub restart: Option<Vec<String>>,
     pub can_restart: Option<bool>,
+
+    /// Commands that make the service re-read its configuration
+    pub reload: Option<Vec<String>>,
+
+    /// Whether the service can be reloaded without restarting. Default: `Some(false)`
+    pub can_reload: Option<bool>,
+
+    /// Signal sent to the main process of the service to reload it if
+    /// there are no `reload` commands (e.g. `"HUP"`)
+    pub reload_signal: Option<String>,
+
+    /// How the service reports that it is ready. Default: `Ready::Exit`
+    pub ready: Option<Ready>,
+
+    /// PID file written by the daemon. Used if `ready = "pidfile"`
+    pub pidfile: Option<String>,
+
+    /// Time in seconds to wait for the service to become ready. Default: `Some(30)`
+    pub ready_timeout: Option<u64>,
+
+    /// When the service is restarted automatically. Default: `RestartPolicy::never`
+    pub restart_policy: Option<RestartPolicy>,
+
+    /// Maximum number of automatic restarts. Default: `Some(5)`
+    pub max_restarts: Option<u32>,
+
+    /// Task performed by init itself instead of the start commands
+    pub builtin: Option<Builtin>,
+}
+
+/// Early boot task performed by init itself (see `crate::builtin`)
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum Builtin {
+    /// Set the hostname from `/etc/hostname`
+    hostname,
+
+    /// Bring up the loopback interface
+    loopback,
+
+    /// Apply `/etc/sysctl.conf` and `/etc/sysctl.d/*.conf`
+    sysctl,
+}
+
+/// When init restarts the service automatically
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[allow(non_camel_case_types)]
+pub enum RestartPolicy {
+    /// The service is never restarted
+    #[default]
+    never,
+
+    /// The service is restarted if its main process fails or its health
+    /// check keeps failing
+    on_failure,
+
+    /// The service is restarted whenever its main process exits or its
+    /// health check keeps failing
+    always,
+}
+
+/// Periodic health check of the running service
+///
+/// Exactly one of `command`, `tcp` and `unix` must be specified.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HealthSection {
+    /// Command that exits with zero code if the service is healthy
+    pub command: Option<String>,
+
+    /// TCP address that must accept connections (e.g. `"127.0.0.1:80"`)
+    pub tcp: Option<String>,
+
+    /// Unix domain socket that must accept connections
+    pub unix: Option<String>,
+
+    /// Time in seconds between checks. Default: `Some(30)`
+    pub interval: Option<u64>,
+
+    /// Time in seconds to wait for a single check. Default: `Some(5)`
+    pub timeout: Option<u64>,
+
+    /// Number of consecutive failed checks after which the service is
+    /// considered unhealthy. Default: `Some(3)`
+    pub threshold: Option<u32>,
+}
+
+/// Start conditions of the service (see `crate::conditions`)
+///
+/// All specified conditions must be met, otherwise the service is
+/// `skipped`. Values starting with `!` are negated.
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ConditionsSection {
+    /// Paths that must exist
+    pub path_exists: Option<Vec<String>>,
+
+    /// Paths that must not exist
+    pub path_not_exists: Option<Vec<String>>,
+
+    /// Files that must exist and be non-empty
+    pub file_not_empty: Option<Vec<String>>,
+
+    /// Kernel command line arguments that must be present: `quiet` matches
+    /// both `quiet` and `quiet=...`, `console=ttyS0` only the exact value
+    pub kernel_cmdline: Option<Vec<String>>,
+
+    /// Machine architectures, any of them (`x86_64`, `aarch64`, `armv7l`)
+    pub architecture: Option<Vec<String>>,
+
+    /// Virtualization, any of: `none`, `vm`, `container` or the name of
+    /// the technology (`kvm`, `qemu`, `xen`, `docker`, `lxc`, ...)
+    pub virtualization: Option<Vec<String>>,
+
+    /// Compatible strings of the device tree, any of them
+    /// (`raspberrypi,4-model-b`, `allwinner,sun8i-h3`)
+    pub device_tree_compatible: Option<Vec<String>>,
+
+    /// Environment variables of init: `NAME` must be set, `NAME=value`
+    /// must have the value
+    pub env: Option<Vec<String>>,
+}
+
+/// File, directory, symlink or device node created by init (see
+/// `crate::tmpfiles`)
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PathSection {
+    /// Absolute path
+    pub path: String,
+
+    #[serde(rename = "type")]
+    pub kind: PathType,
+
+    /// Octal permissions (`"0755"`). Default: `0755` for directories,
+    /// `0644` for files and device nodes. The mode of an existing path is
+    /// changed only if it is specified
+    pub mode: Option<String>,
+
+    /// Owner (name or UID). Default: not changed (`root` for new paths)
+    pub user: Option<String>,
+
+    /// Group (name or GID). Default: the primary group of `user`
+    pub group: Option<String>,
+
+    /// Files not accessed or modified for this time are removed from the
+    /// directory (`30s`, `10m`, `1h`, `7d`, `2w`)
+    pub age: Option<String>,
+
+    /// Content of the new file, target of the symlink or `MAJOR:MINOR`
+    /// numbers of the device node
+    pub argument: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum PathType {
+    /// Directory (`d` in `tmpfiles.d`)
+    directory,
+
+    /// Regular file (`f`)
+    file,
+
+    /// Symbolic link (`L`)
+    symlink,
+
+    /// Character device node (`c`)
+    char,
+
+    /// Block device node (`b`)
+    block,
+
+    /// Existing directory that is only cleaned by age (`e`)
+    clean,
+}
+
+/// The way the service reports that it is ready
+///
+/// Next services are started only after the previous one is ready (or its
+/// `ready_timeout` expires).
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+#[serde(try_from = "ReadyRepr", into = "ReadyRepr")]
+pub enum Ready {
+    /// The service is ready when all start commands have exited (`"exit"`)
+    #[default]
+    Exit,
+
+    /// The service is ready when the daemon has written its PID to the
+    /// `pidfile` (`"pidfile"`)
+    Pidfile,
+
+    /// The last start command is the main process. It sends `READY=1` to
+    /// the `NOTIFY_SOCKET` like `sd_notify(3)` does (`"notify"`)
+    Notify,
+
+    /// The last start command is the main process. It writes a newline to
+    /// the file descriptor N (`"fd:N"`)
+    Fd(i32),
+
+    /// The last start command is the main process. The service is ready
+    /// when the socket appears (`{ path = "/run/x.sock" }`)
+    Socket(String),
+}
+
+/// Representation of `Ready` in the service file
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum ReadyRepr {
+    Kind(String),
+    Socket { path: String },
 }
 
 #[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct InitSection {
     pub description: String,
     pub on_error: Option<OnError>,
+
+    /// Services that must be running before this one is started. If
+    /// any of them fails, this service fails too
+    pub requires: Option<Vec<String>>,
+
+    /// Services that are started before this one, but whose failure
+    /// doesn't prevent it from starting
+    pub wants: Option<Vec<String>>,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Default)]
 #[allow(non_camel_case_types)]
 pub enum OnError {
     ignore,
+    #[default]
     error,
     abort,
 }
 
-struct Cmd {
-    prog: String,
-    args: Option<String>,
+impl ServiceSection {
+    /// Returns the signal specified in `reload_signal`. Both `"HUP"` and
+    /// `"SIGHUP"` forms are accepted
+    pub fn reload_signal(&self) -> Result<Option<Signal>> {
+        let Some(sig) = &self.reload_signal else {
+            return Ok(None);
+        };
+
+        let name = format!("SIG{}", sig.trim_start_matches("SIG"));
+        Signal::from_str(&name)
+            .map(Some)
+            .map_err(|_| anyhow!("unknown reload signal: {sig}"))
+    }
+}
+
+impl TryFrom<ReadyRepr> for Ready {
+    type Error = String;
+
+    fn try_from(repr: ReadyRepr) -> std::result::Result<Self, Self::Error> {
+        match repr {
+            ReadyRepr::Socket { path } => Ok(Self::Socket(path)),
+            ReadyRepr::Kind(kind) => match kind.as_str() {
+                "exit" => Ok(Self::Exit),
+                "pidfile" => Ok(Self::Pidfile),
+                "notify" => Ok(Self::Notify),
+                _ => match kind.strip_prefix("fd:").map(str::parse) {
+                    Some(Ok(fd)) if fd > 2 => Ok(Self::Fd(fd)),
+                    _ => Err(format!(
+                        "unknown readiness type `{kind}` (expected `exit`, `pidfile`, \
+                         `notify`, `fd:N` with N > 2 or `{{ path = \"...\" }}`)"
+                    )),
+                },
+            },
+        }
+    }
+}
+
+impl From<Ready> for ReadyRepr {
+    fn from(ready: Ready) -> Self {
+        match ready {
+            Ready::Exit => Self::Kind("exit".to_string()),
+            Ready::Pidfile => Self::Kind("pidfile".to_string()),
+            Ready::Notify => Self::Kind("notify".to_string()),
+            Ready::Fd(fd) => Self::Kind(format!("fd:{fd}")),
+            Ready::Socket(path) => Self::Socket { path },
+        }
+    }
 }
 
 pub enum ExecType {
     Start,
     Restart,
+    Reload,
     Stop,
 }
 
 impl Service {
-    pub fn new(srv_name: &str, rl: &str) -> Result<Self> {
-        // функция получает имя сервиса без расширения `*.toml`, поэтому
-        // его нужно добавить
-        let srv_name = format!("{srv_name}.toml");
-
-        let srv_pth = Path::new(CONF_DIR).join(rl).join(&srv_name);
-        Service::parse(srv_pth)
+    /// Reads the `srv_name` service (without the `.toml` extension) of
+    /// the `rl` runlevel
+    pub fn new(paths: &Paths, srv_name: &str, rl: &str) -> Result<Self> {
+        Service::parse(paths.service_file(rl, srv_name))
     }
 
-    fn run_cmd(&self, command: &Cmd) -> Result<i32> {
-        let code: i32;
-        if let Some(args) = &command.args {
-            let cmd = Command::new(&command.prog).arg(args).status()?.code();
-            code = cmd.unwrap_or(0);
-        } else {
-            let cmd = Command::new(&command.prog).status()?.code();
-            code = cmd.unwrap_or(0);
-        }
+    /// Builds the process for the `cmd` command of this service
+    pub(crate) fn command(&self, cmd: &str) -> Result<Command> {
+        let argv = split_args(cmd)?;
+        Msg::new(MsgType::Debug).print(format_args!("running `{}`", argv.join(" ")));
+
+        let Some((prog, args)) = argv.split_first() else {
+            return Err(anyhow!("empty command"));
+        };
+
+        let mut command = Command::new(prog);
+        command.args(args);
 
-        Ok(code)
+        Ok(command)
     }
 
-    fn parse_cmd(&self, cmd: &str) -> Cmd {
-        let command = cmd.split_once(" ");
-        match command {
-            Some(c) => Cmd {
-                prog: c.0.to_string(),
-                args: Some(c.1.to_string()),
-            },
-            None => Cmd {
-                prog: cmd.to_string(),
-                args: None,
-            },
-        }
+    /// Runs the `cmd` command of this service and waits for it to exit
+    pub(crate) fn run(&self, cmd: &str) -> Result<i32> {
+        let code = self.command(cmd)?.status()?.code();
+        Ok(code.unwrap_or(0))
     }
 
     pub fn exec(&self, exec_type: ExecType) -> Result<i32> {
         let cmd = match exec_type {
             ExecType::Start => &self.service.start,
             ExecType::Restart => &self.service.restart,
+            ExecType::Reload => &self.service.reload,
             ExecType::Stop => &self.service.stop,
         };
 
@@ -99,8 +371,7 @@ impl Service {
             Some(cmd) => {
                 let mut run = 0;
                 for c in cmd {
-                    let c = self.parse_cmd(c);
-                    run = self.run_cmd(&c)?;
+                    run = self.run(c)?;
 
                     // если одна команда завершилась с ошибкой,
                     // то прервать выполнение остальных команд
@@ -118,6 +389,44 @@ impl Service {
 
 impl TomlConfig for Service {}
 
+/// Splits the command into the program and its arguments like a shell
+/// does: arguments are separated by whitespace, single and double quotes
+/// group words, backslash escapes the next character (except in single
+/// quotes). No other shell features are supported
+pub fn split_args(cmd: &str) -> Result<Vec<String>> {
+    let mut argv = vec![];
+    let mut arg: Option<String> = None;
+    let mut quote = None;
+    let mut chars = cmd.chars();
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('\''), c) => arg.get_or_insert_with(String::new).push(c),
+            (_, '\\') => {
+                let c = chars
+                    .next()
+                    .ok_or_else(|| anyhow!("`{cmd}`: trailing backslash"))?;
+                arg.get_or_insert_with(String::new).push(c);
+            }
+            (Some(_), c) => arg.get_or_insert_with(String::new).push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                arg.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => argv.extend(arg.take()),
+            (None, c) => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    if quote.is_some() {
+        return Err(anyhow!("`{cmd}`: unterminated quote"));
+    }
+    argv.extend(arg);
+
+    Ok(argv)
+}
+
 impl Default for Service {
     fn default() -> Self {
         Self {
@@ -126,21 +435,29 @@ impl Default for Service {
                 stop: None,
                 restart: None,
                 can_restart: Some(false),
+                reload: None,
+                can_reload: Some(false),
+                reload_signal: None,
+                ready: None,
+                pidfile: None,
+                ready_timeout: None,
+                restart_policy: None,
+                max_restarts: None,
+                builtin: None,
             },
             init: InitSection {
                 description: "[example] Default service".to_string(),
                 on_error: Some(OnError::default()),
+                requires: None,
+                wants: None,
             },
+            health: None,
+            paths: None,
+            conditions: None,
         }
     }
 }
 
-impl Default for OnError {
-    fn default() -> Self {
-        Self::error
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -158,4 +475,54 @@ mod tests {
         let data = toml::to_string(&service).unwrap();
         fs::write("test_service.toml", data).unwrap();
     }
+
+    #[test]
+    fn ready_test() {
+        let parse = |ready: &str| {
+            toml::from_str::<ServiceSection>(&format!("ready = {ready}")).map(|srv| srv.ready)
+        };
+
+        assert_eq!(parse("\"notify\"").unwrap(), Some(Ready::Notify));
+        assert_eq!(parse("\"fd:3\"").unwrap(), Some(Ready::Fd(3)));
+        assert_eq!(
+            parse("{ path = \"/run/x.sock\" }").unwrap(),
+            Some(Ready::Socket("/run/x.sock".to_string()))
+        );
+        assert!(parse("\"fd:1\"").is_err());
+        assert!(parse("\"socket\"").is_err());
+    }
+
+    #[test]
+    fn split_args_test() {
+        assert_eq!(
+            split_args("/bin/mount -t proc  none /proc").unwrap(),
+            ["/bin/mount", "-t", "proc", "none", "/proc"]
+        );
+        assert_eq!(
+            split_args(r#"/usr/sbin/nginx -g 'daemon off;' "a \"b\"" c\ d ''"#).unwrap(),
+            ["/usr/sbin/nginx", "-g", "daemon off;", "a \"b\"", "c d", ""]
+        );
+        assert!(split_args("/bin/echo 'x").is_err());
+    }
+
+    #[test]
+    fn reload_signal_test() {
+        let mut service = Service::default();
+        assert_eq!(service.service.reload_signal().unwrap(), None);
+
+        service.service.reload_signal = Some("HUP".to_string());
+        assert_eq!(
+            service.service.reload_signal().unwrap(),
+            Some(Signal::SIGHUP)
+        );
+
+        service.service.reload_signal = Some("SIGUSR1".to_string());
+        assert_eq!(
+            service.service.reload_signal().unwrap(),
+            Some(Signal::SIGUSR1)
+        );
+
+        service.service.reload_signal = Some("RELOAD".to_string());
+        assert!(service.service.reload_signal().is_err());
+    }
 }
@@ -0,0 +1,346 @@
+//! Runtime state of the services
+//!
+//! The authoritative state is kept in memory by init. It is mirrored into
+//! `/run/init/state` (one TOML file per service) so that other programs can
+//! read it even if init doesn't answer. `/run` is a tmpfs, so the state
+//! never survives a reboot.
+
+use anyhow::anyhow;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fmt;
+use std::fmt::Display;
+use std::fs;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use crate::paths::Paths;
+use crate::traits::TomlConfig;
+
+/// Maximum number of state transitions kept for each service
+const MAX_TRANSITIONS: usize = 32;
+
+/// State of the service
+///
+/// Allowed transitions:
+///
+/// ```text
+/// inactive, exited, failed,
+/// skipped                  -> starting
+/// starting                 -> skipped
+/// starting, restarting     -> running, failed
+/// running                  -> stopping, restarting, exited, failed, unhealthy
+/// unhealthy                -> running, stopping, restarting, exited, failed
+/// stopping                 -> inactive, failed
+/// ```
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[allow(non_camel_case_types)]
+pub enum ServiceState {
+    /// The service is not started
+    #[default]
+    inactive,
+
+    /// Start commands are being executed
+    starting,
+
+    /// Start commands have been completed successfully
+    running,
+
+    /// Stop commands are being executed
+    stopping,
+
+    /// The main process of the service has exited successfully
+    exited,
+
+    /// Some commands of the service or its main process have failed
+    failed,
+
+    /// Restart commands are being executed
+    restarting,
+
+    /// The service is running, but its health check keeps failing
+    unhealthy,
+
+    /// The start conditions of the service are not met
+    skipped,
+}
+
+/// Transition of the service to the new state
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Transition {
+    pub state: ServiceState,
+
+    /// Time of the transition (seconds since the Unix epoch)
+    pub at: u64,
+}
+
+/// State of the service started by init
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct ServiceRecord {
+    /// Runlevel
+    pub rl: String,
+
+    /// Service name
+    pub name: String,
+
+    /// Sequence number of the last launch. Services are stopped in
+    /// the reverse order
+    pub seq: u64,
+
+    pub state: ServiceState,
+
+    /// PID of the main process of the service if it is tracked by init
+    pub pid: Option<u32>,
+
+    /// Return code of the last command executed for this service
+    pub last_exit: Option<i32>,
+
+    /// Reason of the last failure
+    pub failure: Option<String>,
+
+    /// Number of automatic restarts since the service was started manually
+    #[serde(default)]
+    pub restarts: u32,
+
+    /// Number of consecutive failed health checks
+    #[serde(default)]
+    pub failed_checks: u32,
+
+    /// Last transitions of the service from the oldest to the newest
+    pub transitions: Vec<Transition>,
+}
+
+impl ServiceState {
+    /// Whether the service can switch from this state to the `next` one
+    pub fn can_switch_to(self, next: Self) -> bool {
+        matches!(
+            (self, next),
+            (
+                Self::inactive | Self::exited | Self::failed | Self::skipped,
+                Self::starting
+            ) | (
+                Self::starting | Self::restarting,
+                Self::running | Self::failed
+            ) | (Self::starting, Self::skipped)
+                | (
+                    Self::running,
+                    Self::stopping
+                        | Self::restarting
+                        | Self::exited
+                        | Self::failed
+                        | Self::unhealthy
+                )
+                | (
+                    Self::unhealthy,
+                    Self::running | Self::stopping | Self::restarting | Self::exited | Self::failed
+                )
+                | (Self::stopping, Self::inactive | Self::failed)
+        )
+    }
+
+    /// Whether the stop commands must be executed to stop the service
+    pub fn needs_stop(self) -> bool {
+        matches!(self, Self::running | Self::unhealthy)
+    }
+}
+
+impl Display for ServiceState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self {
+            Self::inactive => "inactive",
+            Self::starting => "starting",
+            Self::running => "running",
+            Self::stopping => "stopping",
+            Self::exited => "exited",
+            Self::failed => "failed",
+            Self::restarting => "restarting",
+            Self::unhealthy => "unhealthy",
+            Self::skipped => "skipped",
+        };
+        write!(f, "{state}")
+    }
+}
+
+impl ServiceRecord {
+    pub fn new(rl: &str, name: &str) -> Self {
+        Self {
+            rl: rl.to_string(),
+            name: name.to_string(),
+            seq: 0,
+            state: ServiceState::default(),
+            pid: None,
+            last_exit: None,
+            failure: None,
+            restarts: 0,
+            failed_checks: 0,
+            transitions: vec![],
+        }
+    }
+
+    /// Whether the service has been started successfully and is
+    /// still running (healthy or not)
+    pub fn is_running(&self) -> bool {
+        self.state.needs_stop()
+    }
+
+    /// Switches the service to the `state`. Fails if this transition
+    /// is not allowed
+    pub fn set_state(&mut self, state: ServiceState) -> Result<()> {
+        if !self.state.can_switch_to(state) {
+            return Err(anyhow!(
+                "service {} can't switch from {} to {state}",
+                &self.name,
+                self.state
+            ));
+        }
+
+        self.state = state;
+        self.transitions.push(Transition { state, at: now() });
+        if self.transitions.len() > MAX_TRANSITIONS {
+            self.transitions.remove(0);
+        }
+
+        Ok(())
+    }
+
+    /// Switches the service to the `ServiceState::failed` state
+    pub fn fail<D: Display>(&mut self, reason: D) -> Result<()> {
+        self.set_state(ServiceState::failed)?;
+        self.failure = Some(reason.to_string());
+
+        Ok(())
+    }
+
+    /// Returns the time of the last start of the service
+    pub fn started_at(&self) -> Option<u64> {
+        self.transitions
+            .iter()
+            .rev()
+            .find(|t| t.state == ServiceState::starting)
+            .map(|t| t.at)
+    }
+}
+
+impl TomlConfig for ServiceRecord {}
+
+/// Mirrors the state of all `services` into `/run/init/state`. Each file
+/// is replaced atomically, so readers never see a partially written one
+pub fn save(paths: &Paths, services: &[ServiceRecord]) -> Result<()> {
+    let state_dir = paths.state_dir();
+    fs::create_dir_all(&state_dir)?;
+
+    let mut names = HashSet::new();
+    for service in services {
+        let name = format!("{}-{}.toml", &service.rl, &service.name);
+        let tmp = state_dir.join(format!(".{name}.tmp"));
+        service.write(&tmp)?;
+        fs::rename(&tmp, state_dir.join(&name))?;
+        names.insert(OsString::from(name));
+    }
+
+    // removing stopped services
+    for entry in fs::read_dir(&state_dir)? {
+        let entry = entry?;
+        if !names.contains(&entry.file_name()) {
+            fs::remove_file(entry.path())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the state of all services written by the running init. Services
+/// are returned in the order of their launch
+pub fn load(paths: &Paths) -> Result<Vec<ServiceRecord>> {
+    let state_dir = paths.state_dir();
+    if !state_dir.is_dir() {
+        return Err(anyhow!(
+            "init is not running ({} not found)",
+            state_dir.display()
+        ));
+    }
+
+    let mut services = vec![];
+    for entry in fs::read_dir(&state_dir)? {
+        let pth = entry?.path();
+        if pth.extension().is_some_and(|ext| ext == "toml") {
+            services.push(ServiceRecord::parse(&pth)?);
+        }
+    }
+    services.sort_by_key(|srv| srv.seq);
+
+    Ok(services)
+}
+
+/// Returns the current time in seconds since the Unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_root;
+
+    #[test]
+    fn transitions_test() {
+        let mut srv = ServiceRecord::new("rl2", "syslogd");
+        assert!(srv.set_state(ServiceState::running).is_err());
+        assert_eq!(srv.state, ServiceState::inactive);
+
+        srv.set_state(ServiceState::starting).unwrap();
+        srv.set_state(ServiceState::running).unwrap();
+        srv.set_state(ServiceState::unhealthy).unwrap();
+        assert!(srv.is_running());
+        srv.set_state(ServiceState::restarting).unwrap();
+        srv.fail("non-zero return code (1)").unwrap();
+        assert!(srv.set_state(ServiceState::stopping).is_err());
+        srv.set_state(ServiceState::starting).unwrap();
+
+        assert_eq!(srv.transitions.len(), 6);
+        assert_eq!(srv.failure.as_deref(), Some("non-zero return code (1)"));
+        assert!(srv.started_at().is_some());
+    }
+
+    #[test]
+    fn record_roundtrip() {
+        let mut srv = ServiceRecord::new("rl1", "mount_procfs");
+        srv.set_state(ServiceState::starting).unwrap();
+
+        let data = toml::to_string(&srv).unwrap();
+        assert_eq!(toml::from_str::<ServiceRecord>(&data).unwrap(), srv);
+    }
+
+    #[test]
+    fn save_test() {
+        let root = temp_root("state");
+        let paths = Paths::with_root(&root);
+
+        let mut first = ServiceRecord::new("rl1", "getty");
+        first.seq = 1;
+        let mut second = ServiceRecord::new("rl2", "getty");
+        second.seq = 2;
+        second.pid = Some(42);
+
+        save(&paths, &[first, second.clone()]).unwrap();
+        assert_eq!(load(&paths).unwrap().len(), 2);
+
+        save(&paths, &[second]).unwrap();
+        let services = load(&paths).unwrap();
+        let entries = fs::read_dir(paths.state_dir()).unwrap().count();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(services.len(), 1);
+        assert_eq!(
+            (services[0].rl.as_str(), services[0].pid),
+            ("rl2", Some(42))
+        );
+        assert_eq!(entries, 1);
+    }
+}
@@ -0,0 +1,1955 @@
+//! Supervisor of the running system
+//!
+//! Keeps track of the current runlevel and of the services started by
+//! init, and switches the system between runlevels at runtime.
+
+use anyhow::anyhow;
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::os::fd::AsFd;
+use std::os::fd::BorrowedFd;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::time::Duration;
+use std::time::Instant;
+
+use nix::sys::reboot::reboot;
+use nix::sys::reboot::RebootMode;
+use nix::sys::signal::kill;
+use nix::sys::signal::Signal;
+use nix::sys::wait::WaitStatus;
+use nix::unistd::sync;
+use nix::unistd::Pid;
+
+use crate::boot::BootRecord;
+use crate::builtin;
+use crate::cmdline::BootOptions;
+use crate::conditions;
+use crate::conditions::Host;
+use crate::config::Action;
+use crate::config::Config;
+use crate::config::EmergencyExit;
+use crate::config::Runlevel;
+use crate::consts::ZRAM_DEVICE;
+use crate::devices::DeviceManager;
+use crate::emergency;
+use crate::fsck;
+use crate::fsck::Outcome;
+use crate::fstab;
+use crate::fstab::FstabEntry;
+use crate::health;
+use crate::health::Probe;
+use crate::modules;
+use crate::modules::ModuleDb;
+use crate::mount;
+use crate::mount::EARLY_RUNLEVEL;
+use crate::paths::Paths;
+use crate::plan;
+use crate::plan::Step;
+use crate::progress;
+use crate::ready;
+use crate::service::split_args;
+use crate::service::ExecType;
+use crate::service::OnError;
+use crate::service::Ready;
+use crate::service::RestartPolicy;
+use crate::service::Service;
+use crate::state;
+use crate::state::ServiceRecord;
+use crate::state::ServiceState;
+use crate::swap;
+use crate::swap::SwapKind;
+use crate::tmpfiles;
+use crate::traits::TomlConfig;
+
+/// Login shell used if the runlevel doesn't declare its own
+const DEFAULT_LOGIN_SHELL: &str = "/bin/ash";
+
+/// Number of automatic restarts if `max_restarts` is not specified
+const DEFAULT_MAX_RESTARTS: u32 = 5;
+
+pub struct Supervisor {
+    /// Master config file of the LFA init
+    pub conf: Config,
+
+    /// Services started by init in the order of their launch
+    pub services: Vec<ServiceRecord>,
+
+    /// Sequence number of the last service launch
+    seq: u64,
+
+    /// Boot options from the kernel command line
+    pub opts: BootOptions,
+
+    /// Location of the configuration and runtime files
+    pub paths: Paths,
+
+    /// Index of the current runlevel in `conf.runlevel`
+    current: Option<usize>,
+
+    /// Health checks of the running services
+    checks: Vec<Check>,
+
+    /// Steps recorded instead of being performed in the dry-run mode
+    plan: Option<Vec<Step>>,
+
+    /// Timings of the boot. Recorded only if the system has been booted
+    /// by this supervisor
+    pub boot: Option<BootRecord>,
+
+    /// Handler of the kernel uevents. Started at boot if
+    /// `/etc/init/devices.toml` exists
+    devices: Option<DeviceManager>,
+
+    /// Facts about the system checked by the start conditions of the
+    /// services. Detected at the first check
+    host: Option<Host>,
+}
+
+/// Services started together: by entering a runlevel or by one
+/// `service start` command
+#[derive(Default)]
+struct StartPass {
+    /// Services being started, used to detect dependency cycles
+    chain: Vec<String>,
+
+    /// Services whose start has been attempted and its result. Failed
+    /// services are not retried by the services depending on them
+    started: BTreeMap<String, bool>,
+}
+
+/// Health check of the running service
+struct Check {
+    rl: String,
+    name: String,
+    service: Service,
+
+    /// Time of the next check
+    next: Instant,
+
+    /// Check in progress
+    probe: Option<Probe>,
+}
+
+impl Supervisor {
+    pub fn new(conf: Config, paths: Paths) -> Self {
+        Self {
+            conf,
+            services: vec![],
+            seq: 0,
+            opts: BootOptions::default(),
+            paths,
+            current: None,
+            checks: vec![],
+            plan: None,
+            boot: None,
+            devices: None,
+            host: None,
+        }
+    }
+
+    /// Switches the supervisor to the dry-run mode: nothing is executed,
+    /// the steps are recorded and can be taken with `take_plan()`
+    pub fn dry_run(mut self) -> Self {
+        self.plan = Some(vec![]);
+        self
+    }
+
+    /// Returns the steps recorded in the dry-run mode
+    pub fn take_plan(&mut self) -> Vec<Step> {
+        self.plan.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// Applies boot options passed through the kernel command line
+    pub fn apply_boot_options(&mut self, opts: BootOptions) {
+        if let Some(rl) = &opts.runlevel {
+            if self.conf.runlevel.iter().any(|r| &r.dir == rl) {
+                progress!("init: booting into the {rl} runlevel (kernel command line)\n");
+                self.conf.final_runlevel = rl.clone();
+            } else {
+                eprintln!("init: warning: unknown runlevel {rl} in the kernel command line");
+            }
+        }
+
+        if !opts.skip.is_empty() {
+            progress!("init: skipping services: {}\n", opts.skip.join(", "));
+        }
+
+        if let Some(sh) = &opts.shell {
+            progress!("init: using {sh} as the login shell\n");
+        }
+
+        self.opts = opts;
+    }
+
+    /// Returns the name of the current runlevel
+    pub fn current(&self) -> Option<&str> {
+        self.current.map(|idx| self.conf.runlevel[idx].dir.as_str())
+    }
+
+    /// Returns the login shell of the current runlevel
+    pub fn login_shell(&self) -> String {
+        if let Some(sh) = &self.opts.shell {
+            return sh.clone();
+        }
+
+        self.current
+            .and_then(|idx| self.conf.runlevel[idx].login_shell.clone())
+            .filter(|sh| !sh.is_empty())
+            .unwrap_or(DEFAULT_LOGIN_SHELL.to_string())
+    }
+
+    /// Sequentially enters all used runlevels up to `final_runlevel`
+    pub fn boot(&mut self) {
+        self.boot = Some(BootRecord::new());
+
+        // `/run` has to be mounted before the state is written
+        self.early_mount();
+        self.load_modules();
+        self.activate_swap();
+        self.create_tmpfiles();
+        self.start_devices();
+
+        // removing the state left by the previous init
+        self.save_state();
+
+        for idx in 0..self.conf.runlevel.len() {
+            let rl = &self.conf.runlevel[idx];
+
+            if !rl.r#use.unwrap_or(true) {
+                progress!("\nSkiping runlevel {}\n", &rl.dir);
+                continue;
+            }
+
+            let is_final = rl.dir == self.conf.final_runlevel;
+            self.enter_runlevel(idx);
+
+            if is_final {
+                break;
+            }
+        }
+
+        if let Some(boot) = &mut self.boot {
+            boot.finish();
+        }
+        self.save_state();
+    }
+
+    /// Switches the running system to the `target` runlevel
+    ///
+    /// When going down, services of all runlevels above the target are stopped
+    /// in reverse order. When going up, all used runlevels between the current
+    /// and the target one are entered, starting the services that are not
+    /// running yet.
+    pub fn switch_runlevel(&mut self, target: &str) -> Result<()> {
+        let target_idx = self
+            .conf
+            .runlevel
+            .iter()
+            .position(|rl| rl.dir == target)
+            .ok_or_else(|| anyhow!("unknown runlevel: {target}"))?;
+
+        match self.current {
+            Some(cur) if target_idx < cur => {
+                for idx in (target_idx + 1..=cur).rev() {
+                    self.stop_runlevel(idx);
+                }
+
+                // services of the target runlevel are already running,
+                // but other actions must be performed anyway
+                if let Action::run_services =
+                    self.conf.runlevel[target_idx].action.unwrap_or_default()
+                {
+                    self.current = Some(target_idx);
+                    progress!("\nSwitched to the {target} runlevel\n");
+                } else {
+                    self.enter_runlevel(target_idx);
+                }
+            }
+            Some(cur) if target_idx == cur => {
+                progress!("\nAlready in the {target} runlevel\n");
+            }
+            cur => {
+                let first = cur.map(|idx| idx + 1).unwrap_or(0);
+                for idx in first..=target_idx {
+                    if idx != target_idx && !self.conf.runlevel[idx].r#use.unwrap_or(true) {
+                        continue;
+                    }
+                    self.enter_runlevel(idx);
+                }
+            }
+        }
+
+        self.save_state();
+
+        Ok(())
+    }
+
+    /// Stops all running services in the reverse order of their launch
+    pub fn stop_all(&mut self) {
+        self.stop_where(|_| true);
+        self.save_state();
+    }
+
+    /// Stops all loaded services, flushes file system buffers and then
+    /// powers off or reboots the system
+    pub fn shutdown(&mut self, mode: RebootMode) -> Result<()> {
+        self.stop_all();
+        self.deactivate_swap();
+        self.unmount_fstab();
+        self.remount_root_ro();
+
+        let reboot_mode = mode == RebootMode::RB_AUTOBOOT;
+        if self.planned(Step::Shutdown {
+            reboot: reboot_mode,
+        }) {
+            return Ok(());
+        }
+
+        sync();
+        match reboot(mode)? {}
+    }
+
+    /// Replaces init with the `prog` program
+    pub fn exec(&mut self, prog: &str) -> Result<()> {
+        let argv = split_args(prog)?;
+        let Some((prog, args)) = argv.split_first() else {
+            return Err(anyhow!("empty program"));
+        };
+
+        if self.planned(Step::Exec { argv: argv.clone() }) {
+            return Ok(());
+        }
+
+        self.save_state();
+        println!("\nExecuting {prog}...");
+
+        // `exec()` returns only on error
+        Err(Command::new(prog).args(args).exec().into())
+    }
+
+    /// Runs the `shell` on the console and waits for it to exit
+    pub fn run_shell(&mut self, shell: &str) -> Result<()> {
+        let step = Step::Shell {
+            shell: shell.to_string(),
+        };
+        if self.planned(step) {
+            return Ok(());
+        }
+
+        println!("\nRunning {shell}...");
+        let status = Command::new(shell).status()?;
+        println!("{shell} exited ({status})");
+
+        Ok(())
+    }
+
+    /// Sequentially starts the services of the `rl` runlevel that are not
+    /// running yet
+    pub fn start_services(&mut self, rl: &Runlevel) -> Result<()> {
+        let Some(services) = &rl.services else {
+            return Err(anyhow!("services not found"));
+        };
+
+        let mut pass = StartPass::default();
+        for service in services {
+            if self.is_running(&rl.dir, service) {
+                continue;
+            }
+
+            if self.opts.skip.contains(service) {
+                progress!("  -> skipping {service} service (init.skip)\n");
+                self.planned(Step::Skip {
+                    runlevel: rl.dir.clone(),
+                    service: service.clone(),
+                    reason: "init.skip".to_string(),
+                });
+                continue;
+            }
+
+            let res = self.start_with_deps(&rl.dir, service, &mut pass);
+            if res.is_err() && is_critical(&self.paths, &rl.dir, service) {
+                self.emergency(&format!("service {}/{service} failed", &rl.dir));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Starts the `name` service of any runlevel
+    pub fn start_service(&mut self, name: &str) -> Result<()> {
+        let rl = self.service_runlevel(name)?;
+
+        if self.is_running(&rl, name) {
+            return Err(anyhow!("service {name} is already running"));
+        }
+
+        self.record(&rl, name).restarts = 0;
+        let res = self.start_with_deps(&rl, name, &mut StartPass::default());
+        self.save_state();
+
+        res
+    }
+
+    /// Stops the running `name` service
+    pub fn stop_service(&mut self, name: &str) -> Result<()> {
+        let rl = self.service_runlevel(name)?;
+
+        if !self.is_running(&rl, name) {
+            return Err(anyhow!("service {name} is not running"));
+        }
+
+        let res = self.halt(&rl, name);
+        self.save_state();
+
+        res
+    }
+
+    /// Restarts the `name` service. The `restart` commands are used if the
+    /// service supports them, otherwise (or if `force = true`) the service is
+    /// stopped and started again
+    pub fn restart_service(&mut self, name: &str, force: bool) -> Result<()> {
+        let rl = self.service_runlevel(name)?;
+        let service = Service::new(&self.paths, name, &rl)?;
+
+        if force
+            || !self.is_running(&rl, name)
+            || !service.service.can_restart.unwrap_or(false)
+            || service.service.restart.is_none()
+        {
+            if self.is_running(&rl, name) {
+                self.stop_service(name)?;
+            }
+            return self.start_service(name);
+        }
+
+        progress!("  -> restarting {rl}/{name} service...");
+        self.record(&rl, name).set_state(ServiceState::restarting)?;
+
+        let res = exec_service(&service, ExecType::Restart);
+        let res = self.finish(&rl, name, res, ServiceState::running);
+        self.save_state();
+
+        res
+    }
+
+    /// Makes the running `name` service re-read its configuration. The
+    /// `reload` commands are used if they are specified, otherwise the
+    /// `reload_signal` is sent to the main process of the service
+    pub fn reload_service(&mut self, name: &str) -> Result<()> {
+        let rl = self.service_runlevel(name)?;
+        let service = Service::new(&self.paths, name, &rl)?;
+
+        if !self.is_running(&rl, name) {
+            return Err(anyhow!("service {name} is not running"));
+        }
+        if !service.service.can_reload.unwrap_or(false) {
+            return Err(anyhow!("service {name} can't be reloaded"));
+        }
+
+        let pid = self.record(&rl, name).pid;
+        let signal = match (
+            &service.service.reload,
+            service.service.reload_signal()?,
+            pid,
+        ) {
+            (Some(_), _, _) => None,
+            (None, Some(sig), Some(pid)) => Some((pid, sig)),
+            (None, Some(_), None) => {
+                return Err(anyhow!("main process of service {name} is not tracked"))
+            }
+            (None, None, _) => {
+                return Err(anyhow!(
+                    "service {name} has neither `reload` commands nor `reload_signal`"
+                ))
+            }
+        };
+
+        progress!("  -> reloading {rl}/{name} service...");
+        let res = match signal {
+            Some((pid, sig)) => report(&service, send_signal(pid, sig)),
+            None => exec_service(&service, ExecType::Reload),
+        };
+
+        // the service keeps running even if it has failed to reload
+        let record = self.record(&rl, name);
+        let res = match res {
+            Ok(0) => Ok(()),
+            Ok(code) => Err(anyhow!("service {name} returned {code}")),
+            Err(why) => Err(why),
+        };
+        if let Err(why) = &res {
+            record.failure = Some(format!("reload failed: {why}"));
+        }
+        self.save_state();
+
+        res
+    }
+
+    /// Returns information about the `name` service
+    pub fn service_status(&self, name: &str) -> Result<ServiceRecord> {
+        let rl = self.service_runlevel(name)?;
+
+        Ok(self
+            .services
+            .iter()
+            .find(|srv| srv.rl == rl && srv.name == name)
+            .cloned()
+            .unwrap_or_else(|| ServiceRecord::new(&rl, name)))
+    }
+
+    /// Handles the exit of a child process reaped by init. Returns `false`
+    /// if it is not the main process of any service
+    pub fn reap(&mut self, status: WaitStatus) -> bool {
+        let (pid, code) = match status {
+            WaitStatus::Exited(pid, code) => (pid, code),
+            WaitStatus::Signaled(pid, sig, _) => (pid, 128 + sig as i32),
+            _ => return false,
+        };
+
+        if let Some(check) = self
+            .checks
+            .iter_mut()
+            .find(|check| check.probe.as_ref().and_then(Probe::pid) == Some(pid.as_raw() as u32))
+        {
+            let (rl, name) = (check.rl.clone(), check.name.clone());
+            let res = check
+                .probe
+                .take()
+                .map_or(Ok(()), |probe| probe.exited(status));
+            self.checked(&rl, &name, res);
+            return true;
+        }
+
+        let Some(record) = self
+            .services
+            .iter_mut()
+            .find(|srv| srv.pid == Some(pid.as_raw() as u32))
+        else {
+            return false;
+        };
+
+        record.pid = None;
+        let (rl, name) = (record.rl.clone(), record.name.clone());
+        if let Some(boot) = &mut self.boot {
+            boot.exit(&name);
+        }
+        self.checks
+            .retain(|check| check.rl != rl || check.name != name);
+
+        let record = self.record(&rl, &name);
+
+        // the main process is expected to exit when the service is stopped
+        if record.is_running() {
+            record.last_exit = Some(code);
+            let res = match status {
+                WaitStatus::Exited(_, 0) => record.set_state(ServiceState::exited),
+                WaitStatus::Signaled(_, sig, _) => {
+                    record.fail(format!("main process killed by {}", sig.as_str()))
+                }
+                _ => record.fail(format!("main process exited with code {code}")),
+            };
+
+            match res {
+                Ok(()) if record.state == ServiceState::failed => eprintln!(
+                    "init: service {}/{} failed: {}",
+                    &record.rl,
+                    &record.name,
+                    record.failure.as_deref().unwrap_or_default()
+                ),
+                Ok(()) => {}
+                Err(why) => eprintln!("init: {why}"),
+            }
+
+            let failed = record.state == ServiceState::failed;
+            self.auto_restart(&rl, &name, failed);
+        }
+
+        self.save_state();
+        true
+    }
+
+    /// Runs the health checks that are due. Services that have failed
+    /// `threshold` checks in a row become unhealthy and are restarted
+    /// according to their restart policy
+    pub fn check_health(&mut self) {
+        let now = Instant::now();
+        let mut results = vec![];
+
+        for check in self.checks.iter_mut() {
+            let Some(health) = &check.service.health else {
+                continue;
+            };
+
+            if check.probe.is_none() && check.next <= now {
+                let interval = health.interval.unwrap_or(health::DEFAULT_INTERVAL);
+                check.next = now + Duration::from_secs(interval);
+
+                match health::start(&check.service, health) {
+                    Ok(probe) => check.probe = Some(probe),
+                    Err(why) => results.push((check.rl.clone(), check.name.clone(), Err(why))),
+                }
+            }
+
+            if let Some(res) = check.probe.as_mut().and_then(|probe| probe.poll()) {
+                check.probe = None;
+                results.push((check.rl.clone(), check.name.clone(), res));
+            }
+        }
+
+        for (rl, name, res) in results {
+            self.checked(&rl, &name, res);
+        }
+    }
+
+    /// Records the result of the health check of the service
+    fn checked(&mut self, rl: &str, name: &str, res: Result<()>) {
+        let Some(threshold) = self
+            .checks
+            .iter()
+            .find(|check| check.rl == rl && check.name == name)
+            .and_then(|check| check.service.health.as_ref())
+            .map(|health| health.threshold.unwrap_or(health::DEFAULT_THRESHOLD))
+        else {
+            return;
+        };
+
+        let record = self.record(rl, name);
+        match res {
+            Ok(()) => {
+                record.failed_checks = 0;
+                if record.state == ServiceState::unhealthy {
+                    eprintln!("init: service {rl}/{name} is healthy again");
+                    if record.set_state(ServiceState::running).is_ok() {
+                        self.save_state();
+                    }
+                }
+            }
+            Err(why) => {
+                record.failed_checks += 1;
+
+                if record.failed_checks >= threshold && record.state == ServiceState::running {
+                    eprintln!("init: service {rl}/{name} is unhealthy: {why}");
+                    if record.set_state(ServiceState::unhealthy).is_ok() {
+                        record.failure = Some(format!("health check failed: {why}"));
+                        self.save_state();
+                        self.auto_restart(rl, name, true);
+                        return;
+                    }
+                }
+                self.save_state();
+            }
+        }
+    }
+
+    /// Enters the emergency mode and then continues or reboots the system
+    pub fn emergency(&mut self, reason: &str) {
+        let step = Step::Emergency {
+            reason: reason.to_string(),
+        };
+        if self.planned(step) {
+            return;
+        }
+
+        let conf = self.conf.emergency.clone().unwrap_or_default();
+
+        if let EmergencyExit::reboot = emergency::run(&self.paths, &conf, reason) {
+            if let Err(why) = self.shutdown(RebootMode::RB_AUTOBOOT) {
+                eprintln!("init: reboot error: {why}");
+            }
+        }
+    }
+
+    /// Mounts the API filesystems and the filesystems from `/etc/fstab`
+    /// that are not mounted yet. Mounts are reported as services of the
+    /// `early` pseudo-runlevel
+    fn early_mount(&mut self) {
+        let conf = self.conf.early_mount.clone().unwrap_or_default();
+        if !conf.enabled.unwrap_or(true) {
+            return;
+        }
+
+        progress!("\nMounting API filesystems...\n");
+        for point in conf.mount.unwrap_or_else(mount::default_mounts) {
+            let step = Step::Mount {
+                source: point.source.clone().unwrap_or(point.fstype.clone()),
+                target: point.target.clone(),
+                fstype: point.fstype.clone(),
+                options: point.options.clone().unwrap_or("defaults".to_string()),
+            };
+            let _ = self.mount(step, &point.target, |paths| mount::mount(paths, &point));
+        }
+
+        if conf.fstab.unwrap_or(true) {
+            self.mount_fstab();
+        }
+    }
+
+    /// Mounts the filesystems from `/etc/fstab` except the root one and
+    /// the `noauto` ones. A failure of the filesystem without `nofail`
+    /// enters the emergency mode
+    fn mount_fstab(&mut self) {
+        let entries = match fstab::read(&self.paths) {
+            Ok(entries) if entries.is_empty() => return,
+            Ok(entries) => entries,
+            Err(why) => {
+                eprintln!("init: error: {why}");
+                return;
+            }
+        };
+        let order = match fstab::mount_order(&entries) {
+            Ok(order) => order,
+            Err(why) => {
+                self.emergency(&format!("/etc/fstab: {why}"));
+                return;
+            }
+        };
+
+        if let Some(root) = entries.iter().find(|entry| entry.target == "/") {
+            self.mount_root(root);
+        }
+
+        progress!("\nMounting filesystems from /etc/fstab...\n");
+        for entry in order {
+            if entry.target == "/" || entry.has_option("noauto") {
+                continue;
+            }
+            if !self.is_mounted(&entry.target) && !self.check_filesystem(entry) {
+                continue;
+            }
+
+            let step = Step::Mount {
+                source: entry.spec.clone(),
+                target: entry.target.clone(),
+                fstype: entry.fstype.clone(),
+                options: entry.options.clone(),
+            };
+            let res = self.mount(step, &entry.target, |paths| {
+                fstab::mount_entry(paths, entry)
+            });
+
+            if res.is_err() && !entry.has_option("nofail") {
+                self.emergency(&format!("failed to mount {}", &entry.target));
+            }
+        }
+    }
+
+    /// Checks the root filesystem while it is mounted read-only and
+    /// remounts it with its options from `/etc/fstab`
+    fn mount_root(&mut self, root: &FstabEntry) {
+        progress!("\nChecking the root filesystem...\n");
+
+        // checking the filesystem mounted read-write would damage it
+        let is_rw = mount::mountinfo(&self.paths)
+            .unwrap_or_default()
+            .iter()
+            .any(|info| info.target == "/" && info.options.split(',').any(|opt| opt == "rw"));
+        match is_rw {
+            true => progress!("  -> / is mounted read-write, the check is skipped\n"),
+            false => {
+                self.check_filesystem(root);
+            }
+        }
+
+        let options = match root.mount_options() {
+            options if options.is_empty() => "defaults".to_string(),
+            options => options,
+        };
+        progress!("  -> remounting / ({options})...");
+        let step = Step::Remount {
+            target: "/".to_string(),
+            options: options.clone(),
+        };
+        let res = match self.planned(step) {
+            true => Ok(()),
+            false => mount::remount(&self.paths, "/", &options),
+        };
+
+        match res {
+            Ok(()) => progress!("ok\n"),
+            Err(why) => {
+                eprintln!("ERROR: {why}");
+                self.emergency(&why.to_string());
+            }
+        }
+    }
+
+    /// Checks the filesystem of the entry if it has a non-zero pass number.
+    /// Returns whether the filesystem should be mounted
+    ///
+    /// The system is rebooted if the checking program requires it. If the
+    /// errors are left uncorrected, the emergency mode is entered, or the
+    /// filesystem is not mounted if it has the `nofail` option
+    fn check_filesystem(&mut self, entry: &FstabEntry) -> bool {
+        let Some(conf) = self.conf.fsck.clone() else {
+            return true;
+        };
+        let mode = self.opts.fsck.or(conf.mode).unwrap_or_default();
+        if !fsck::needs_check(&conf, mode, entry) {
+            return true;
+        }
+
+        let device = match self.plan.is_some() {
+            true => fstab::resolve_source(&self.paths, &entry.spec).unwrap_or(entry.spec.clone()),
+            false => match fstab::wait_source(&self.paths, entry) {
+                Ok(device) => device,
+                // the mount fails and reports the error
+                Err(_) => return true,
+            },
+        };
+        let argv = fsck::command(&conf, mode, entry, &device);
+
+        let name = format!("fsck@{}", mount::unit_name(&entry.target));
+        progress!("  -> checking {device}...");
+        let _ = self
+            .record(EARLY_RUNLEVEL, &name)
+            .set_state(ServiceState::starting);
+
+        let step = Step::Fsck {
+            target: entry.target.clone(),
+            argv: argv.clone(),
+        };
+        let res = match self.planned(step) {
+            true => Ok(0),
+            false => {
+                if let Some(boot) = &mut self.boot {
+                    boot.start(EARLY_RUNLEVEL, &name);
+                }
+                fsck::run(&argv)
+            }
+        };
+
+        let outcome = match &res {
+            Ok(code) => Outcome::from_code(*code),
+            Err(_) => Outcome::Failed,
+        };
+        if let Some(boot) = &mut self.boot {
+            boot.started(&name, outcome != Outcome::Failed);
+        }
+
+        if outcome == Outcome::Failed {
+            let why = match res {
+                Ok(code) => anyhow!("uncorrected errors (exit code {code})"),
+                Err(why) => why,
+            };
+            eprintln!("ERROR: {why}");
+            let _ = self.finish(
+                EARLY_RUNLEVEL,
+                &name,
+                Err(anyhow!("{why}")),
+                ServiceState::running,
+            );
+
+            if entry.has_option("nofail") {
+                eprintln!("init: warning: {} is not mounted", &entry.target);
+                return false;
+            }
+            self.emergency(&format!("filesystem check of {} failed: {why}", &device));
+            return true;
+        }
+
+        match outcome {
+            Outcome::Corrected => progress!("errors corrected\n"),
+            Outcome::Reboot => progress!("errors corrected, reboot required\n"),
+            _ => progress!("ok\n"),
+        }
+        let _ = self.finish(EARLY_RUNLEVEL, &name, Ok(0), ServiceState::running);
+        let _ = self
+            .record(EARLY_RUNLEVEL, &name)
+            .set_state(ServiceState::exited);
+
+        if outcome == Outcome::Reboot {
+            if let Err(why) = self.shutdown(RebootMode::RB_AUTOBOOT) {
+                eprintln!("init: reboot error: {why}");
+            }
+        }
+
+        true
+    }
+
+    fn is_mounted(&self, target: &str) -> bool {
+        mount::mountinfo(&self.paths)
+            .unwrap_or_default()
+            .iter()
+            .any(|info| info.target == target)
+    }
+
+    /// Mounts the filesystem on the `target` unless it is already mounted
+    fn mount<F>(&mut self, step: Step, target: &str, mount: F) -> Result<()>
+    where
+        F: FnOnce(&Paths) -> Result<()>,
+    {
+        // the table is read every time as it becomes available only after
+        // `/proc` is mounted
+        match mount::mountinfo(&self.paths) {
+            Ok(mounted) if mounted.iter().any(|info| info.target == target) => {
+                progress!("  -> {target} is already mounted\n");
+                return Ok(());
+            }
+            Ok(_) => {}
+            Err(why) => eprintln!("init: warning: {why}"),
+        }
+
+        progress!("  -> mounting {target}...");
+        self.run_early(vec![step], &mount::unit_name(target), mount)
+    }
+
+    /// Performs the action of the `early` pseudo-runlevel (mounting,
+    /// activating swap) reported as the service `name`
+    fn run_early<F>(&mut self, steps: Vec<Step>, name: &str, action: F) -> Result<()>
+    where
+        F: FnOnce(&Paths) -> Result<()>,
+    {
+        let _ = self
+            .record(EARLY_RUNLEVEL, name)
+            .set_state(ServiceState::starting);
+
+        let mut planned = false;
+        for step in steps {
+            planned = self.planned(step);
+        }
+        let res = match planned {
+            true => Ok(()),
+            false => {
+                if let Some(boot) = &mut self.boot {
+                    boot.start(EARLY_RUNLEVEL, name);
+                }
+                action(&self.paths)
+            }
+        };
+
+        match &res {
+            Ok(()) => progress!("ok\n"),
+            Err(why) => eprintln!("ERROR: {why}"),
+        }
+        if let Some(boot) = &mut self.boot {
+            boot.started(name, res.is_ok());
+        }
+        let _ = self.finish(
+            EARLY_RUNLEVEL,
+            name,
+            res.as_ref().map(|_| 0).map_err(|why| anyhow!("{why}")),
+            ServiceState::running,
+        );
+
+        res
+    }
+
+    /// Unmounts the filesystems from `/etc/fstab` in the reverse order of
+    /// their mounting
+    fn unmount_fstab(&mut self) {
+        let entries = fstab::read(&self.paths).unwrap_or_default();
+        let order = match fstab::unmount_order(&entries) {
+            Ok(order) => order,
+            Err(why) => {
+                eprintln!("init: error: /etc/fstab: {why}");
+                return;
+            }
+        };
+        let mounted = mount::mountinfo(&self.paths).unwrap_or_default();
+
+        for entry in order {
+            // in dry run the filesystems are considered mounted at boot
+            let is_mounted = match self.plan.is_some() {
+                true => !entry.has_option("noauto"),
+                false => mounted.iter().any(|info| info.target == entry.target),
+            };
+            if !is_mounted {
+                continue;
+            }
+
+            let step = Step::Unmount {
+                target: entry.target.clone(),
+            };
+            progress!("  -> unmounting {}...", &entry.target);
+            let res = match self.planned(step) {
+                true => Ok(()),
+                false => mount::unmount(&self.paths, &entry.target),
+            };
+            match res {
+                Ok(()) => progress!("ok\n"),
+                Err(why) => eprintln!("ERROR: {why}"),
+            }
+
+            self.stop_early(&mount::unit_name(&entry.target));
+        }
+    }
+
+    /// Remounts `/` read-only, since it can't be unmounted, so that it is
+    /// clean at the next boot
+    fn remount_root_ro(&mut self) {
+        progress!("  -> remounting / read-only...");
+        let step = Step::Remount {
+            target: "/".to_string(),
+            options: "ro".to_string(),
+        };
+        let res = match self.planned(step) {
+            true => Ok(()),
+            false => mount::remount(&self.paths, "/", "ro"),
+        };
+        match res {
+            Ok(()) => progress!("ok\n"),
+            Err(why) => eprintln!("ERROR: {why}"),
+        }
+    }
+
+    /// Loads the kernel modules from `/etc/modules`,
+    /// `/etc/modules-load.d/*.conf` and the `[modules]` section that are not
+    /// loaded yet. Failures are not fatal
+    fn load_modules(&mut self) {
+        let Some(conf) = self.conf.modules.clone() else {
+            return;
+        };
+        if !conf.enabled.unwrap_or(true) {
+            return;
+        }
+
+        let requests = match modules::requested(&self.paths, &conf) {
+            Ok(requests) => requests,
+            Err(why) => {
+                eprintln!("init: error: failed to read the list of modules: {why}");
+                return;
+            }
+        };
+        if requests.is_empty() {
+            return;
+        }
+
+        progress!("\nLoading kernel modules...\n");
+        // `modprobe` resolves the dependencies itself
+        let db = match conf.modprobe {
+            Some(_) => Ok(ModuleDb::default()),
+            None => ModuleDb::load(&self.paths),
+        };
+        // the dependencies loaded for the previous modules are skipped
+        let mut loaded = modules::loaded(&self.paths);
+        for req in requests {
+            let name = modules::module_name(&req.name);
+            if loaded.contains(&name) {
+                progress!("  -> {name} is already loaded\n");
+                continue;
+            }
+            if db.as_ref().is_ok_and(|db| db.is_builtin(&name)) {
+                progress!("  -> {name} is built into the kernel\n");
+                continue;
+            }
+
+            progress!("  -> loading {name}...");
+            let unit = format!("{name}.module");
+            let res = match (&conf.modprobe, &db) {
+                (Some(modprobe), _) => match modules::modprobe_command(modprobe, &req) {
+                    Ok(argv) => {
+                        let step = Step::Modprobe {
+                            module: name.clone(),
+                            argv: argv.clone(),
+                        };
+                        self.run_early(vec![step], &unit, |_| modules::modprobe(&argv))
+                    }
+                    Err(why) => self.run_early(vec![], &unit, |_| Err(why)),
+                },
+                (None, Ok(db)) => match db.load_order(&req.name) {
+                    Ok(mut files) => {
+                        files.retain(|file| {
+                            !loaded.contains(&modules::module_name(&file.to_string_lossy()))
+                        });
+                        // the parameters are passed to the requested module only
+                        let params = |idx: usize| match idx + 1 == files.len() {
+                            true => req.params.clone(),
+                            false => String::new(),
+                        };
+                        let steps = files
+                            .iter()
+                            .enumerate()
+                            .map(|(idx, file)| Step::Insmod {
+                                module: modules::module_name(&file.to_string_lossy()),
+                                file: file.display().to_string(),
+                                params: params(idx),
+                            })
+                            .collect();
+                        let res = self.run_early(steps, &unit, |paths| {
+                            files.iter().enumerate().try_for_each(|(idx, file)| {
+                                modules::insmod(paths, file, &params(idx))
+                            })
+                        });
+                        if res.is_ok() {
+                            loaded.extend(
+                                files
+                                    .iter()
+                                    .map(|file| modules::module_name(&file.to_string_lossy())),
+                            );
+                        }
+                        res
+                    }
+                    Err(why) => self.run_early(vec![], &unit, |_| Err(why)),
+                },
+                (None, Err(why)) => self.run_early(vec![], &unit, |_| Err(anyhow!("{why}"))),
+            };
+            if res.is_ok() {
+                loaded.insert(name);
+            }
+        }
+    }
+
+    /// Activates the swap areas from `/etc/fstab` and the `[swap]` section
+    /// that are not active yet. Failures are not fatal
+    fn activate_swap(&mut self) {
+        let Some(conf) = self.conf.swap.clone() else {
+            return;
+        };
+        if !conf.enabled.unwrap_or(true) {
+            return;
+        }
+
+        // errors of `/etc/fstab` are reported when it is mounted
+        let entries = fstab::read(&self.paths).unwrap_or_default();
+        let areas = swap::areas(&entries, &conf);
+        if areas.is_empty() {
+            return;
+        }
+
+        progress!("\nActivating swap...\n");
+        let active = swap::active(&self.paths).unwrap_or_default();
+        for area in areas {
+            let device =
+                fstab::resolve_source(&self.paths, &area.source).unwrap_or(area.source.clone());
+            if active.contains(&device) {
+                progress!("  -> {device} is already active\n");
+                continue;
+            }
+
+            let mut steps = vec![];
+            match &area.kind {
+                SwapKind::Device => {}
+                SwapKind::File { size } => {
+                    if !self.paths.resolve(&device).exists() {
+                        steps.push(Step::Mkswap {
+                            path: device.clone(),
+                            size: size.clone(),
+                        });
+                    }
+                }
+                SwapKind::Zram { size, algorithm } => steps.push(Step::Zram {
+                    device: device.clone(),
+                    size: size.clone(),
+                    algorithm: algorithm.clone(),
+                }),
+            }
+            steps.push(Step::Swapon {
+                source: device.clone(),
+                priority: area.priority,
+            });
+
+            progress!("  -> activating {device}...");
+            let _ = self.run_early(steps, &swap::unit_name(&device), |paths| {
+                swap::activate(paths, &area, &device)
+            });
+        }
+    }
+
+    /// Applies the lists from `/etc/init/tmpfiles.d`, each reported as the
+    /// `NAME.tmpfiles` service. Failures are not fatal
+    fn create_tmpfiles(&mut self) {
+        let lists = match tmpfiles::read(&self.paths) {
+            Ok(lists) => lists,
+            Err(why) => {
+                eprintln!("init: error: {why}");
+                return;
+            }
+        };
+        if lists.is_empty() {
+            return;
+        }
+
+        progress!("\nCreating runtime files...\n");
+        for (name, entries) in lists {
+            let steps = entries
+                .iter()
+                .map(|entry| Step::Path {
+                    entry: entry.clone(),
+                })
+                .collect();
+
+            progress!("  -> applying {name}.conf...");
+            let _ = self.run_early(steps, &format!("{name}.tmpfiles"), |paths| {
+                tmpfiles::apply_all(paths, &entries)
+            });
+        }
+    }
+
+    /// Starts the device manager and handles the uevents of the devices
+    /// present at boot
+    fn start_devices(&mut self) {
+        if self.plan.is_some() || !self.paths.devices_conf().exists() {
+            return;
+        }
+
+        progress!("\nStarting the device manager...\n");
+        progress!("  -> coldplugging devices...");
+        match DeviceManager::open(&self.paths, self.conf.modules.as_ref()) {
+            Ok(mut devices) => {
+                devices.coldplug(&self.paths);
+                self.devices = Some(devices);
+                progress!("ok\n");
+            }
+            Err(why) => eprintln!("ERROR: {why}"),
+        }
+    }
+
+    /// Socket of the device manager to wait for uevents on
+    pub fn devices_fd(&self) -> Option<BorrowedFd<'_>> {
+        self.devices.as_ref().map(|devices| devices.as_fd())
+    }
+
+    /// Handles the new uevents and starts the services requested by the
+    /// device rules. The services requested at boot are started here
+    /// after the boot has finished
+    pub fn handle_devices(&mut self) {
+        let Some(devices) = &mut self.devices else {
+            return;
+        };
+        devices.receive(&self.paths);
+
+        for name in devices.take_services() {
+            let running = self
+                .service_runlevel(&name)
+                .is_ok_and(|rl| self.is_running(&rl, &name));
+            if running {
+                continue;
+            }
+
+            if let Err(why) = self.start_service(&name) {
+                eprintln!("init: error: failed to start {name} for the device: {why}");
+            }
+        }
+    }
+
+    /// Deactivates all active swap areas before the filesystems are
+    /// unmounted
+    fn deactivate_swap(&mut self) {
+        let sources: Vec<String> = match &self.plan {
+            // the areas activated in the simulated boot
+            Some(plan) => plan
+                .iter()
+                .filter_map(|step| match step {
+                    Step::Swapon { source, .. } => Some(source.clone()),
+                    _ => None,
+                })
+                .collect(),
+            None => swap::active(&self.paths).unwrap_or_default(),
+        };
+        if sources.is_empty() {
+            return;
+        }
+
+        progress!("\nDeactivating swap...\n");
+        for source in sources {
+            progress!("  -> deactivating {source}...");
+            let step = Step::Swapoff {
+                source: source.clone(),
+            };
+            let res = match self.planned(step) {
+                true => Ok(()),
+                false => swap::deactivate(&self.paths, &source).and_then(|_| {
+                    match source == ZRAM_DEVICE {
+                        true => swap::reset_zram(&self.paths),
+                        false => Ok(()),
+                    }
+                }),
+            };
+            match res {
+                Ok(()) => progress!("ok\n"),
+                Err(why) => eprintln!("ERROR: {why}"),
+            }
+
+            self.stop_early(&swap::unit_name(&source));
+        }
+    }
+
+    /// Marks the running service of the `early` pseudo-runlevel stopped
+    fn stop_early(&mut self, name: &str) {
+        if let Some(record) = self
+            .services
+            .iter_mut()
+            .find(|srv| srv.rl == EARLY_RUNLEVEL && srv.name == name && srv.is_running())
+        {
+            let _ = record.set_state(ServiceState::stopping);
+            let _ = record.set_state(ServiceState::inactive);
+        }
+    }
+
+    fn enter_runlevel(&mut self, idx: usize) {
+        let rl = self.conf.runlevel[idx].clone();
+        progress!("\nSwitching to the {} runlevel...\n", &rl.dir);
+        self.current = Some(idx);
+
+        if let Some(boot) = &mut self.boot {
+            boot.enter_runlevel(&rl.dir);
+        }
+        self.planned(Step::Runlevel {
+            runlevel: rl.dir.clone(),
+            action: rl.action.unwrap_or_default(),
+        });
+
+        if let Err(why) = rl.enter(self) {
+            eprintln!(
+                "init: error: failed to enter the {} runlevel: {why}",
+                &rl.dir
+            );
+        }
+    }
+
+    fn service_runlevel(&self, name: &str) -> Result<String> {
+        self.conf
+            .find_service(name)
+            .map(|rl| rl.dir.clone())
+            .ok_or_else(|| anyhow!("service {name} not found"))
+    }
+
+    fn is_running(&self, rl: &str, name: &str) -> bool {
+        self.services
+            .iter()
+            .any(|srv| srv.rl == rl && srv.name == name && srv.is_running())
+    }
+
+    /// Returns the state of the service, adding it to the list of
+    /// services if it has never been started
+    fn record(&mut self, rl: &str, name: &str) -> &mut ServiceRecord {
+        let idx = match self
+            .services
+            .iter()
+            .position(|srv| srv.rl == rl && srv.name == name)
+        {
+            Some(idx) => idx,
+            None => {
+                self.services.push(ServiceRecord::new(rl, name));
+                self.services.len() - 1
+            }
+        };
+
+        &mut self.services[idx]
+    }
+
+    /// Starts the services required and wanted by the service and then the
+    /// service itself. Services that have already been started in this
+    /// `pass` are not started again
+    fn start_with_deps(&mut self, rl: &str, name: &str, pass: &mut StartPass) -> Result<()> {
+        if let Some(started) = pass.started.get(name) {
+            return match started {
+                true => Ok(()),
+                false => Err(anyhow!("service {name} has failed to start")),
+            };
+        }
+        if pass.chain.iter().any(|srv| srv == name) {
+            return Err(anyhow!(
+                "dependency cycle: {} -> {name}",
+                pass.chain.join(" -> ")
+            ));
+        }
+
+        // errors of the service file are reported by `launch()`
+        let (requires, wants) = match Service::new(&self.paths, name, rl) {
+            Ok(service) => (
+                service.init.requires.unwrap_or_default(),
+                service.init.wants.unwrap_or_default(),
+            ),
+            Err(_) => (vec![], vec![]),
+        };
+
+        pass.chain.push(name.to_string());
+        let mut res = Ok(());
+        for dep in &requires {
+            if let Err(why) = self.start_dep(rl, dep, true, pass) {
+                res = Err(anyhow!("required service {dep} failed: {why}"));
+                break;
+            }
+        }
+        if res.is_ok() {
+            for dep in &wants {
+                let _ = self.start_dep(rl, dep, false, pass);
+            }
+        }
+        pass.chain.pop();
+
+        let res = match res {
+            Ok(()) => self.launch(rl, name),
+            Err(why) => {
+                self.fail_start(rl, name, &why);
+                Err(why)
+            }
+        };
+        pass.started.insert(name.to_string(), res.is_ok());
+
+        res
+    }
+
+    /// Starts the `dep` dependency of a service of the `rl` runlevel if it
+    /// is not running yet. Dependencies of other runlevels are never started:
+    /// they must be already running. Required dependencies skipped with
+    /// `init.skip` are failed
+    fn start_dep(
+        &mut self,
+        rl: &str,
+        dep: &str,
+        required: bool,
+        pass: &mut StartPass,
+    ) -> Result<()> {
+        let dep_rl = self.service_runlevel(dep)?;
+
+        // services skipped because of their start conditions don't fail
+        // the services depending on them
+        let skipped = self
+            .services
+            .iter()
+            .any(|srv| srv.name == dep && srv.state == ServiceState::skipped);
+        if self.is_running(&dep_rl, dep) || skipped {
+            return Ok(());
+        }
+        if self.opts.skip.iter().any(|srv| srv == dep) {
+            return match required {
+                true => Err(anyhow!("service {dep} is skipped (init.skip)")),
+                false => Ok(()),
+            };
+        }
+        if dep_rl != rl {
+            return Err(anyhow!(
+                "service {dep} of the {dep_rl} runlevel is not running"
+            ));
+        }
+
+        self.start_with_deps(rl, dep, pass)
+    }
+
+    /// Marks the service failed without running its start commands
+    fn fail_start(&mut self, rl: &str, name: &str, why: &anyhow::Error) {
+        progress!("  -> running {name} service...");
+        eprintln!("ERROR: {why}");
+
+        self.planned(Step::Fail {
+            runlevel: rl.to_string(),
+            service: name.to_string(),
+            reason: why.to_string(),
+        });
+
+        self.seq += 1;
+        let seq = self.seq;
+        let record = self.record(rl, name);
+        record.seq = seq;
+        if record.set_state(ServiceState::starting).is_ok() {
+            let _ = record.fail(why);
+        }
+
+        if let Some(boot) = &mut self.boot {
+            boot.start(rl, name);
+            boot.started(name, false);
+        }
+    }
+
+    /// Returns the start condition of the service that is not met. Errors
+    /// in the service file are reported by the start itself
+    fn unmet_condition(&mut self, rl: &str, name: &str) -> Option<String> {
+        let service = Service::new(&self.paths, name, rl).ok()?;
+        let conds = service.conditions.as_ref()?;
+        let host = self
+            .host
+            .get_or_insert_with(|| Host::detect(&self.paths, &self.opts));
+
+        conditions::unmet(&self.paths, conds, host)
+    }
+
+    /// Marks the service skipped because of its start conditions. This is
+    /// not an error, the services depending on it are started anyway
+    fn skip_start(&mut self, rl: &str, name: &str, reason: &str) {
+        progress!("skipped ({reason})\n");
+
+        self.planned(Step::Skip {
+            runlevel: rl.to_string(),
+            service: name.to_string(),
+            reason: format!("condition {reason}"),
+        });
+
+        if let Some(boot) = &mut self.boot {
+            boot.start(rl, name);
+            boot.skipped(name);
+        }
+        let _ = self.record(rl, name).set_state(ServiceState::skipped);
+    }
+
+    /// Runs the start commands of the service
+    fn launch(&mut self, rl: &str, name: &str) -> Result<()> {
+        progress!("  -> running {name} service...");
+
+        self.seq += 1;
+        let seq = self.seq;
+        let record = self.record(rl, name);
+        record.seq = seq;
+        record.set_state(ServiceState::starting)?;
+
+        if let Some(reason) = self.unmet_condition(rl, name) {
+            self.skip_start(rl, name, &reason);
+            return Ok(());
+        }
+        if self.plan.is_some() {
+            return self.plan_launch(rl, name);
+        }
+        if let Some(boot) = &mut self.boot {
+            boot.start(rl, name);
+        }
+
+        let mut pid = None;
+        let (service, res) = match Service::new(&self.paths, name, rl) {
+            Ok(service) => {
+                let paths = service.paths.as_deref().unwrap_or_default();
+                let res = tmpfiles::apply_all(&self.paths, paths).and_then(|_| {
+                    match service.service.builtin {
+                        Some(task) => builtin::run(&self.paths, &self.conf, task).map(|_| 0),
+                        None => ready::start(&self.paths, &service, name).map(|started| {
+                            pid = started.pid;
+                            started.code
+                        }),
+                    }
+                });
+                let res = report(&service, res);
+                (Some(service), res)
+            }
+            Err(why) => {
+                println!("ERROR: {why}");
+                (None, Err(why))
+            }
+        };
+
+        let res = self.finish(rl, name, res, ServiceState::running);
+        if let Some(boot) = &mut self.boot {
+            boot.started(name, res.is_ok());
+        }
+        res?;
+
+        let record = self.record(rl, name);
+        record.pid = pid;
+        record.failed_checks = 0;
+
+        if let Some(service) = service.filter(|srv| srv.health.is_some()) {
+            let interval = service
+                .health
+                .as_ref()
+                .and_then(|health| health.interval)
+                .unwrap_or(health::DEFAULT_INTERVAL);
+
+            self.checks.push(Check {
+                rl: rl.to_string(),
+                name: name.to_string(),
+                service,
+                next: Instant::now() + Duration::from_secs(interval),
+                probe: None,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Records the start of the service in the dry-run mode
+    fn plan_launch(&mut self, rl: &str, name: &str) -> Result<()> {
+        let step = Service::new(&self.paths, name, rl).and_then(|service| {
+            let paths = service.paths.clone().unwrap_or_default();
+            for entry in &paths {
+                tmpfiles::validate(&self.paths, entry)?;
+            }
+
+            let step = Step::Start {
+                runlevel: rl.to_string(),
+                service: name.to_string(),
+                commands: plan::start_commands(&self.paths, &service, name)?,
+                ready: service.service.ready.unwrap_or_default(),
+                builtin: service.service.builtin,
+            };
+            Ok((paths, step))
+        });
+
+        match step {
+            Ok((paths, step)) => {
+                for entry in paths {
+                    self.planned(Step::Path { entry });
+                }
+                self.planned(step);
+                self.finish(rl, name, Ok(0), ServiceState::running)
+            }
+            Err(why) => {
+                self.planned(Step::Fail {
+                    runlevel: rl.to_string(),
+                    service: name.to_string(),
+                    reason: why.to_string(),
+                });
+                self.finish(rl, name, Err(why), ServiceState::running)
+            }
+        }
+    }
+
+    /// Records the stop of the service in the dry-run mode. Services
+    /// without stop commands whose main process is tracked get `SIGTERM`
+    fn plan_halt(&mut self, rl: &str, name: &str) -> Result<()> {
+        let step = Service::new(&self.paths, name, rl).and_then(|service| {
+            let stop = service.service.stop.as_deref();
+            let is_daemon = service.service.ready.unwrap_or_default() != Ready::Exit;
+
+            Ok(Step::Stop {
+                runlevel: rl.to_string(),
+                service: name.to_string(),
+                commands: plan::planned_commands(stop.unwrap_or_default())?,
+                signal: (stop.is_none() && is_daemon).then(|| "SIGTERM".to_string()),
+            })
+        });
+
+        let res = step.map(|step| {
+            self.planned(step);
+            0
+        });
+        self.finish(rl, name, res, ServiceState::inactive)
+    }
+
+    /// Records the `step` if the supervisor is in the dry-run mode.
+    /// Returns `false` if the step has to be performed
+    fn planned(&mut self, step: Step) -> bool {
+        match &mut self.plan {
+            Some(plan) => {
+                plan.push(step);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Restarts the service whose main process has exited or which has
+    /// become unhealthy (`failed = true`) if its restart policy allows it
+    fn auto_restart(&mut self, rl: &str, name: &str, failed: bool) {
+        let Ok(service) = Service::new(&self.paths, name, rl) else {
+            return;
+        };
+
+        let restart = match service.service.restart_policy.unwrap_or_default() {
+            RestartPolicy::never => false,
+            RestartPolicy::on_failure => failed,
+            RestartPolicy::always => true,
+        };
+        if !restart {
+            return;
+        }
+
+        let max = service.service.max_restarts.unwrap_or(DEFAULT_MAX_RESTARTS);
+        let record = self.record(rl, name);
+        if record.restarts >= max {
+            eprintln!("init: service {rl}/{name} has been restarted {max} times, giving up");
+            return;
+        }
+        record.restarts += 1;
+
+        if record.is_running() {
+            let _ = self.halt(rl, name);
+        }
+        let _ = self.launch(rl, name);
+    }
+
+    /// Runs the stop commands of the running service
+    fn halt(&mut self, rl: &str, name: &str) -> Result<()> {
+        progress!("  -> stoping {rl}/{name} service...");
+        let record = self.record(rl, name);
+        record.set_state(ServiceState::stopping)?;
+        let pid = record.pid;
+
+        if self.plan.is_some() {
+            return self.plan_halt(rl, name);
+        }
+
+        let res = match (Service::new(&self.paths, name, rl), pid) {
+            // daemons tracked by init are terminated if there is no
+            // other way to stop them
+            (Ok(service), Some(pid)) if service.service.stop.is_none() => {
+                report(&service, send_signal(pid, Signal::SIGTERM))
+            }
+            (Ok(service), _) => exec_service(&service, ExecType::Stop),
+            (Err(why), _) => {
+                println!("ERROR: {why}");
+                Err(why)
+            }
+        };
+
+        self.checks
+            .retain(|check| check.rl != rl || check.name != name);
+        self.record(rl, name).pid = None;
+        if let Some(boot) = &mut self.boot {
+            boot.exit(name);
+        }
+
+        self.finish(rl, name, res, ServiceState::inactive)
+    }
+
+    /// Switches the service to the `next` state if its commands have been
+    /// completed successfully or to the `ServiceState::failed` otherwise
+    fn finish(&mut self, rl: &str, name: &str, res: Result<i32>, next: ServiceState) -> Result<()> {
+        let record = self.record(rl, name);
+
+        match res {
+            Ok(0) => {
+                record.last_exit = Some(0);
+                record.set_state(next)
+            }
+            Ok(code) => {
+                record.last_exit = Some(code);
+                record.fail(format!("non-zero return code ({code})"))?;
+                Err(anyhow!("service {name} returned {code}"))
+            }
+            Err(why) => {
+                record.fail(&why)?;
+                Err(why)
+            }
+        }
+    }
+
+    /// Stops the running services matching the `filter` in the reverse
+    /// order of their launch
+    fn stop_where<F: Fn(&ServiceRecord) -> bool>(&mut self, filter: F) {
+        let mut services: Vec<(u64, String, String)> = self
+            .services
+            .iter()
+            // the filesystems mounted by init are not services
+            .filter(|srv| srv.state.needs_stop() && srv.rl != EARLY_RUNLEVEL && filter(srv))
+            .map(|srv| (srv.seq, srv.rl.clone(), srv.name.clone()))
+            .collect();
+        services.sort_by_key(|(seq, _, _)| std::cmp::Reverse(*seq));
+
+        for (_, rl, name) in services {
+            let _ = self.halt(&rl, &name);
+        }
+    }
+
+    fn stop_runlevel(&mut self, idx: usize) {
+        let dir = self.conf.runlevel[idx].dir.clone();
+        progress!("\nLeaving the {dir} runlevel...\n");
+
+        self.stop_where(|srv| srv.rl == dir);
+    }
+
+    fn save_state(&self) {
+        if self.plan.is_some() {
+            return;
+        }
+
+        if let Err(why) = state::save(&self.paths, &self.services) {
+            eprintln!(
+                "init: failed to write the state of services to the {} ({why})!",
+                self.paths.state_dir().display()
+            );
+        }
+
+        if let Some(boot) = &self.boot {
+            if let Err(why) = boot.write(self.paths.boot_record()) {
+                eprintln!(
+                    "init: failed to write the boot timings to the {} ({why})!",
+                    self.paths.boot_record().display()
+                );
+            }
+        }
+    }
+}
+
+/// Whether the failure of the service must abort the boot
+fn is_critical(paths: &Paths, rl: &str, name: &str) -> bool {
+    Service::new(paths, name, rl).is_ok_and(|srv| matches!(srv.init.on_error, Some(OnError::abort)))
+}
+
+/// Sends the `sig` signal to the main process of the service
+fn send_signal(pid: u32, sig: Signal) -> Result<i32> {
+    kill(Pid::from_raw(pid as i32), sig)?;
+    Ok(0)
+}
+
+/// Runs the commands of the service and reports the result. Returns the
+/// return code of the last command
+pub(crate) fn exec_service(service: &Service, exec_type: ExecType) -> Result<i32> {
+    report(service, service.exec(exec_type))
+}
+
+/// Reports the result of the commands of the service
+fn report(service: &Service, res: Result<i32>) -> Result<i32> {
+    match res {
+        Ok(run) => {
+            if run == 0 {
+                progress!("ok\n");
+            } else {
+                on_error(
+                    &service.init.on_error,
+                    format!("non-zero return code ({run})"),
+                );
+            }
+            Ok(run)
+        }
+        Err(why) => {
+            on_error(&service.init.on_error, &why);
+            Err(why)
+        }
+    }
+}
+
+fn on_error<D: Display>(err: &Option<OnError>, err_txt: D) {
+    if let Some(err) = err {
+        match err {
+            OnError::ignore => progress!("ERROR\n"),
+            OnError::error => {
+                eprintln!("ERROR: {err_txt}");
+            }
+            OnError::abort => {
+                // emergency mode is entered by the caller
+                eprintln!("ERROR: {err_txt}");
+            }
+        }
+    } else {
+        on_error(&Some(OnError::default()), err_txt);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_root;
+    use crate::testutil::write;
+    use std::fs;
+
+    fn state(sv: &Supervisor, name: &str) -> ServiceState {
+        sv.service_status(name).unwrap().state
+    }
+
+    #[test]
+    fn boot_test() {
+        let root = temp_root("boot");
+        let paths = Paths::with_root(&root);
+        let flag = root.join("mounted");
+
+        write(
+            &paths,
+            "/etc/init/init.toml",
+            r#"
+final_runlevel = "rl2"
+
+[early_mount]
+enabled = false
+
+[[runlevel]]
+dir = "rl1"
+description = "Single user mode"
+services = ["mount", "broken"]
+
+[[runlevel]]
+dir = "rl2"
+description = "Multi user mode"
+services = ["daemon"]
+"#,
+        );
+        write(
+            &paths,
+            "/etc/init/rl1/mount.toml",
+            &format!(
+                "[service]\nstart = [\"/bin/touch {0}\"]\nstop = [\"/bin/rm {0}\"]\n\n\
+                 [init]\ndescription = \"mount\"\n",
+                flag.display()
+            ),
+        );
+        write(
+            &paths,
+            "/etc/init/rl1/broken.toml",
+            "[service]\nstart = [\"/bin/false\"]\n\n[init]\ndescription = \"broken\"\non_error = \"ignore\"\n",
+        );
+        write(
+            &paths,
+            "/etc/init/rl2/daemon.toml",
+            "[service]\nstart = [\"/bin/true\"]\n\n[init]\ndescription = \"daemon\"\nrequires = [\"mount\"]\n",
+        );
+
+        let conf = Config::parse(paths.master_conf()).unwrap();
+        let mut sv = Supervisor::new(conf, paths.clone());
+        sv.boot();
+
+        assert_eq!(sv.current(), Some("rl2"));
+        assert_eq!(state(&sv, "mount"), ServiceState::running);
+        assert_eq!(state(&sv, "broken"), ServiceState::failed);
+        assert_eq!(state(&sv, "daemon"), ServiceState::running);
+        assert!(flag.exists());
+        assert_eq!(state::load(&paths).unwrap().len(), 3);
+        let timings = crate::boot::load(&paths).unwrap();
+        assert_eq!(timings.runlevels.len(), 2);
+        assert!(timings.service("broken").unwrap().failed.is_some());
+        assert!(timings.finished.is_some());
+
+        sv.switch_runlevel("rl1").unwrap();
+        assert_eq!(state(&sv, "daemon"), ServiceState::inactive);
+        assert_eq!(state(&sv, "mount"), ServiceState::running);
+
+        sv.stop_all();
+        assert_eq!(state(&sv, "mount"), ServiceState::inactive);
+        assert!(!flag.exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn deps_test() {
+        let root = temp_root("deps");
+        let paths = Paths::with_root(&root);
+        let runs = root.join("runs");
+
+        write(
+            &paths,
+            "/etc/init/init.toml",
+            r#"
+final_runlevel = "rl2"
+
+[[runlevel]]
+dir = "rl1"
+description = "Single user mode"
+services = ["a", "b", "broken", "c", "d"]
+
+[[runlevel]]
+dir = "rl2"
+description = "Multi user mode"
+services = ["daemon"]
+"#,
+        );
+        let service = |name: &str, start: &str, deps: &str| {
+            write(
+                &paths,
+                &format!(
+                    "/etc/init/rl{}/{name}.toml",
+                    if name == "daemon" { 2 } else { 1 }
+                ),
+                &format!(
+                    "[service]\nstart = [\"{start}\"]\n\n[init]\ndescription = \"{name}\"\n\
+                     on_error = \"ignore\"\n{deps}\n"
+                ),
+            )
+        };
+        let broken = format!("/bin/sh -c 'echo broken >> {}; exit 1'", runs.display());
+        service("a", "/bin/true", "requires = [\"broken\"]");
+        service("b", "/bin/true", "requires = [\"broken\"]");
+        service("broken", &broken, "");
+        service("c", "/bin/true", "requires = [\"skipped\"]");
+        service("d", "/bin/true", "requires = [\"daemon\"]");
+        service("skipped", "/bin/true", "");
+        service("daemon", "/bin/true", "");
+
+        let conf = Config::parse(paths.master_conf()).unwrap();
+        let mut sv = Supervisor::new(conf, paths.clone());
+        sv.apply_boot_options(BootOptions::parse("init.skip=skipped"));
+        sv.switch_runlevel("rl1").unwrap();
+
+        // the failed dependency is started only once
+        assert_eq!(fs::read_to_string(&runs).unwrap(), "broken\n");
+        assert_eq!(state(&sv, "a"), ServiceState::failed);
+        assert_eq!(state(&sv, "b"), ServiceState::failed);
+        assert_eq!(state(&sv, "broken"), ServiceState::failed);
+        // required services skipped by `init.skip` are not satisfied
+        assert_eq!(state(&sv, "c"), ServiceState::failed);
+        // services of the later runlevels are not started early
+        assert_eq!(state(&sv, "d"), ServiceState::failed);
+        assert_eq!(state(&sv, "daemon"), ServiceState::inactive);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
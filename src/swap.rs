@@ -0,0 +1,428 @@
+//! Activation of swap areas by init
+//!
+//! After the filesystems are mounted, init activates with `swapon(2)` the
+//! swap entries of `/etc/fstab` (`pri=N`, `discard` and `noauto` options
+//! are understood), the swap files from the `[swap]` section (created if
+//! they don't exist) and the compressed swap in RAM (zram). A failure to
+//! activate the area is reported, but doesn't stop the boot. At shutdown
+//! all active swap areas are deactivated before the filesystems are
+//! unmounted.
+
+use anyhow::anyhow;
+use anyhow::Result;
+use std::ffi::CString;
+use std::fs;
+use std::fs::File;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use nix::errno::Errno;
+use nix::fcntl::fallocate;
+use nix::fcntl::FallocateFlags;
+use nix::libc;
+use nix::unistd::sysconf;
+use nix::unistd::SysconfVar;
+
+use crate::config::Swap;
+use crate::config::Zram;
+use crate::consts::MEMINFO_FILE;
+use crate::consts::ZRAM_DEVICE;
+use crate::fstab::FstabEntry;
+use crate::mount;
+use crate::paths::Paths;
+
+/// Size of the zram device if `zram.size` is not specified
+const DEFAULT_ZRAM_SIZE: &str = "50%";
+
+/// Priority of the zram device if `zram.priority` is not specified. It is
+/// higher than the priorities assigned by the kernel, so the compressed
+/// memory is used before the disk
+const DEFAULT_ZRAM_PRIORITY: i32 = 100;
+
+/// Flags of `swapon(2)` from `<sys/swap.h>`
+const SWAP_FLAG_PREFER: libc::c_int = 0x8000;
+const SWAP_FLAG_PRIO_MASK: libc::c_int = 0x7fff;
+const SWAP_FLAG_DISCARD: libc::c_int = 0x10000;
+
+/// Signature at the end of the first page of the swap area
+const SIGNATURE: &[u8] = b"SWAPSPACE2";
+
+/// Swap area activated by init
+#[derive(Debug, Clone, PartialEq)]
+pub struct SwapArea {
+    /// Device, file or `UUID=...`/`LABEL=...`
+    pub source: String,
+
+    pub kind: SwapKind,
+
+    /// Priority from `0` to `32767`, areas with higher priority are used
+    /// first. If not specified, the kernel assigns a negative one
+    pub priority: Option<i32>,
+
+    /// Discard freed pages on SSD
+    pub discard: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SwapKind {
+    /// Partition or existing swap file
+    Device,
+
+    /// Swap file created with the `size` if it doesn't exist
+    File { size: String },
+
+    /// Compressed swap in RAM
+    Zram {
+        size: String,
+        algorithm: Option<String>,
+    },
+}
+
+/// Returns the swap areas to activate: the zram device, the swap entries
+/// of `/etc/fstab` (without `noauto`) and the swap files from the config
+pub fn areas(entries: &[FstabEntry], conf: &Swap) -> Vec<SwapArea> {
+    let mut areas = vec![];
+
+    if let Some(zram) = conf.zram.as_ref().filter(|z| z.enabled.unwrap_or(true)) {
+        areas.push(zram_area(zram));
+    }
+
+    if conf.fstab.unwrap_or(true) {
+        areas.extend(
+            entries
+                .iter()
+                .filter(|entry| entry.is_swap() && !entry.has_option("noauto"))
+                .map(|entry| SwapArea {
+                    source: entry.spec.clone(),
+                    kind: SwapKind::Device,
+                    priority: entry.option_value("pri").and_then(|pri| pri.parse().ok()),
+                    discard: entry.has_option("discard"),
+                }),
+        );
+    }
+
+    areas.extend(conf.file.iter().flatten().map(|file| SwapArea {
+        source: file.path.clone(),
+        kind: match &file.size {
+            Some(size) => SwapKind::File { size: size.clone() },
+            None => SwapKind::Device,
+        },
+        priority: file.priority,
+        discard: false,
+    }));
+
+    areas
+}
+
+fn zram_area(zram: &Zram) -> SwapArea {
+    SwapArea {
+        source: ZRAM_DEVICE.to_string(),
+        kind: SwapKind::Zram {
+            size: zram.size.clone().unwrap_or(DEFAULT_ZRAM_SIZE.to_string()),
+            algorithm: zram.algorithm.clone(),
+        },
+        priority: Some(zram.priority.unwrap_or(DEFAULT_ZRAM_PRIORITY)),
+        discard: false,
+    }
+}
+
+/// Returns the name the swap area is reported with (`/dev/sda2` ->
+/// `dev-sda2.swap`)
+pub fn unit_name(source: &str) -> String {
+    mount::unit_name(source).replace(".mount", ".swap")
+}
+
+/// Reads the active swap areas from `/proc/swaps`
+pub fn active(paths: &Paths) -> Result<Vec<String>> {
+    let pth = paths.swaps();
+    if !pth.exists() {
+        return Ok(vec![]);
+    }
+
+    Ok(parse_swaps(&fs::read_to_string(pth)?))
+}
+
+fn parse_swaps(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().next())
+        .map(mount::unescape)
+        .collect()
+}
+
+/// Parses the size: `512K`, `256M`, `1G` or the percentage of RAM
+/// (`50%`). A number without a suffix is in bytes
+pub fn parse_size(size: &str, mem_total: u64) -> Result<u64> {
+    let invalid = || anyhow!("invalid size: {size}");
+
+    if let Some(percent) = size.strip_suffix('%') {
+        let percent: u64 = percent.parse().map_err(|_| invalid())?;
+        return Ok(mem_total / 100 * percent);
+    }
+
+    let (num, mult) = match size.char_indices().last().ok_or_else(invalid)? {
+        (pos, 'K' | 'k') => (&size[..pos], 1 << 10),
+        (pos, 'M' | 'm') => (&size[..pos], 1 << 20),
+        (pos, 'G' | 'g') => (&size[..pos], 1 << 30),
+        _ => (size, 1),
+    };
+    let num: u64 = num.parse().map_err(|_| invalid())?;
+
+    Ok(num * mult)
+}
+
+/// Returns the amount of RAM in bytes
+fn mem_total(paths: &Paths) -> Result<u64> {
+    let content = fs::read_to_string(paths.meminfo())?;
+    content
+        .lines()
+        .find_map(|line| line.strip_prefix("MemTotal:"))
+        .and_then(|val| val.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+        .ok_or_else(|| anyhow!("{MEMINFO_FILE}: MemTotal not found"))
+}
+
+fn page_size() -> u64 {
+    match sysconf(SysconfVar::PAGE_SIZE) {
+        Ok(Some(size)) => size as u64,
+        _ => 4096,
+    }
+}
+
+/// Writes the header of the swap area (`mkswap`) of the `size` bytes
+fn write_header(file: &mut File, size: u64, uuid: [u8; 16]) -> Result<()> {
+    let page = page_size();
+    let pages = size / page;
+    if pages < 10 {
+        return Err(anyhow!("swap area is too small ({size} bytes)"));
+    }
+
+    // version, last page and the number of bad pages follow the boot
+    // block of 1024 bytes, then the UUID and the label
+    let mut header = vec![];
+    header.extend(1u32.to_ne_bytes());
+    header.extend((pages as u32 - 1).to_ne_bytes());
+    header.extend(0u32.to_ne_bytes());
+    header.extend(uuid);
+    header.extend([0; 16]);
+
+    file.seek(SeekFrom::Start(1024))?;
+    file.write_all(&header)?;
+    file.seek(SeekFrom::Start(page - SIGNATURE.len() as u64))?;
+    file.write_all(SIGNATURE)?;
+    file.sync_all()?;
+
+    Ok(())
+}
+
+fn random_uuid(paths: &Paths) -> [u8; 16] {
+    let mut uuid = [0; 16];
+    if let Ok(text) = fs::read_to_string(paths.random_uuid()) {
+        let hex: Vec<u8> = text
+            .trim()
+            .split('-')
+            .collect::<String>()
+            .as_bytes()
+            .chunks(2)
+            .filter_map(|byte| u8::from_str_radix(std::str::from_utf8(byte).ok()?, 16).ok())
+            .collect();
+        if hex.len() == uuid.len() {
+            uuid.copy_from_slice(&hex);
+        }
+    }
+    uuid
+}
+
+/// Creates the swap file of the `size` bytes. The space is allocated, as
+/// the kernel refuses to use files with holes
+fn create_file(paths: &Paths, pth: &Path, size: u64) -> Result<()> {
+    let mut file = File::options()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(pth)?;
+
+    let res = fallocate(file.as_raw_fd(), FallocateFlags::empty(), 0, size as i64)
+        .map_err(|why| anyhow!("failed to allocate {}: {why}", pth.display()))
+        .and_then(|_| write_header(&mut file, size, random_uuid(paths)));
+
+    // a partially created file would be taken for a ready swap area
+    // at the next boot
+    if res.is_err() {
+        let _ = fs::remove_file(pth);
+    }
+    res
+}
+
+/// Configures the zram device and writes the swap header to it. The zram
+/// module must be loaded
+fn setup_zram(paths: &Paths, size: &str, algorithm: Option<&str>) -> Result<()> {
+    let sys = paths.zram_sys_dir();
+    if !sys.is_dir() {
+        return Err(anyhow!(
+            "{ZRAM_DEVICE} not found (is the zram module loaded?)"
+        ));
+    }
+
+    let size = parse_size(size, mem_total(paths)?)?;
+    if let Some(algorithm) = algorithm {
+        fs::write(sys.join("comp_algorithm"), algorithm)
+            .map_err(|why| anyhow!("unsupported compression algorithm {algorithm}: {why}"))?;
+    }
+    fs::write(sys.join("disksize"), size.to_string())?;
+
+    let mut dev = File::options()
+        .write(true)
+        .open(paths.zram_device())?;
+    write_header(&mut dev, size, random_uuid(paths))
+}
+
+/// Frees the memory of the deactivated zram device
+pub fn reset_zram(paths: &Paths) -> Result<()> {
+    let sys = paths.zram_sys_dir();
+    match sys.is_dir() {
+        true => Ok(fs::write(sys.join("reset"), "1")?),
+        false => Ok(()),
+    }
+}
+
+/// Prepares the swap area (creates the file or sets up zram) and
+/// activates it. `device` is the resolved `area.source`
+pub fn activate(paths: &Paths, area: &SwapArea, device: &str) -> Result<()> {
+    let pth = paths.resolve(device);
+
+    match &area.kind {
+        SwapKind::Device => {}
+        SwapKind::File { size } => {
+            if !pth.exists() {
+                create_file(paths, &pth, parse_size(size, mem_total(paths)?)?)?;
+            }
+        }
+        SwapKind::Zram { size, algorithm } => setup_zram(paths, size, algorithm.as_deref())?,
+    }
+
+    let mut flags = 0;
+    if let Some(priority) = area.priority {
+        flags |= SWAP_FLAG_PREFER | (priority & SWAP_FLAG_PRIO_MASK);
+    }
+    if area.discard {
+        flags |= SWAP_FLAG_DISCARD;
+    }
+
+    let cpath = CString::new(pth.as_os_str().as_bytes())?;
+    // SAFETY: the path is a valid NUL-terminated string
+    let res = unsafe { libc::swapon(cpath.as_ptr(), flags) };
+    Errno::result(res).map_err(|why| anyhow!("failed to activate swap {device}: {why}"))?;
+
+    Ok(())
+}
+
+/// Deactivates the swap area
+pub fn deactivate(paths: &Paths, device: &str) -> Result<()> {
+    let cpath = CString::new(paths.resolve(device).as_os_str().as_bytes())?;
+    // SAFETY: the path is a valid NUL-terminated string
+    let res = unsafe { libc::swapoff(cpath.as_ptr()) };
+    Errno::result(res).map_err(|why| anyhow!("failed to deactivate swap {device}: {why}"))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SwapFile;
+    use crate::fstab;
+    use std::io::Read;
+
+    #[test]
+    fn areas_test() {
+        let entries = fstab::parse(
+            "/dev/sda1 / ext4 defaults 0 1\n\
+             UUID=0f3e-11 none swap pri=10,discard 0 0\n\
+             /dev/sdb2 none swap noauto 0 0\n",
+        )
+        .unwrap();
+        let conf = Swap {
+            file: Some(vec![SwapFile {
+                path: "/swapfile".to_string(),
+                size: Some("512M".to_string()),
+                priority: None,
+            }]),
+            zram: Some(Zram::default()),
+            ..Swap::default()
+        };
+
+        let areas = areas(&entries, &conf);
+        let sources: Vec<&str> = areas.iter().map(|area| area.source.as_str()).collect();
+        assert_eq!(sources, ["/dev/zram0", "UUID=0f3e-11", "/swapfile"]);
+        assert_eq!(areas[0].priority, Some(100));
+        assert_eq!(areas[1].priority, Some(10));
+        assert!(areas[1].discard);
+        assert_eq!(
+            areas[2].kind,
+            SwapKind::File {
+                size: "512M".to_string()
+            }
+        );
+
+        assert_eq!(unit_name("/dev/zram0"), "dev-zram0.swap");
+        assert_eq!(
+            parse_swaps(
+                "Filename\tType\tSize\tUsed\tPriority\n\
+                 /dev/zram0 partition 1048572 0 100\n\
+                 /swap\\040file file 524284 0 -2\n"
+            ),
+            ["/dev/zram0", "/swap file"]
+        );
+    }
+
+    #[test]
+    fn size_test() {
+        assert_eq!(parse_size("512M", 0).unwrap(), 512 << 20);
+        assert_eq!(parse_size("1G", 0).unwrap(), 1 << 30);
+        assert_eq!(parse_size("4096", 0).unwrap(), 4096);
+        assert_eq!(parse_size("50%", 1000).unwrap(), 500);
+        assert!(parse_size("", 0).is_err());
+        assert!(parse_size("1T", 0).is_err());
+    }
+
+    #[test]
+    fn header_test() {
+        let pth = std::env::temp_dir().join(format!("lfa_init_swap_{}", std::process::id()));
+        let _ = fs::remove_file(&pth);
+        let size = page_size() * 16;
+
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&pth)
+            .unwrap();
+        file.set_len(size).unwrap();
+        write_header(&mut file, size, [0xab; 16]).unwrap();
+
+        let mut buf = vec![];
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut buf).unwrap();
+        let page = page_size() as usize;
+        assert_eq!(&buf[page - 10..page], SIGNATURE);
+        assert_eq!(&buf[1024..1028], 1u32.to_ne_bytes());
+        assert_eq!(&buf[1028..1032], 15u32.to_ne_bytes());
+        assert_eq!(&buf[0x40c..0x41c], [0xab; 16]);
+
+        assert!(write_header(&mut file, page_size(), [0; 16]).is_err());
+        fs::remove_file(&pth).unwrap();
+
+        // the file is not left behind if it can't be made a swap area
+        let paths = Paths::with_root(std::env::temp_dir());
+        assert!(create_file(&paths, &pth, page_size()).is_err());
+        assert!(!pth.exists());
+    }
+}
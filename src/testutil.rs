@@ -0,0 +1,20 @@
+//! Fixtures shared by the unit tests
+
+use std::fs;
+use std::path::PathBuf;
+
+use crate::paths::Paths;
+
+/// Returns the empty temporary root directory of the `name` test
+pub fn temp_root(name: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("lfa_init_{name}_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    root
+}
+
+/// Writes the file inside the root directory, creating its parents
+pub fn write(paths: &Paths, pth: &str, content: &str) {
+    let pth = paths.resolve(pth);
+    fs::create_dir_all(pth.parent().unwrap()).unwrap();
+    fs::write(pth, content).unwrap();
+}
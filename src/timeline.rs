@@ -0,0 +1,191 @@
+//! Boot timeline similar to bootchart
+//!
+//! Each service is drawn as a bar from its start to the moment it has
+//! become ready (green) or failed (red), followed by a pale bar while it
+//! was running. Services skipped because of their start conditions are
+//! grey. Runlevel switches are shown as vertical lines.
+
+use std::fmt::Write;
+
+use crate::boot::secs;
+use crate::boot::BootRecord;
+
+/// Milliseconds per pixel
+const SCALE: u64 = 10;
+
+/// Minimal width of the chart in pixels
+const MIN_WIDTH: u64 = 800;
+
+const ROW_HEIGHT: u64 = 20;
+
+/// Height of the header with the title and the time axis
+const HEADER: u64 = 50;
+
+/// Returns the timeline as an SVG image
+pub fn to_svg(boot: &BootRecord) -> String {
+    let end = boot.end();
+    let width = (end / SCALE + 200).max(MIN_WIDTH);
+    let height = HEADER + ROW_HEIGHT * (boot.services.len() as u64 + 1);
+    let x = |ms: u64| ms / SCALE;
+
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" \
+         font-family=\"monospace\" font-size=\"11\">"
+    );
+    let _ = writeln!(
+        svg,
+        "  <text x=\"5\" y=\"15\" font-size=\"14\">Boot: init started at {}, finished at {}</text>",
+        secs(boot.init_start),
+        boot.finished.map(secs).as_deref().unwrap_or("-")
+    );
+
+    // time axis
+    let step = match end {
+        0..=20_000 => 1000,
+        20_001..=120_000 => 5000,
+        _ => 30_000,
+    };
+    for ms in (0..=end).step_by(step) {
+        let _ = writeln!(
+            svg,
+            "  <line x1=\"{0}\" y1=\"{1}\" x2=\"{0}\" y2=\"{height}\" stroke=\"#ddd\"/>\n  \
+             <text x=\"{0}\" y=\"{2}\">{3}s</text>",
+            x(ms),
+            HEADER - 10,
+            HEADER - 15,
+            ms / 1000
+        );
+    }
+
+    // the kernel before init
+    let _ = writeln!(
+        svg,
+        "  <rect x=\"0\" y=\"{HEADER}\" width=\"{}\" height=\"{}\" fill=\"#ccc\"/>\n  \
+         <text x=\"2\" y=\"{}\">kernel</text>",
+        x(boot.init_start),
+        ROW_HEIGHT - 4,
+        HEADER + ROW_HEIGHT - 7
+    );
+
+    for rl in &boot.runlevels {
+        let _ = writeln!(
+            svg,
+            "  <line x1=\"{0}\" y1=\"{1}\" x2=\"{0}\" y2=\"{height}\" stroke=\"#00f\" \
+             stroke-dasharray=\"4\"/>\n  <text x=\"{2}\" y=\"{1}\" fill=\"#00f\">{3}</text>",
+            x(rl.at),
+            HEADER - 2,
+            x(rl.at) + 2,
+            escape(&rl.runlevel)
+        );
+    }
+
+    for (idx, timing) in boot.services.iter().enumerate() {
+        let y = HEADER + ROW_HEIGHT * (idx as u64 + 1);
+        let start = timing.start;
+        let (ready, color) = match (timing.ready, timing.failed, timing.skipped) {
+            (Some(ready), _, _) => (ready, "#4c4"),
+            (None, Some(failed), _) => (failed, "#e44"),
+            (None, None, Some(skipped)) => (skipped, "#ccc"),
+            (None, None, None) => (end, "#fc4"),
+        };
+
+        if timing.ready.is_some() {
+            let exit = timing.exit.unwrap_or(end);
+            let _ = writeln!(
+                svg,
+                "  <rect x=\"{}\" y=\"{y}\" width=\"{}\" height=\"{}\" fill=\"#cfc\"/>",
+                x(ready),
+                x(exit.saturating_sub(ready)),
+                ROW_HEIGHT - 4
+            );
+        }
+
+        let duration = timing.duration().map(secs).unwrap_or_default();
+        let name = escape(&timing.service);
+        let _ = writeln!(
+            svg,
+            "  <rect x=\"{bx}\" y=\"{y}\" width=\"{bw}\" height=\"{bh}\" fill=\"{color}\">\
+             <title>{rl}/{name}</title></rect>\n  <text x=\"{tx}\" y=\"{ty}\">{name} {duration}</text>",
+            bx = x(start),
+            bw = x(ready.saturating_sub(start)).max(1),
+            bh = ROW_HEIGHT - 4,
+            rl = escape(&timing.runlevel),
+            tx = x(start) + 2,
+            ty = y + ROW_HEIGHT - 7,
+        );
+    }
+
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// Returns an HTML page with the timeline and the list of the services
+/// sorted by the time of their start
+pub fn to_html(boot: &BootRecord) -> String {
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>Boot timeline</title>\n</head>\n<body>\n",
+    );
+    html.push_str(&to_svg(boot));
+
+    html.push_str(
+        "<table>\n<tr><th>Time</th><th>Runlevel</th><th>Service</th><th>Result</th></tr>\n",
+    );
+    for timing in boot.blame() {
+        let result = match timing.ready {
+            Some(_) => "ready",
+            None => "failed",
+        };
+        let _ = writeln!(
+            html,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{result}</td></tr>",
+            timing.duration().map(secs).unwrap_or_default(),
+            escape(&timing.runlevel),
+            escape(&timing.service)
+        );
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+
+    html
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boot::ServiceTiming;
+
+    #[test]
+    fn svg_test() {
+        let boot = BootRecord {
+            init_start: 1500,
+            finished: Some(4000),
+            runlevels: vec![],
+            services: vec![ServiceTiming {
+                runlevel: "rl1".to_string(),
+                service: "a<b".to_string(),
+                start: 2000,
+                ready: None,
+                failed: Some(3000),
+                exit: None,
+                skipped: None,
+            }],
+        };
+
+        let svg = to_svg(&boot);
+        assert!(svg.starts_with("<svg "));
+        assert!(svg.contains("<rect x=\"200\" y=\"70\" width=\"100\" height=\"16\" fill=\"#e44\">"));
+        assert!(svg.contains(">a&lt;b 1.000s</text>"));
+        assert!(
+            to_html(&boot).contains("<td>1.000s</td><td>rl1</td><td>a&lt;b</td><td>failed</td>")
+        );
+    }
+}
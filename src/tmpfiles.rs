@@ -0,0 +1,563 @@
+//! Creation of runtime files and directories
+//!
+//! Files, directories, symlinks and device nodes are described by the
+//! `[[paths]]` tables of the services and by the `tmpfiles.d`-style lists
+//! in `/etc/init/tmpfiles.d/*.conf`. Each line of a list contains the type,
+//! path, mode, user, group, age and argument separated by whitespace; `-`
+//! leaves the field unset and the argument takes the rest of the line:
+//!
+//! ```text
+//! # Type Path        Mode User   Group  Age Argument
+//! d      /run/sshd   0755 root   root   -
+//! d      /tmp        1777 root   root   10d
+//! f      /run/utmp   0664 root   utmp   -
+//! L      /etc/mtab   -    -      -      -   /proc/self/mounts
+//! c      /dev/null   0666 root   root   -   1:3
+//! e      /var/tmp    -    -      -      30d
+//! ```
+//!
+//! The lists are applied before the first runlevel, the `[[paths]]` of a
+//! service right before it is started. Existing paths are never replaced:
+//! only their mode and owner are adjusted if they are specified. Symlinks
+//! are not followed, neither in the path nor in its parents, and an
+//! existing path of another type is an error.
+
+use anyhow::anyhow;
+use anyhow::Result;
+use std::fs;
+use std::fs::Metadata;
+use std::io::ErrorKind;
+use std::os::unix::fs::lchown;
+use std::os::unix::fs::symlink;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use nix::sys::stat::fchmodat;
+use nix::sys::stat::makedev;
+use nix::sys::stat::mknod;
+use nix::sys::stat::FchmodatFlags;
+use nix::sys::stat::Mode;
+use nix::sys::stat::SFlag;
+
+use crate::paths::Paths;
+use crate::service::PathSection;
+use crate::service::PathType;
+
+/// Mode of the new directories
+const DEFAULT_DIR_MODE: u32 = 0o755;
+
+/// Mode of the new files and device nodes
+const DEFAULT_FILE_MODE: u32 = 0o644;
+
+/// Parses the `tmpfiles.d`-style list
+pub fn parse(content: &str) -> Result<Vec<PathSection>> {
+    let mut entries = vec![];
+
+    for (idx, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut rest = line;
+        let mut fields = [None; 6];
+        for field in &mut fields {
+            let (value, tail) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            *field = Some(value).filter(|value| !value.is_empty() && *value != "-");
+            rest = tail.trim_start();
+        }
+        let [kind, path, mode, user, group, age] = fields;
+        let field = |value: Option<&str>| value.map(str::to_string);
+
+        let kind = match kind.unwrap_or_default() {
+            "d" => PathType::directory,
+            "f" => PathType::file,
+            "L" => PathType::symlink,
+            "c" => PathType::char,
+            "b" => PathType::block,
+            "e" => PathType::clean,
+            kind => return Err(anyhow!("line {}: unsupported type {kind}", idx + 1)),
+        };
+        let path = path.ok_or_else(|| anyhow!("line {}: path is not specified", idx + 1))?;
+
+        entries.push(PathSection {
+            path: path.to_string(),
+            kind,
+            mode: field(mode),
+            user: field(user),
+            group: field(group),
+            age: field(age),
+            argument: Some(rest).filter(|arg| !arg.is_empty()).map(str::to_string),
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Reads the lists from `/etc/init/tmpfiles.d/*.conf` in the lexical order.
+/// Returns the names of the lists (without `.conf`) and their entries
+pub fn read(paths: &Paths) -> Result<Vec<(String, Vec<PathSection>)>> {
+    let mut files: Vec<_> = match fs::read_dir(paths.tmpfiles_dir()) {
+        Ok(dir) => dir
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|pth| pth.extension().is_some_and(|ext| ext == "conf"))
+            .collect(),
+        Err(_) => return Ok(vec![]),
+    };
+    files.sort();
+
+    files
+        .iter()
+        .map(|pth| {
+            let name = pth
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string();
+            let entries = fs::read_to_string(pth)
+                .map_err(|why| anyhow!("{why}"))
+                .and_then(|content| parse(&content))
+                .map_err(|why| anyhow!("{}: {why}", pth.display()))?;
+            Ok((name, entries))
+        })
+        .collect()
+}
+
+/// Parses the octal mode (`"0755"`, `"1777"`)
+pub fn parse_mode(mode: &str) -> Result<u32> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| anyhow!("invalid mode: {mode}"))
+}
+
+/// Parses the age: numbers with the `s`, `m` (`min`), `h`, `d` or `w`
+/// units (`1h30m`). Numbers without a unit are seconds
+pub fn parse_age(age: &str) -> Result<Duration> {
+    let invalid = || anyhow!("invalid age: {age}");
+
+    let mut secs = 0;
+    let mut rest = age;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let num: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+        rest = &rest[digits..];
+
+        let unit = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let mult = match &rest[..unit] {
+            "" | "s" => 1,
+            "m" | "min" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            "w" => 7 * 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        rest = &rest[unit..];
+        secs += num * mult;
+    }
+
+    match age.is_empty() {
+        true => Err(invalid()),
+        false => Ok(Duration::from_secs(secs)),
+    }
+}
+
+/// Parses the `MAJOR:MINOR` numbers of the device node
+pub fn parse_device(device: &str) -> Result<(u64, u64)> {
+    device
+        .split_once(':')
+        .and_then(|(major, minor)| Some((major.parse().ok()?, minor.parse().ok()?)))
+        .ok_or_else(|| anyhow!("invalid device numbers: {device}"))
+}
+
+/// Looks for the name in the `passwd` or `group` database. Returns the
+/// fields of the found line
+fn lookup(db: &Path, name: &str) -> Result<Vec<String>> {
+    let content = fs::read_to_string(db).map_err(|why| anyhow!("{}: {why}", db.display()))?;
+
+    content
+        .lines()
+        .map(|line| line.split(':').map(str::to_string).collect::<Vec<_>>())
+        .find(|fields| fields.len() > 3 && fields[0] == name)
+        .ok_or_else(|| anyhow!("unknown user or group: {name}"))
+}
+
+fn parse_id(id: &str) -> Result<u32> {
+    id.parse().map_err(|_| anyhow!("invalid ID: {id}"))
+}
+
+/// Returns the UID and GID for the owner of the entry
+pub fn owner(paths: &Paths, entry: &PathSection) -> Result<(Option<u32>, Option<u32>)> {
+    ids(paths, entry.user.as_deref(), entry.group.as_deref())
+}
+
+/// Resolves the user and the group (names or numeric IDs) to the UID and
+/// GID. The group defaults to the primary group of the user given by name
+pub fn ids(
+    paths: &Paths,
+    user: Option<&str>,
+    group: Option<&str>,
+) -> Result<(Option<u32>, Option<u32>)> {
+    let mut uid = None;
+    let mut gid = None;
+
+    if let Some(user) = user {
+        match user.parse() {
+            Ok(id) => uid = Some(id),
+            Err(_) => {
+                let fields = lookup(&paths.passwd(), user)?;
+                uid = Some(parse_id(&fields[2])?);
+                gid = Some(parse_id(&fields[3])?);
+            }
+        }
+    }
+    if let Some(group) = group {
+        gid = Some(match group.parse() {
+            Ok(id) => id,
+            Err(_) => parse_id(&lookup(&paths.group(), group)?[2])?,
+        });
+    }
+
+    Ok((uid, gid))
+}
+
+/// Checks the entry without applying it
+pub fn validate(paths: &Paths, entry: &PathSection) -> Result<()> {
+    check(paths, entry).map_err(|why| anyhow!("{}: {why}", &entry.path))
+}
+
+/// Creates the path of the entry if it doesn't exist, adjusts its mode and
+/// owner and removes the old files from the directory
+pub fn apply(paths: &Paths, entry: &PathSection) -> Result<()> {
+    check(paths, entry)
+        .and_then(|_| create(paths, entry))
+        .map_err(|why| anyhow!("{}: {why}", &entry.path))
+}
+
+fn check(paths: &Paths, entry: &PathSection) -> Result<()> {
+    if !entry.path.starts_with('/') {
+        return Err(anyhow!("path must be absolute"));
+    }
+    if let Some(mode) = &entry.mode {
+        parse_mode(mode)?;
+    }
+    if let Some(age) = &entry.age {
+        parse_age(age)?;
+    }
+    owner(paths, entry)?;
+
+    match (entry.kind, &entry.argument) {
+        (PathType::symlink, None) => Err(anyhow!("symlink target is not specified")),
+        (PathType::char | PathType::block, None) => {
+            Err(anyhow!("device numbers are not specified"))
+        }
+        (PathType::char | PathType::block, Some(device)) => parse_device(device).map(|_| ()),
+        _ => Ok(()),
+    }
+}
+
+fn create(paths: &Paths, entry: &PathSection) -> Result<()> {
+    let pth = paths.resolve(&entry.path);
+
+    // only cleaned paths are not created, so are their parents
+    if !parents(paths, &entry.path, entry.kind != PathType::clean)? {
+        return Ok(());
+    }
+    let exists = pth.symlink_metadata().is_ok();
+
+    let default_mode = match entry.kind {
+        PathType::directory => DEFAULT_DIR_MODE,
+        _ => DEFAULT_FILE_MODE,
+    };
+    let mode = match &entry.mode {
+        Some(mode) => Some(parse_mode(mode)?),
+        None => (!exists).then_some(default_mode),
+    };
+    let argument = entry.argument.clone().unwrap_or_default();
+
+    match entry.kind {
+        PathType::directory if !exists => fs::create_dir(&pth)?,
+        PathType::file if !exists => fs::write(&pth, argument)?,
+        PathType::symlink => {
+            if !exists {
+                symlink(argument, &pth)?;
+            }
+            // the mode and the owner of the target are not changed
+            return Ok(());
+        }
+        PathType::char | PathType::block if !exists => {
+            let (major, minor) = parse_device(&argument)?;
+            let kind = match entry.kind {
+                PathType::char => SFlag::S_IFCHR,
+                _ => SFlag::S_IFBLK,
+            };
+            let perm = Mode::from_bits_truncate(mode.unwrap_or(default_mode));
+            mknod(&pth, kind, perm, makedev(major, minor))?;
+        }
+        PathType::clean if !exists => return Ok(()),
+        _ => {}
+    }
+
+    // an existing path may be planted by anyone who can write to its
+    // directory: it is checked without following symlinks and its mode,
+    // owner and contents are never changed through a symlink
+    let meta = pth.symlink_metadata()?;
+    if !is_kind(&meta, entry.kind) {
+        return Err(anyhow!("exists and is not a {}", kind_name(entry.kind)));
+    }
+
+    if let Some(mode) = mode {
+        let mode = Mode::from_bits_truncate(mode);
+        fchmodat(None, &pth, mode, FchmodatFlags::NoFollowSymlink)?;
+    }
+    let (uid, gid) = owner(paths, entry)?;
+    if uid.is_some() || gid.is_some() {
+        lchown(&pth, uid, gid)?;
+    }
+
+    if let Some(age) = &entry.age {
+        if meta.is_dir() {
+            let deadline = SystemTime::now()
+                .checked_sub(parse_age(age)?)
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            clean(&pth, deadline);
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks the parent directories of the `pth` one by one and creates the
+/// missing ones if `create` is set. A symlink among the parents is an
+/// error, as it could redirect the path outside of its tree. Returns
+/// `false` if a parent is missing and is not created
+fn parents(paths: &Paths, pth: &str, create: bool) -> Result<bool> {
+    let mut dir = PathBuf::from("/");
+    let parent = Path::new(pth).parent().unwrap_or(Path::new("/"));
+
+    for comp in parent.components() {
+        let Component::Normal(name) = comp else {
+            continue;
+        };
+        dir.push(name);
+
+        let host = paths.resolve(&dir);
+        match host.symlink_metadata() {
+            Ok(meta) if meta.is_symlink() => {
+                return Err(anyhow!("parent {} is a symlink", dir.display()))
+            }
+            Ok(meta) if !meta.is_dir() => {
+                return Err(anyhow!("parent {} is not a directory", dir.display()))
+            }
+            Ok(_) => {}
+            Err(why) if why.kind() == ErrorKind::NotFound && create => fs::create_dir(&host)?,
+            Err(why) if why.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(why) => return Err(why.into()),
+        }
+    }
+
+    Ok(true)
+}
+
+/// Whether the existing path has the type of the entry. Symlinks match
+/// only the `symlink` entries
+fn is_kind(meta: &Metadata, kind: PathType) -> bool {
+    let file_type = meta.file_type();
+    match kind {
+        PathType::directory | PathType::clean => file_type.is_dir(),
+        PathType::file => file_type.is_file(),
+        PathType::symlink => file_type.is_symlink(),
+        PathType::char => file_type.is_char_device(),
+        PathType::block => file_type.is_block_device(),
+    }
+}
+
+fn kind_name(kind: PathType) -> &'static str {
+    match kind {
+        PathType::directory | PathType::clean => "directory",
+        PathType::file => "regular file",
+        PathType::symlink => "symlink",
+        PathType::char => "character device",
+        PathType::block => "block device",
+    }
+}
+
+/// Applies all entries. The failed entries don't prevent the others from
+/// being applied; the first error is returned
+pub fn apply_all(paths: &Paths, entries: &[PathSection]) -> Result<()> {
+    let mut res = Ok(());
+    for entry in entries {
+        let applied = apply(paths, entry);
+        if res.is_ok() {
+            res = applied;
+        }
+    }
+
+    res
+}
+
+/// Removes the files and the empty directories that were neither accessed
+/// nor modified after the `deadline`. Symlinks are not followed
+fn clean(dir: &Path, deadline: SystemTime) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+
+    for entry in entries.flatten() {
+        let pth = entry.path();
+        let Ok(meta) = pth.symlink_metadata() else {
+            continue;
+        };
+        let used = [meta.modified(), meta.accessed()]
+            .into_iter()
+            .flatten()
+            .max()
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+
+        if meta.is_dir() {
+            clean(&pth, deadline);
+            if used < deadline {
+                // fails if the directory still contains new files
+                let _ = fs::remove_dir(&pth);
+            }
+        } else if used < deadline {
+            let _ = fs::remove_file(&pth);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_root;
+    use std::fs::File;
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn parse_test() {
+        let entries = parse(
+            "# runtime directories\n\
+             d /run/sshd 0700 - - -\n\
+             L /etc/mtab - - - - /proc/self/mounts\n\
+             f /run/motd - - - - Welcome to LFA\n\
+             e /var/tmp\n",
+        )
+        .unwrap();
+
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].kind, PathType::directory);
+        assert_eq!(entries[0].mode.as_deref(), Some("0700"));
+        assert_eq!(entries[0].user, None);
+        assert_eq!(entries[1].argument.as_deref(), Some("/proc/self/mounts"));
+        assert_eq!(entries[2].argument.as_deref(), Some("Welcome to LFA"));
+        assert_eq!(entries[3].kind, PathType::clean);
+        assert!(parse("x /run/x\n").is_err());
+
+        assert_eq!(parse_age("10d").unwrap(), Duration::from_secs(864000));
+        assert_eq!(parse_age("1h30m").unwrap(), Duration::from_secs(5400));
+        assert_eq!(parse_age("45").unwrap(), Duration::from_secs(45));
+        assert!(parse_age("1y").is_err());
+        assert_eq!(parse_mode("1777").unwrap(), 0o1777);
+        assert!(parse_mode("0999").is_err());
+        assert_eq!(parse_device("1:3").unwrap(), (1, 3));
+    }
+
+    #[test]
+    fn apply_test() {
+        let root = temp_root("tmpfiles");
+        let paths = Paths::with_root(&root);
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::write(paths.passwd(), "root:x:0:0::/root:/bin/sh\n").unwrap();
+        fs::write(paths.group(), "root:x:0:\n").unwrap();
+
+        let entries = parse(
+            "d /run/sshd 0700 - - -\n\
+             f /run/motd - - - - hello\n\
+             L /run/mtab - - - - /proc/self/mounts\n\
+             d /tmp - - - 1h\n",
+        )
+        .unwrap();
+
+        fs::create_dir_all(root.join("tmp/old")).unwrap();
+        let old = File::create(root.join("tmp/old/file")).unwrap();
+        let past = SystemTime::now() - Duration::from_secs(2 * 60 * 60);
+        old.set_times(fs::FileTimes::new().set_accessed(past).set_modified(past))
+            .unwrap();
+        fs::write(root.join("tmp/new"), "").unwrap();
+
+        apply_all(&paths, &entries).unwrap();
+
+        let meta = fs::metadata(root.join("run/sshd")).unwrap();
+        assert!(meta.is_dir());
+        assert_eq!(meta.mode() & 0o7777, 0o700);
+        assert_eq!(fs::read_to_string(root.join("run/motd")).unwrap(), "hello");
+        assert_eq!(
+            fs::read_link(root.join("run/mtab")).unwrap(),
+            Path::new("/proc/self/mounts")
+        );
+        assert!(!root.join("tmp/old/file").exists());
+        assert!(root.join("tmp/new").exists());
+
+        // existing files are kept
+        fs::write(root.join("run/motd"), "changed").unwrap();
+        apply_all(&paths, &entries).unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("run/motd")).unwrap(),
+            "changed"
+        );
+
+        let owned = parse("d /run/x - root - -\nd /run/y - 1000 root -\n").unwrap();
+        assert_eq!(owner(&paths, &owned[0]).unwrap(), (Some(0), Some(0)));
+        assert_eq!(owner(&paths, &owned[1]).unwrap(), (Some(1000), Some(0)));
+        let unknown = parse("d /run/x - nobody - -\n").unwrap();
+        assert!(apply_all(&paths, &unknown).is_err());
+
+        // planted symlinks are not followed
+        fs::write(root.join("victim"), "").unwrap();
+        fs::set_permissions(root.join("victim"), fs::Permissions::from_mode(0o600)).unwrap();
+        fs::create_dir_all(root.join("victim_dir")).unwrap();
+        let victim = File::create(root.join("victim_dir/file")).unwrap();
+        victim
+            .set_times(fs::FileTimes::new().set_accessed(past).set_modified(past))
+            .unwrap();
+        symlink(root.join("victim"), root.join("run/lock")).unwrap();
+        symlink(root.join("victim_dir"), root.join("run/cache")).unwrap();
+
+        let planted = parse(
+            "f /run/lock 0666 - - -
+e /run/cache - - - 1h
+",
+        )
+        .unwrap();
+        let err = apply(&paths, &planted[0]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "/run/lock: exists and is not a regular file"
+        );
+        assert!(apply(&paths, &planted[1]).is_err());
+        let meta = fs::metadata(root.join("victim")).unwrap();
+        assert_eq!(meta.mode() & 0o7777, 0o600);
+        assert!(root.join("victim_dir/file").exists());
+
+        // nor are the symlinks among the parents
+        symlink(root.join("victim_dir"), root.join("run/x")).unwrap();
+        let err = apply_all(&paths, &parse("f /run/x/y/z 0666 - - -\n").unwrap()).unwrap_err();
+        assert_eq!(err.to_string(), "/run/x/y/z: parent /run/x is a symlink");
+        assert!(!root.join("victim_dir/y").exists());
+
+        // and neither are the other types
+        assert!(apply_all(&paths, &parse("d /run/motd - - - -\n").unwrap()).is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
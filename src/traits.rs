@@ -1,20 +1,31 @@
 use serde::Deserialize;
 use serde::Serialize;
 use std::path::Path;
+use anyhow::anyhow;
 use anyhow::Result;
 use std::fs;
 use toml;
 
 pub trait TomlConfig {
-	/// Reading TOML-config file and deserializing it into `Self` object
+	/// Reading TOML-config file and deserializing it into `Self` object.
+	/// Errors are reported as `file:line:column: message`
 	fn parse<P>(pth: P) -> Result<Self>
 	where
 		P: AsRef<Path>,
 		Self: Sized,
 		for<'de> Self: Deserialize<'de>,
 	{
-		let content = fs::read_to_string(&pth)?;
-		Ok(toml::from_str(&content)?)
+		let pth = pth.as_ref();
+		let content = fs::read_to_string(pth)
+			.map_err(|why| anyhow!("{}: {why}", pth.display()))?;
+
+		toml::from_str(&content).map_err(|why| {
+			let (line, col) = why
+				.span()
+				.map(|span| position(&content, span.start))
+				.unwrap_or((1, 1));
+			anyhow!("{}:{line}:{col}: {}", pth.display(), why.message().trim_end())
+		})
 	}
 
 	/// Writing a serialized object `Self` to the TOML config file
@@ -29,3 +40,24 @@ pub trait TomlConfig {
 		Ok(())
 	}
 }
+
+/// Returns the line and column (both starting from 1) of the byte `offset`
+/// in the `content`
+fn position(content: &str, offset: usize) -> (usize, usize) {
+	let before = &content[..offset.min(content.len())];
+	let line = before.matches('\n').count() + 1;
+	let col = before.rsplit('\n').next().unwrap_or_default().chars().count() + 1;
+
+	(line, col)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn position_test() {
+		assert_eq!(position("a = 1\nb = 2", 0), (1, 1));
+		assert_eq!(position("a = 1\nb = 2", 10), (2, 5));
+	}
+}